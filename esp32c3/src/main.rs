@@ -11,8 +11,13 @@ mod app {
 
     use esp32c3_hal::{
         self as _,
-        clock::ClockControl,
-        gpio::{Gpio7, Output, PushPull},
+        clock::{ClockControl, Clocks},
+        gpio::{Event as GpioEvent, Gpio7, Gpio9, Input, Output, PullUp, PushPull},
+        ledc::{
+            channel::{self, ChannelIFace},
+            timer::{self, TimerIFace},
+            LSGlobalClkSource, LowSpeed, LEDC,
+        },
         peripherals::{Peripherals, TIMG0, TIMG1, UART0},
         prelude::*,
         rmt::{Channel0, Rmt},
@@ -25,43 +30,133 @@ mod app {
         Uart, IO,
     };
 
-    use esp_hal_smartled::{smartLedAdapter, SmartLedsAdapter};
+    use esp_hal_smartled::SmartLedsAdapter;
+
+    use rtic_monotonics::esp32c3_systimer::{self, Systimer};
 
-    use smart_leds::{brightness, SmartLedsWrite, RGB};
+    use smart_leds::{brightness, gamma, SmartLedsWrite, RGB};
 
     use shared::{
-        deserialize_crc_cobs, hamming::decode_hamming, serialize_crc_cobs, Ack, BlinkerOptions,
-        Command, DateTime, IN_SIZE, OUT_SIZE,
+        blink::{JobTable, Output as BlinkOutput, Schedule},
+        clock::{Clock, Correction},
+        deserialize_crc_cobs,
+        events::EventQueue,
+        hamming::decode_hamming,
+        night::NightMode,
+        pwm::{job_duty, MAX_DUTY},
+        recur::{first_start, Runs},
+        rgb::{Color, RgbMode, RgbSettings},
+        serialize_crc_cobs,
+        strip::{Strip, MAX_PIXELS},
+        tz::TimeZone,
+        Ack, BlinkTarget, BlinkerOptions, Command, DateTime, DeviceMessage, Drift, Event,
+        RgbSchedule, Slew, IN_SIZE, MAX_BLINK_JOBS, OUT_SIZE,
     };
 
+    /* events waiting for the host to acknowledge them */
+    const EVENT_QUEUE: usize = 8;
+    /* how often to look for events to (re)send */
+    const EVENT_POLL_MS: u64 = 100;
+    /* presses closer together than this are contact bounce */
+    const DEBOUNCE_TICKS: u64 = SystemTimer::TICKS_PER_SECOND / 5;
+
+    const MS_PER_MINUTE: u64 = 60 * 1000;
+    const MS_PER_HOUR: u64 = 60 * MS_PER_MINUTE;
+    /* RMT pulses for a strip, 24 bits a pixel and an end marker */
+    const STRIP_BUFFER: usize = MAX_PIXELS * 24 + 1;
+
     #[derive(Debug)]
     pub enum RgbState {
         On,
         Off,
     }
 
+    /* the shared clock on top of SystemTimer, it also corrects for the drift of
+     * the crystal once the host has set the time a few times */
     pub struct ReferenceTimes {
-        utc_reference: u64,
-        sys_reference: u64,
+        clock: Clock,
+        time_zone: TimeZone,
+        /* t1 of the sync the last AdjustTime was measured with */
+        last_adjust: Option<u64>,
     }
 
     impl ReferenceTimes {
-        fn update(&mut self, utc_ref: u64) {
-            self.sys_reference = SystemTimer::now();
-            self.utc_reference = utc_ref;
+        /* None if the correction is slewed in, otherwise by how much whatever
+         * was scheduled on the time since boot has to move, which is only
+         * non zero the first time the clock is set */
+        fn update(&mut self, utc_ref: u64) -> Option<i64> {
+            self.set_at(SystemTimer::now(), utc_ref)
+        }
+
+        /* move the clock by offset ms from where it is now */
+        fn adjust(&mut self, offset: i64) -> Option<i64> {
+            let now = SystemTimer::now();
+            let utc_ref = self.clock.time_ms(now).saturating_add_signed(offset);
+            self.set_at(now, utc_ref)
+        }
+
+        fn set_at(&mut self, now: u64, utc_ref: u64) -> Option<i64> {
+            let boot_shift = if self.clock.is_set() {
+                0
+            } else {
+                utc_ref as i64 - self.clock.time_ms(now) as i64
+            };
+            match self.clock.set(now, utc_ref) {
+                Correction::Step => Some(boot_shift),
+                Correction::Slew => None,
+            }
         }
 
+        /* whether the correction measured with t1 was made already, a retry
+         * after its ack got lost must not move the clock a second time */
+        fn adjusted_for(&mut self, t1: u64) -> bool {
+            self.last_adjust.replace(t1) == Some(t1)
+        }
+
+        fn set_slew(&mut self, slew: Slew) {
+            self.clock.set_slew(slew);
+        }
+
+        /* time in ms at the given SystemTimer ticks */
+        pub fn get_time_ms_at(&self, ticks: u64) -> u64 {
+            self.clock.time_ms(ticks)
+        }
+
+        /* time in ms */
         pub fn get_time(&mut self) -> u64 {
-            let elapsed = SystemTimer::now() - self.sys_reference;
-            let ticks = SystemTimer::TICKS_PER_SECOND;
-            // do division with rounding to nearest
-            self.utc_reference + ((elapsed + (ticks - 1)) / ticks)
+            self.get_time_ms_at(SystemTimer::now())
+        }
+
+        /* wall clock time in ms, for everything that goes by the hour of day */
+        pub fn get_local_time(&mut self) -> u64 {
+            let utc = self.get_time();
+            self.time_zone.local_ms(utc)
+        }
+
+        fn set_time_zone(&mut self, time_zone: TimeZone) {
+            self.time_zone = time_zone;
+        }
+
+        pub fn time_zone(&self) -> TimeZone {
+            self.time_zone
+        }
+
+        pub fn is_set(&self) -> bool {
+            self.clock.is_set()
+        }
+
+        pub fn drift(&self) -> Drift {
+            Drift {
+                ppm: self.clock.ppm(),
+                estimates: self.clock.estimates(),
+            }
         }
 
         pub fn new() -> Self {
             ReferenceTimes {
-                utc_reference: 0,
-                sys_reference: 0,
+                clock: Clock::new(SystemTimer::TICKS_PER_SECOND),
+                time_zone: TimeZone::UTC,
+                last_adjust: None,
             }
         }
     }
@@ -76,30 +171,58 @@ mod app {
     struct Shared {
         cmd: [u8; OUT_SIZE],
         rgb_state: RgbState,
-        blink_data: BlinkerOptions,
+        /* on top of rgb_state, None when there is nothing (left) scheduled */
+        rgb_schedule: Option<Runs>,
+        /* what colour the LED shows when it's on, and how bright */
+        rgb_settings: RgbSettings,
+        /* the pixels when the LED is a strip */
+        strip: Strip,
+        /* dims or turns off both LEDs at night */
+        night: NightMode,
+        /* duty cycle in percent the blink LED is on at */
+        led_brightness: u8,
+        /* how far schedules made relative to boot still have to move, for
+         * time_stepped to pick up */
+        boot_shift: i64,
+        blink_jobs: JobTable<MAX_BLINK_JOBS>,
         reference_times: ReferenceTimes,
         timer0: Timer<Timer0<TIMG0>>,
         timer1: Timer<Timer0<TIMG1>>,
+        uart_tx: UartTx<'static, UART0>,
+        events: EventQueue<EVENT_QUEUE>,
     }
 
     #[local]
     struct Local {
         uart_rx: UartRx<'static, UART0>,
-        uart_tx: UartTx<'static, UART0>,
         cmd_idx: usize,
-        led: Gpio7<Output<PushPull>>,
-        rgb_led: SmartLedsAdapter<Channel0<0>, 0, 25>,
+        led: channel::Channel<'static, LowSpeed, Gpio7<Output<PushPull>>>,
+        button: Gpio9<Input<PullUp>>,
+        last_press: u64,
+        rgb_led: SmartLedsAdapter<Channel0<0>, 0, STRIP_BUFFER>,
         hamming_corrected: bool,
     }
 
-    #[init]
-    fn init(_: init::Context) -> (Shared, Local) {
+    /* the LEDC channel borrows its timer, which borrows the LEDC and the
+     * clocks, so they all have to live for good */
+    #[init(local = [
+        clocks: Option<Clocks<'static>> = None,
+        ledc: Option<LEDC<'static>> = None,
+        led_timer: Option<timer::Timer<'static, LowSpeed>> = None,
+    ])]
+    fn init(cx: init::Context) -> (Shared, Local) {
         rtt_init_print!();
         rprintln!(env!("CARGO_CRATE_NAME"));
 
+        let systimer_token = rtic_monotonics::create_systimer_token!();
+        Systimer::start(cx.core.SYSTIMER, systimer_token);
+
         let peripherals = Peripherals::take();
         let mut system = peripherals.SYSTEM.split();
-        let clocks = ClockControl::max(system.clock_control).freeze();
+        let clocks = &*cx
+            .local
+            .clocks
+            .insert(ClockControl::max(system.clock_control).freeze());
 
         let uart_config = Config {
             baudrate: 115200,
@@ -131,7 +254,9 @@ mod app {
         )
         .unwrap();
 
-        let rgb_led = <smartLedAdapter!(0, 1)>::new(rmt.channel0, io.pins.gpio2);
+        /* room for the longest strip, a single LED is a strip of one */
+        let rgb_led =
+            SmartLedsAdapter::<Channel0<0>, 0, STRIP_BUFFER>::new(rmt.channel0, io.pins.gpio2);
 
         /* this is apparently dumb */
         uart0.set_rx_fifo_full_threshold(2).unwrap();
@@ -157,24 +282,71 @@ mod app {
         let mut timer1 = timer_group1.timer0;
         timer1.listen();
 
-        let led = io.pins.gpio7.into_push_pull_output();
+        /* the blink LED dims and fades through PWM, 24 kHz is well past
+         * flicker and 5 bits of duty are plenty for an LED */
+        let ledc = cx.local.ledc.insert(LEDC::new(
+            peripherals.LEDC,
+            clocks,
+            &mut system.peripheral_clock_control,
+        ));
+        ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+        let ledc: &'static LEDC<'static> = ledc;
+        let led_timer = cx
+            .local
+            .led_timer
+            .insert(ledc.get_timer::<LowSpeed>(timer::Number::Timer0));
+        led_timer
+            .configure(timer::config::Config {
+                duty: timer::config::Duty::Duty5Bit,
+                clock_source: timer::LSClockSource::APBClk,
+                frequency: 24u32.kHz(),
+            })
+            .unwrap();
+        let led_timer: &'static timer::Timer<'static, LowSpeed> = led_timer;
+        let mut led = ledc.get_channel(
+            channel::Number::Channel0,
+            io.pins.gpio7.into_push_pull_output(),
+        );
+        led.configure(channel::config::Config {
+            timer: led_timer,
+            duty_pct: 0,
+            pin_config: channel::config::PinConfig::PushPull,
+        })
+        .unwrap();
+
+        let mut button = io.pins.gpio9.into_pull_up_input();
+        button.listen(GpioEvent::FallingEdge);
+
+        /* let the host know our time reference is gone */
+        let mut events = EventQueue::new();
+        events.push(Event::Boot);
+        send_events::spawn().unwrap();
 
         rprintln!("init works");
 
         (
             Shared {
-                blink_data: BlinkerOptions::Off,
+                blink_jobs: JobTable::new(),
                 cmd: [0; OUT_SIZE],
                 rgb_state: RgbState::Off,
+                rgb_schedule: None,
+                rgb_settings: RgbSettings::new(),
+                strip: Strip::new(),
+                night: NightMode::new(),
+                led_brightness: MAX_DUTY,
+                boot_shift: 0,
                 reference_times: ReferenceTimes::new(),
                 timer0,
                 timer1,
+                uart_tx,
+                events,
             },
             Local {
                 uart_rx,
-                uart_tx,
                 cmd_idx: 0,
                 led,
+                button,
+                last_press: 0,
                 rgb_led,
                 hamming_corrected: false,
             },
@@ -183,6 +355,9 @@ mod app {
 
     #[task(binds = UART0, local = [cmd_idx, uart_rx, hamming_corrected], shared = [cmd])]
     fn aggregate(mut cx: aggregate::Context) {
+        /* as early as possible, time sync wants to know when the frame arrived */
+        let received_at = SystemTimer::now();
+
         // rprint!("received UART0 rx interrupt: ");
 
         /* read two bytes */
@@ -238,7 +413,7 @@ mod app {
         if c == 0 || *cx.local.cmd_idx >= OUT_SIZE || hamming_err {
             // assert!(hamming_err);
             // rprint!(" full packet at {}", *cx.local.cmd_idx);
-            broker::spawn(*cx.local.hamming_corrected).unwrap();
+            broker::spawn(*cx.local.hamming_corrected, received_at).unwrap();
             *cx.local.cmd_idx = 0;
             *cx.local.hamming_corrected = false;
         }
@@ -247,21 +422,294 @@ mod app {
         cx.local.uart_rx.reset_rx_fifo_full_interrupt();
     }
 
-    #[task(shared = [cmd, reference_times], local = [uart_tx])]
-    async fn broker(mut cx: broker::Context, hamming_corrected: bool) {
+    #[task(shared = [cmd, reference_times, uart_tx, events, blink_jobs, timer0, rgb_schedule, rgb_settings, strip, night, led_brightness, timer1])]
+    async fn broker(mut cx: broker::Context, hamming_corrected: bool, received_at: u64) {
         let cmd = cx
             .shared
             .cmd
             .lock(|cmd| deserialize_crc_cobs::<Command>(cmd));
-        /* assume utc_reference of 0 means unset */
-        let datetime_set = cx.shared.reference_times.lock(|r| r.utc_reference != 0);
+        let datetime_set = cx.shared.reference_times.lock(|r| r.is_set());
+
+        /* acknowledgements are fire and forget, answering them would never end */
+        if let Ok(Command::AckEvent(seq)) = cmd {
+            cx.shared.events.lock(|events| events.ack(seq));
+            return;
+        }
+
+        if let Ok(Command::SyncTime { t1 }) = cmd {
+            let mut buf: [u8; IN_SIZE] = [0; IN_SIZE];
+            let n = cx.shared.reference_times.lock(|r| {
+                let t2 = r.get_time_ms_at(received_at);
+                /* stamp as late as we can, the write below is all that's left */
+                let t3 = r.get_time_ms_at(SystemTimer::now());
+                serialize_crc_cobs(&DeviceMessage::TimeSync { t1, t2, t3 }, &mut buf).len()
+            });
+            cx.shared
+                .uart_tx
+                .lock(|tx| tx.write_bytes(&buf[0..n]))
+                .expect("Failed to write response back to the host");
+            return;
+        }
+
+        if let Ok(Command::GetDrift) = cmd {
+            let drift = cx.shared.reference_times.lock(|r| r.drift());
+            let mut buf: [u8; IN_SIZE] = [0; IN_SIZE];
+            let response = serialize_crc_cobs(&DeviceMessage::Drift(drift), &mut buf);
+            cx.shared
+                .uart_tx
+                .lock(|tx| tx.write_bytes(response))
+                .expect("Failed to write response back to the host");
+            return;
+        }
+
+        if let Ok(Command::GetNightMode) = cmd {
+            let (now, tz) = cx
+                .shared
+                .reference_times
+                .lock(|r| (r.get_time(), r.time_zone()));
+            let status = cx
+                .shared
+                .night
+                .lock(|night| night.status(now, &tz, datetime_set));
+            let mut buf: [u8; IN_SIZE] = [0; IN_SIZE];
+            let response = serialize_crc_cobs(&DeviceMessage::NightMode(status), &mut buf);
+            cx.shared
+                .uart_tx
+                .lock(|tx| tx.write_bytes(response))
+                .expect("Failed to write response back to the host");
+            return;
+        }
+
+        if let Ok(Command::ListBlinks) = cmd {
+            let jobs = cx.shared.blink_jobs.lock(|jobs| jobs.jobs());
+            let mut buf: [u8; IN_SIZE] = [0; IN_SIZE];
+            let response = serialize_crc_cobs(&DeviceMessage::BlinkJobs(jobs), &mut buf);
+            cx.shared
+                .uart_tx
+                .lock(|tx| tx.write_bytes(response))
+                .expect("Failed to write response back to the host");
+            return;
+        }
+
+        /* Morse messages are blink jobs too */
+        if let Some((
+            request,
+            options @ BlinkerOptions::On {
+                date_time,
+                spec,
+                repeat,
+                ..
+            },
+        )) = cmd.as_ref().ok().and_then(|cmd| cmd.blink())
+        {
+            let (now, tz) = cx
+                .shared
+                .reference_times
+                .lock(|r| (r.get_time(), r.time_zone()));
+            /* a retry gets the job the first try made */
+            let id = cx.shared.blink_jobs.lock(|jobs| {
+                jobs.added_for(request).or_else(|| {
+                    first_start(date_time, repeat, now, datetime_set, &tz)
+                        .filter(|_| spec.is_valid())
+                        .and_then(|start| jobs.add(start, &options, request))
+                })
+            });
+            /* the new job may be the first one due */
+            cx.shared.timer0.lock(|t| t.start(0u64.secs()));
+
+            let response = match id {
+                Some(id) => DeviceMessage::BlinkScheduled(id),
+                None => DeviceMessage::Ack(Ack::NotOk),
+            };
+            rprintln!("Responding with : {:?}", response);
+            let mut buf: [u8; IN_SIZE] = [0; IN_SIZE];
+            let response = serialize_crc_cobs(&response, &mut buf);
+            cx.shared
+                .uart_tx
+                .lock(|tx| tx.write_bytes(response))
+                .expect("Failed to write response back to the host");
+            return;
+        }
 
         let mut ack = if let Ok(cmd) = cmd {
             match cmd {
                 Command::SetDateTime(t) => handle_new_datetime(t),
-                Command::SetBlinker(options) => handle_new_blink_data(options, datetime_set),
-                Command::RgbOn => handle_new_rgb_data(RgbState::On, datetime_set),
-                Command::RgbOff => handle_new_rgb_data(RgbState::Off, datetime_set),
+                Command::SetBlinker { .. } => {
+                    cx.shared.blink_jobs.lock(|jobs| jobs.clear());
+                    cx.shared.timer0.lock(|t| t.start(0u64.secs()));
+                    Ack::Ok
+                }
+                Command::CancelBlink(id) => {
+                    if cx.shared.blink_jobs.lock(|jobs| jobs.cancel(id)) {
+                        cx.shared.timer0.lock(|t| t.start(0u64.secs()));
+                        Ack::Ok
+                    } else {
+                        Ack::NotOk
+                    }
+                }
+                Command::ScheduleRgb(RgbSchedule::Off) => {
+                    cx.shared.rgb_schedule.lock(|s| *s = None);
+                    cx.shared.timer1.lock(|t| t.start(0u64.secs()));
+                    Ack::Ok
+                }
+                Command::ScheduleRgb(RgbSchedule::On {
+                    date_time,
+                    duration,
+                    repeat,
+                }) => {
+                    let (now, tz) = cx
+                        .shared
+                        .reference_times
+                        .lock(|r| (r.get_time(), r.time_zone()));
+                    match first_start(date_time, repeat, now, datetime_set, &tz) {
+                        Some(start) => {
+                            let runs = Runs {
+                                start,
+                                duration,
+                                repeat,
+                            };
+                            cx.shared.rgb_schedule.lock(|s| *s = Some(runs));
+                            cx.shared.timer1.lock(|t| t.start(0u64.secs()));
+                            Ack::Ok
+                        }
+                        None => Ack::NotOk,
+                    }
+                }
+                Command::RgbOn => {
+                    let needs_time = cx.shared.rgb_settings.lock(|s| s.needs_time());
+                    handle_new_rgb_data(RgbState::On, datetime_set || !needs_time)
+                }
+                Command::RgbOff => {
+                    let needs_time = cx.shared.rgb_settings.lock(|s| s.needs_time());
+                    handle_new_rgb_data(RgbState::Off, datetime_set || !needs_time)
+                }
+                Command::SetRgb {
+                    r,
+                    g,
+                    b,
+                    brightness,
+                } => {
+                    cx.shared.rgb_settings.lock(|s| {
+                        s.mode = RgbMode::Fixed;
+                        s.color = Color { r, g, b };
+                        s.brightness = brightness;
+                    });
+                    handle_new_rgb_data(RgbState::On, true)
+                }
+                Command::SetBrightness(brightness) => {
+                    cx.shared.rgb_settings.lock(|s| s.brightness = brightness);
+                    cx.shared.timer1.lock(|t| t.start(0u64.secs()));
+                    Ack::Ok
+                }
+                Command::SetPalette(palette) if palette.is_valid() => {
+                    cx.shared.rgb_settings.lock(|s| s.palette = palette);
+                    cx.shared.timer1.lock(|t| t.start(0u64.secs()));
+                    Ack::Ok
+                }
+                Command::SetPalette(_) => Ack::NotOk,
+                Command::SetRgbMode(mode) => {
+                    let accepted = cx.shared.rgb_settings.lock(|s| {
+                        let accepted = s.accepts(mode, datetime_set);
+                        if accepted {
+                            s.mode = mode;
+                        }
+                        accepted
+                    });
+                    if accepted {
+                        cx.shared.timer1.lock(|t| t.start(0u64.secs()));
+                        Ack::Ok
+                    } else {
+                        Ack::NotOk
+                    }
+                }
+                Command::SetLocation(location) if location.is_valid() => {
+                    cx.shared.rgb_settings.lock(|s| s.location = Some(location));
+                    cx.shared.timer1.lock(|t| t.start(0u64.secs()));
+                    Ack::Ok
+                }
+                Command::SetLocation(_) => Ack::NotOk,
+                Command::Animate(animation) if animation.is_valid() => {
+                    cx.shared.rgb_settings.lock(|s| {
+                        s.mode = RgbMode::Animated;
+                        s.animation = animation;
+                    });
+                    handle_new_rgb_data(RgbState::On, true)
+                }
+                Command::Animate(_) => Ack::NotOk,
+                Command::SetStripLength(len) => {
+                    if cx.shared.strip.lock(|strip| strip.set_len(len)) {
+                        cx.shared.timer1.lock(|t| t.start(0u64.secs()));
+                        Ack::Ok
+                    } else {
+                        Ack::NotOk
+                    }
+                }
+                Command::FillPixels { start, len, color } => {
+                    if cx.shared.strip.lock(|strip| strip.fill(start, len, color)) {
+                        cx.shared.rgb_settings.lock(|s| s.mode = RgbMode::Pixels);
+                        handle_new_rgb_data(RgbState::On, true)
+                    } else {
+                        Ack::NotOk
+                    }
+                }
+                Command::PixelChunk(chunk) => {
+                    if !cx.shared.strip.lock(|strip| strip.load(&chunk)) {
+                        Ack::NotOk
+                    } else if chunk.last {
+                        cx.shared.rgb_settings.lock(|s| s.mode = RgbMode::Pixels);
+                        handle_new_rgb_data(RgbState::On, true)
+                    } else {
+                        Ack::Ok
+                    }
+                }
+                Command::SetLedBrightness(percent) if percent <= MAX_DUTY => {
+                    cx.shared.led_brightness.lock(|b| *b = percent);
+                    cx.shared.timer0.lock(|t| t.start(0u64.secs()));
+                    Ack::Ok
+                }
+                Command::SetLedBrightness(_) => Ack::NotOk,
+                Command::SetNightMode(window) if window.is_none_or(|w| w.is_valid()) => {
+                    cx.shared.night.lock(|night| night.set_window(window));
+                    /* both LEDs go by it, have them look again now */
+                    time_stepped::spawn().ok();
+                    Ack::Ok
+                }
+                Command::SetNightMode(_) => Ack::NotOk,
+                Command::NightOverride(forced) => {
+                    let (now, tz) = cx
+                        .shared
+                        .reference_times
+                        .lock(|r| (r.get_time(), r.time_zone()));
+                    cx.shared
+                        .night
+                        .lock(|night| night.force(forced, now, &tz, datetime_set));
+                    time_stepped::spawn().ok();
+                    Ack::Ok
+                }
+                Command::SetTimeZone(time_zone) => {
+                    cx.shared
+                        .reference_times
+                        .lock(|r| r.set_time_zone(time_zone));
+                    /* same as a step as far as the hour of day is concerned */
+                    time_stepped::spawn().ok();
+                    Ack::Ok
+                }
+                Command::SetSlew(slew) => {
+                    cx.shared.reference_times.lock(|r| r.set_slew(slew));
+                    Ack::Ok
+                }
+                Command::AdjustTime { offset, t1 } => {
+                    if !cx.shared.reference_times.lock(|r| r.adjusted_for(t1)) {
+                        adjust_date_time::spawn(offset).unwrap();
+                    }
+                    Ack::Ok
+                }
+                Command::AckEvent(_)
+                | Command::SyncTime { .. }
+                | Command::GetDrift
+                | Command::ListBlinks
+                | Command::GetNightMode
+                | Command::Morse { .. } => unreachable!(),
             }
         } else {
             rprintln!("illegal cmd: {:?}", cmd.unwrap_err());
@@ -273,42 +721,61 @@ mod app {
         }
 
         let mut buf: [u8; IN_SIZE] = [0; IN_SIZE];
-        let response = serialize_crc_cobs(&ack, &mut buf);
+        let response = serialize_crc_cobs(&DeviceMessage::Ack(ack), &mut buf);
         rprintln!("Responding with : {:?}", ack);
         rprintln!("Responding with : {:?}", response);
-        cx.local
+        cx.shared
             .uart_tx
-            .write_bytes(response)
+            .lock(|tx| tx.write_bytes(response))
             .expect("Failed to write response back to the host");
     }
 
-    fn handle_new_rgb_data(state: RgbState, datetime_set: bool) -> Ack {
-        /* datetime checking is kind of dispersed here, not ideal */
-        if !datetime_set {
-            return Ack::NotOk;
+    /* sends new events and repeats unacknowledged ones once per second */
+    #[task(shared = [uart_tx, events])]
+    async fn send_events(mut cx: send_events::Context) {
+        loop {
+            let now = SystemTimer::now();
+            while let Some((seq, event)) = cx
+                .shared
+                .events
+                .lock(|events| events.due(now, SystemTimer::TICKS_PER_SECOND))
+            {
+                rprintln!("Sending event {} : {:?}", seq, event);
+                let mut buf: [u8; IN_SIZE] = [0; IN_SIZE];
+                let msg = serialize_crc_cobs(&DeviceMessage::Event { seq, event }, &mut buf);
+                cx.shared
+                    .uart_tx
+                    .lock(|tx| tx.write_bytes(msg))
+                    .expect("Failed to write event to the host");
+            }
+
+            Systimer::delay(esp32c3_systimer::ExtU64::millis(EVENT_POLL_MS)).await;
         }
+    }
 
-        update_rgb_data::spawn(state).unwrap();
-        Ack::Ok
+    #[task(binds = GPIO, local = [button, last_press], shared = [events])]
+    fn button(mut cx: button::Context) {
+        cx.local.button.clear_interrupt();
+
+        let now = SystemTimer::now();
+        if now - *cx.local.last_press < DEBOUNCE_TICKS {
+            return;
+        }
+        *cx.local.last_press = now;
+
+        rprintln!("button press");
+        cx.shared
+            .events
+            .lock(|events| events.push(Event::ButtonPressed));
     }
 
-    fn handle_new_blink_data(options: BlinkerOptions, datetime_set: bool) -> Ack {
+    fn handle_new_rgb_data(state: RgbState, datetime_set: bool) -> Ack {
+        /* datetime checking is kind of dispersed here, not ideal */
         if !datetime_set {
             return Ack::NotOk;
         }
 
-        if let BlinkerOptions::On {
-            date_time: _,
-            freq,
-            duration: _,
-        } = options
-        {
-            if freq == 0 {
-                return Ack::NotOk;
-            }
-        }
-
-        set_blink_data::spawn(options).unwrap();
+        update_rgb_data::spawn(state).unwrap();
         Ack::Ok
     }
 
@@ -321,32 +788,6 @@ mod app {
         }
     }
 
-    #[task(shared = [blink_data, timer0, reference_times])]
-    async fn set_blink_data(mut cx: set_blink_data::Context, options: BlinkerOptions) {
-        rprintln!("Inside set_blink_data task");
-
-        cx.shared.blink_data.lock(|blink_data| match options {
-            BlinkerOptions::Off => *blink_data = options,
-            BlinkerOptions::On {
-                date_time,
-                freq,
-                duration,
-            } => match date_time {
-                DateTime::Now => {
-                    let time_now = cx.shared.reference_times.lock(|r| r.get_time());
-                    *blink_data = BlinkerOptions::On {
-                        date_time: DateTime::Utc(time_now),
-                        freq,
-                        duration,
-                    };
-                }
-                DateTime::Utc(_) => *blink_data = options,
-            },
-        });
-
-        cx.shared.timer0.lock(|t| t.start(0u64.secs()));
-    }
-
     #[task(shared = [rgb_state, timer1])]
     async fn update_rgb_data(mut cx: update_rgb_data::Context, state: RgbState) {
         rprintln!("Inside update rgb task");
@@ -357,88 +798,167 @@ mod app {
         cx.shared.timer1.lock(|t| t.start(0u64.secs()));
     }
 
-    #[task(shared = [reference_times, timer0, timer1])]
+    #[task(shared = [reference_times, boot_shift])]
     async fn set_date_time(mut cx: set_date_time::Context, new_time: u64) {
         rprintln!("set_date_time {:?}", new_time);
         rprintln!(
             "received {}:{}:{}",
-            new_time / 3600 % 24,
-            new_time / 60 % 60,
-            new_time % 60
+            new_time / MS_PER_HOUR % 24,
+            new_time / MS_PER_MINUTE % 60,
+            new_time / 1000 % 60
         );
 
-        cx.shared
+        let stepped = cx
+            .shared
             .reference_times
             .lock(|reference_times| reference_times.update(new_time));
 
         let current_time = cx
             .shared
             .reference_times
-            .lock(|reference_times| reference_times.get_time());
+            .lock(|reference_times| reference_times.get_local_time());
 
         rprintln!(
             "current time {}:{}:{}",
-            current_time / 3600 % 24,
-            current_time / 60 % 60,
-            current_time % 60
+            current_time / MS_PER_HOUR % 24,
+            current_time / MS_PER_MINUTE % 60,
+            current_time / 1000 % 60
         );
 
-        /* trigger our LED handlers */
+        if let Some(boot_shift) = stepped {
+            cx.shared.boot_shift.lock(|s| *s += boot_shift);
+            time_stepped::spawn().ok();
+        }
+    }
+
+    #[task(shared = [reference_times, boot_shift])]
+    async fn adjust_date_time(mut cx: adjust_date_time::Context, offset: i64) {
+        let stepped = cx
+            .shared
+            .reference_times
+            .lock(|reference_times| reference_times.adjust(offset));
+        rprintln!("adjust_date_time {} ms, {:?}", offset, stepped);
+
+        if let Some(boot_shift) = stepped {
+            cx.shared.boot_shift.lock(|s| *s += boot_shift);
+            time_stepped::spawn().ok();
+        }
+    }
+
+    /* the time jumped rather than being slewed, so whatever the LED handlers
+     * scheduled is off now, have them look at their schedules again. A blink
+     * set up relative to boot before the time was known moves along with the
+     * clock so it still starts when it was asked to. Spawning it while a run
+     * is still pending fails, which is fine, that run looks at everything as
+     * it is by then */
+    #[task(shared = [timer0, timer1, blink_jobs, rgb_schedule, boot_shift])]
+    async fn time_stepped(mut cx: time_stepped::Context) {
+        let boot_shift = cx.shared.boot_shift.lock(core::mem::take);
+        if boot_shift != 0 {
+            cx.shared.blink_jobs.lock(|jobs| jobs.shift(boot_shift));
+            cx.shared.rgb_schedule.lock(|s| {
+                if let Some(runs) = s {
+                    runs.start = runs.start.saturating_add_signed(boot_shift);
+                }
+            });
+        }
         cx.shared.timer1.lock(|t| t.start(0u64.secs()));
         cx.shared.timer0.lock(|t| t.start(0u64.secs()));
     }
 
-    #[task(binds = TG0_T0_LEVEL,local=[led], shared=[timer0, blink_data, reference_times])]
+    #[task(binds = TG0_T0_LEVEL,local=[led], shared=[timer0, timer1, blink_jobs, reference_times, events, night, led_brightness])]
     fn blink(mut cx: blink::Context) {
-        rprintln!("Inside blink task");
         cx.shared.timer0.lock(|t| t.clear_interrupt());
 
-        let opts = cx.shared.blink_data.lock(|d| *d);
-        match opts {
-            BlinkerOptions::Off => {
-                cx.local.led.set_low().expect("Failed to turn off the led");
+        let (time_now, tz, time_set) = cx
+            .shared
+            .reference_times
+            .lock(|r| (r.get_time(), r.time_zone(), r.is_set()));
+        /* jobs keep running at night, dimmed or unseen */
+        let (night, night_change) = cx
+            .shared
+            .night
+            .lock(|night| (*night, night.next_change(time_now, &tz, time_set)));
+        let mut expired = false;
+        while let Some(id) = cx.shared.blink_jobs.lock(|jobs| jobs.expire(time_now, &tz)) {
+            cx.shared
+                .events
+                .lock(|events| events.push(Event::BlinkFinished(id)));
+            expired = true;
+        }
+
+        let (led, led_job, rgb) = cx.shared.blink_jobs.lock(|jobs| {
+            let led = jobs.schedule(time_now, BlinkOutput::Led);
+            let job = match led {
+                Schedule::Blink { id, .. } => jobs.job(id),
+                _ => None,
+            };
+            (led, job, jobs.schedule(time_now, BlinkOutput::Rgb))
+        });
+        let brightness = cx.shared.led_brightness.lock(|b| *b);
+        let led_wait = match (led, led_job) {
+            (Schedule::Blink { .. }, Some(job)) => {
+                /* fades come back every few ms for their next step */
+                let (duty, for_ms) = job_duty(&job, time_now, brightness);
+                let duty = night.led_duty(duty, time_now, &tz, time_set);
+                cx.local.led.set_duty(duty).expect("Failed to set the led");
+                Some(for_ms)
             }
-            BlinkerOptions::On {
-                date_time,
-                freq,
-                duration,
-            } => {
-                let time_now = cx.shared.reference_times.lock(|r| r.get_time());
-
-                match date_time {
-                    DateTime::Now => panic!("Should never end here"), // Should never be this variant
-                    DateTime::Utc(s_time) => {
-                        if time_now >= s_time + duration {
-                            cx.local.led.set_low().expect("Failed to turn off the led");
-                            return;
-                        }
-                        if time_now >= s_time {
-                            cx.local.led.toggle().expect("Led toggle failed");
-                            // TODO not checking for dividing by 0 but should not happen
-                            let period = ((1f32 / freq as f32) * 1000f32) as u32;
-                            cx.shared.timer0.lock(|t| t.start(period.millis()));
-                            return;
-                        }
-                        /* wait for our time to start with LED off, print out current time once per
-                         * second to make sure we're not drifting too badly */
-                        rprintln!(
-                            "blink time now {}:{}:{}",
-                            time_now / 3600 % 24,
-                            time_now / 60 % 60,
-                            time_now % 60
-                        );
-
-                        cx.local.led.set_low().expect("Failed to turn off the led");
-                        cx.shared.timer0.lock(|t| t.start(1u64.secs()));
-                        // rprintln!("Curr time : {}\nStart_time{}", time_now, s_time);
-                        // cx.shared.timer0.lock(|t| t.start(1u64.secs()));
-                    }
+            (Schedule::Wait(ms), _) => {
+                /* wait for the next job with LED off, print out current time once per
+                 * second to make sure we're not drifting too badly, not on the short
+                 * waits fades and RGB jobs wake us for */
+                if ms >= 1000 {
+                    rprintln!(
+                        "blink time now {}:{}:{}",
+                        time_now / MS_PER_HOUR % 24,
+                        time_now / MS_PER_MINUTE % 60,
+                        time_now / 1000 % 60
+                    );
                 }
+
+                cx.local
+                    .led
+                    .set_duty(0)
+                    .expect("Failed to turn off the led");
+                /* wake up right at the start if it's less than a second away */
+                Some(ms.min(1000))
             }
+            _ => {
+                cx.local
+                    .led
+                    .set_duty(0)
+                    .expect("Failed to turn off the led");
+                None
+            }
+        };
+
+        /* update_rgb draws the RGB LED, wake it whenever one of its jobs
+         * starts, changes level or is over */
+        let rgb_wait = match rgb {
+            Schedule::Idle => None,
+            Schedule::Wait(ms) => Some(ms.min(1000)),
+            Schedule::Blink { for_ms, .. } => Some(for_ms),
+        };
+        if expired || rgb != Schedule::Idle {
+            cx.shared.timer1.lock(|t| t.start(0u64.secs()));
+        }
+        if let Some(ms) = led_wait.into_iter().chain(rgb_wait).min() {
+            /* a job running into the night, or out of it, changes right then */
+            let ms = night_change.map_or(ms, |edge| ms.min(edge));
+            cx.shared.timer0.lock(|t| t.start(ms.millis()));
         }
     }
 
-    #[task(binds=TG1_T0_LEVEL, local=[rgb_led], shared=[reference_times, timer1,rgb_state])]
+    /// Black for the pixels past `len` that the strip showed before it got
+    /// shorter, they'd keep their last colour otherwise
+    fn shrunk_tail(shown: &mut usize, len: usize) -> impl Iterator<Item = RGB<u8>> {
+        let tail = shown.saturating_sub(len);
+        *shown = len;
+        core::iter::repeat(RGB { r: 0, g: 0, b: 0 }).take(tail)
+    }
+
+    #[task(binds=TG1_T0_LEVEL, local=[rgb_led, shown: usize = 1], shared=[reference_times, timer1,rgb_state, rgb_schedule, rgb_settings, strip, blink_jobs, night])]
     fn update_rgb(mut cx: update_rgb::Context) {
         cx.shared.timer1.lock(|t| t.clear_interrupt());
 
@@ -447,44 +967,83 @@ mod app {
             RgbState::Off => false,
         });
 
-        if state {
-            let time_now = cx.shared.reference_times.lock(|r| r.get_time());
-            let hours = time_now / 3600 % 24;
-
-            let color = match hours {
-                x if (3..9).contains(&x) => RGB {
-                    r: 0xF8,
-                    g: 0xF3,
-                    b: 0x2B,
-                },
-                x if (9..15).contains(&x) => RGB {
-                    r: 0x9C,
-                    g: 0xFF,
-                    b: 0xFA,
-                },
-                x if (15..21).contains(&x) => RGB {
-                    r: 0x05,
-                    g: 0x3C,
-                    b: 0x5E,
-                },
-                x if !(3..21).contains(&x) => RGB {
-                    r: 0x31,
-                    g: 0x08,
-                    b: 0x1F,
-                },
-                _ => RGB { r: 0, g: 0, b: 0 },
-            };
+        let (utc_now, tz, time_set) = cx
+            .shared
+            .reference_times
+            .lock(|r| (r.get_time(), r.time_zone(), r.is_set()));
+        /* night mode caps the brightness or turns it down to 0 */
+        let settings = cx.shared.rgb_settings.lock(|s| *s);
+        let (level, night_change) = cx.shared.night.lock(|night| {
+            (
+                night.brightness(settings.brightness, utc_now, &tz, time_set),
+                night.next_change(utc_now, &tz, time_set),
+            )
+        });
+        let scheduled = cx.shared.rgb_schedule.lock(|s| {
+            if let Some(runs) = s {
+                if !runs.advance(utc_now, &tz) {
+                    *s = None;
+                }
+            }
+            *s
+        });
 
+        /* a blink job on the RGB LED comes before everything else, the blink
+         * task wakes us again when it changes */
+        if let Schedule::Blink {
+            on,
+            target: BlinkTarget::Rgb(color),
+            ..
+        } = cx
+            .shared
+            .blink_jobs
+            .lock(|jobs| jobs.schedule(utc_now, BlinkOutput::Rgb))
+        {
+            let Color { r, g, b } = if on { color } else { Color::BLACK };
+            let len = cx.shared.strip.lock(|strip| strip.pixel_count());
             cx.local
                 .rgb_led
-                .write(brightness([color].into_iter(), 20))
+                .write(
+                    brightness(core::iter::repeat(RGB { r, g, b }).take(len), level)
+                        .chain(shrunk_tail(cx.local.shown, len)),
+                )
                 .unwrap();
-            cx.shared.timer1.lock(|t| t.start(1u64.secs()));
+            return;
+        }
+
+        if state || scheduled.is_some_and(|runs| runs.active(utc_now)) {
+            let rgb_led = cx.local.rgb_led;
+            let shown = cx.local.shown;
+            let frame = cx.shared.strip.lock(|strip| {
+                let (frame, colors) = strip.render(&settings, utc_now, &tz);
+                let pixels = colors.map(|Color { r, g, b }| RGB { r, g, b });
+                let tail = shrunk_tail(shown, strip.pixel_count());
+                let written = if frame.gamma {
+                    rgb_led.write(brightness(gamma(pixels), level).chain(tail))
+                } else {
+                    rgb_led.write(brightness(pixels, level).chain(tail))
+                };
+                written.unwrap();
+                frame
+            });
+            /* animations come back for their next frame, the rest once a
+             * second for the time of day, and both when night starts or ends */
+            let wait = night_change.map_or(frame.next_ms, |edge| frame.next_ms.min(edge));
+            cx.shared.timer1.lock(|t| t.start(wait.millis()));
         } else {
+            let len = cx.shared.strip.lock(|strip| strip.pixel_count());
             cx.local
                 .rgb_led
-                .write(brightness([RGB { r: 0, g: 0, b: 0 }].into_iter(), 0))
+                .write(
+                    brightness(core::iter::repeat(RGB { r: 0, g: 0, b: 0 }).take(len), 0)
+                        .chain(shrunk_tail(cx.local.shown, len)),
+                )
                 .unwrap();
+            /* come back right at the start of the next scheduled run */
+            if let Some(runs) = scheduled {
+                let wait = (runs.start - utc_now).min(1000);
+                cx.shared.timer1.lock(|t| t.start(wait.millis()));
+            }
         }
     }
 }
@@ -0,0 +1,56 @@
+//! Retry pacing for long running host tools
+//!
+//! After a failure the next attempt comes soon, after repeated failures the
+//! wait doubles up to a limit so an unplugged board or a dead bridge isn't
+//! hammered with requests.
+
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    first: Duration,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    /// Wait `first` after the first failure, never more than `max`
+    pub fn new(first: Duration, max: Duration) -> Self {
+        Backoff {
+            first,
+            max: max.max(first),
+            failures: 0,
+        }
+    }
+
+    /// The link works again
+    pub fn success(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Another failure, returns how long to wait before trying again
+    pub fn failure(&mut self) -> Duration {
+        let delay = self
+            .first
+            .checked_mul(1 << self.failures.min(16))
+            .map_or(self.max, |d| d.min(self.max));
+        self.failures = self.failures.saturating_add(1);
+        delay
+    }
+
+    /// Failures in a row so far
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+#[test]
+fn backoff_doubles_up_to_max() {
+    let mut b = Backoff::new(Duration::from_secs(2), Duration::from_secs(10));
+    let delays: Vec<u64> = (0..5).map(|_| b.failure().as_secs()).collect();
+    assert_eq!(delays, [2, 4, 8, 10, 10]);
+    assert_eq!(b.failures(), 5);
+
+    b.success();
+    assert_eq!(b.failure(), Duration::from_secs(2));
+}
@@ -0,0 +1,208 @@
+//! serial to TCP bridge
+//!
+//! Run on the machine the board is plugged into `cd host`
+//!
+//! cargo run --bin bridge -- --listen 0.0.0.0:7878
+//!
+//! and point the host application at it
+//!
+//! cargo run -- tcp://benchpc:7878
+//!
+//! Whole frames are forwarded, and only one request at a time is outstanding
+//! at the device, so any number of clients can share one device without their
+//! bytes interleaving. Responses go back to the client that asked, events are
+//! passed on to every client. Without a board at hand use `--simulate` and
+//! connect over the loopback interface.
+
+use clap::Parser;
+use host::frame::{decode_frame, read_frame};
+use host::{open, open_path, sim, Transport};
+use shared::{deserialize_crc_cobs, Command, DeviceMessage, IN_SIZE, OUT_SIZE};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Write halves of the connected clients
+type Clients = Arc<Mutex<HashMap<usize, TcpStream>>>;
+
+/// How long the device gets to answer a request
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(1000);
+/// How long to listen to the device before checking for new requests
+const POLL: Duration = Duration::from_millis(20);
+
+#[derive(Parser)]
+#[command(about = "Share the device over TCP")]
+struct Args {
+    /// Serial port of the device, defaults to the one `host` uses
+    #[arg(short, long)]
+    device: Option<String>,
+
+    /// Address to accept clients on
+    #[arg(short, long, default_value = "127.0.0.1:7878")]
+    listen: SocketAddr,
+
+    /// Serve a simulated device instead of a real one
+    #[arg(long, conflicts_with = "device")]
+    simulate: bool,
+}
+
+/// A frame from a client on its way to the device
+struct Request {
+    client: usize,
+    frame: Vec<u8>,
+    expects_response: bool,
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+
+    let mut device: Box<dyn Transport + Send> = if args.simulate {
+        Box::new(sim::spawn())
+    } else if let Some(path) = &args.device {
+        Box::new(open_path(path)?)
+    } else {
+        Box::new(open()?)
+    };
+    device.set_read_timeout(POLL)?;
+
+    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+    let (requests, pending) = mpsc::channel();
+    {
+        let clients = clients.clone();
+        thread::spawn(move || run_device(device, pending, clients));
+    }
+
+    let listener = TcpListener::bind(args.listen)?;
+    println!("bridge listening on {}", listener.local_addr()?);
+
+    for (id, client) in listener.incoming().enumerate() {
+        let client = match client.and_then(|c| c.set_nodelay(true).map(|_| c)) {
+            Ok(client) => client,
+            Err(e) => {
+                println!("accept failed: {}", e);
+                continue;
+            }
+        };
+
+        match client.try_clone() {
+            Ok(writer) => clients.lock().unwrap().insert(id, writer),
+            Err(e) => {
+                println!("accept failed: {}", e);
+                continue;
+            }
+        };
+
+        let clients = clients.clone();
+        let requests = requests.clone();
+        thread::spawn(move || {
+            let peer = client.peer_addr().ok();
+            println!("{:?} connected", peer);
+            if let Err(e) = serve(id, client, requests) {
+                println!("{:?} dropped: {}", peer, e);
+            }
+            clients.lock().unwrap().remove(&id);
+            println!("{:?} disconnected", peer);
+        });
+    }
+
+    Ok(())
+}
+
+/// Queue frames from one client until it disconnects
+fn serve(id: usize, mut client: TcpStream, requests: Sender<Request>) -> io::Result<()> {
+    let mut buf = [0u8; OUT_SIZE];
+    loop {
+        let n = read_frame(&mut client, &mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+
+        /* anything we can't make sense of gets a NotOk from the device */
+        let mut cobs = [0u8; OUT_SIZE];
+        let decoded = decode_frame(&buf[0..n], &mut cobs);
+        let expects_response = deserialize_crc_cobs::<Command>(&mut cobs[0..decoded.len])
+            .map(|cmd| cmd.expects_response())
+            .unwrap_or(true);
+
+        let request = Request {
+            client: id,
+            frame: buf[0..n].to_vec(),
+            expects_response,
+        };
+        if requests.send(request).is_err() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+    }
+}
+
+/// Owns the device, one outstanding request at a time
+fn run_device(mut device: Box<dyn Transport + Send>, pending: Receiver<Request>, clients: Clients) {
+    let mut buf = [0u8; IN_SIZE];
+    /* client waiting for a response, and until when */
+    let mut waiting: Option<(usize, Instant)> = None;
+
+    loop {
+        if waiting.is_none() {
+            match pending.try_recv() {
+                Ok(request) => {
+                    let res = device
+                        .write_all(&request.frame)
+                        .and_then(|_| device.flush());
+                    match res {
+                        Ok(_) if request.expects_response => {
+                            waiting = Some((request.client, Instant::now() + RESPONSE_TIMEOUT));
+                        }
+                        Ok(_) => {}
+                        /* the client times out and retries, same as with a direct link */
+                        Err(e) => println!("writing to device failed: {}", e),
+                    }
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        match read_frame(&mut *device, &mut buf) {
+            Ok(0) => reconnect(&mut *device, io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                let mut cobs = [0u8; IN_SIZE];
+                let decoded = decode_frame(&buf[0..n], &mut cobs);
+                let msg = deserialize_crc_cobs::<DeviceMessage>(&mut cobs[0..decoded.len]);
+
+                if let Ok(DeviceMessage::Event { .. }) = msg {
+                    let mut clients = clients.lock().unwrap();
+                    for client in clients.values_mut() {
+                        let _ = client.write_all(&buf[0..n]);
+                    }
+                } else if let Some((client, _)) = waiting.take() {
+                    /* damaged responses are passed on too, the client retries */
+                    if let Some(client) = clients.lock().unwrap().get_mut(&client) {
+                        let _ = client.write_all(&buf[0..n]);
+                    }
+                }
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock =>
+            {
+                if matches!(waiting, Some((_, deadline)) if Instant::now() >= deadline) {
+                    println!("device did not answer");
+                    waiting = None;
+                }
+            }
+            Err(e) => reconnect(&mut *device, e),
+        }
+    }
+}
+
+/// The port itself is broken, maybe the board was replugged
+fn reconnect(device: &mut (dyn Transport + Send), e: io::Error) {
+    println!("device link failed: {}, reconnecting", e);
+    thread::sleep(RESPONSE_TIMEOUT);
+    if let Err(e) = device.reconnect() {
+        println!("reconnect failed: {}", e);
+    }
+}
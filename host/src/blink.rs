@@ -0,0 +1,217 @@
+//! Reading blink specs the way people type them
+//!
+//! A spec is a rate or period with an optional duty cycle, like `2hz`,
+//! `0.5 hz 10%` or `1.5s 25%`, or an on/off pattern in ms starting with on,
+//! like `pattern 100 100 100 100 300 500`. How long it runs is a duration as
+//! [`parse_duration`] reads it or a number of repetitions, like `3x`. The
+//! LED it blinks is `led` or `rgb` with a colour, like `rgb #ff0000`. On the
+//! blink LED a step can be `square` or fade in and out, like `fade 200ms` for
+//! both or `fade 100ms 400ms` for in and out apiece.
+
+use crate::rgb::parse_color;
+use crate::when::{parse_duration, ParseError};
+use shared::pwm::BlinkShape;
+use shared::{BlinkLength, BlinkSpec, BlinkTarget, MAX_PATTERN_STEPS};
+use std::fmt::Write;
+
+/// `2hz`, `0.5 hz 10%`, `500ms`, `1.5s 25%` or `pattern 100 100 300 500`
+pub fn parse_blink_spec(input: &str) -> Result<BlinkSpec, ParseError> {
+    let input = input.trim().to_lowercase();
+    let unrecognised = || ParseError::Unrecognised(input.clone());
+    if input.is_empty() {
+        return Err(ParseError::Empty);
+    }
+
+    if let Some(rest) = input.strip_prefix("pattern") {
+        let steps = rest
+            .split_whitespace()
+            .map(|s| s.parse::<u16>().map_err(|_| unrecognised()))
+            .collect::<Result<Vec<_>, _>>()?;
+        return BlinkSpec::pattern(&steps)
+            .filter(|spec| spec.is_valid())
+            .ok_or(ParseError::OutOfRange);
+    }
+
+    let (rate, duty_percent) = match input.rsplit_once(' ') {
+        Some((rate, duty)) if duty.ends_with('%') => {
+            let duty = duty.trim_end_matches('%').parse::<u8>();
+            (rate, duty.map_err(|_| unrecognised())?)
+        }
+        _ => (input.as_str(), 50),
+    };
+    let period_ms = match rate.strip_suffix("hz") {
+        Some(hz) => {
+            let hz: f64 = hz.trim().parse().map_err(|_| unrecognised())?;
+            (1000.0 / hz).round()
+        }
+        None => (parse_duration(rate)?.as_secs_f64() * 1000.0).round(),
+    };
+    if !(1.0..=u32::MAX as f64).contains(&period_ms) || duty_percent > 100 {
+        return Err(ParseError::OutOfRange);
+    }
+    Ok(BlinkSpec::Periodic {
+        period_ms: period_ms as u32,
+        duty_percent,
+    })
+}
+
+/// `3x` or `3 times` for repetitions, anything else is a duration
+pub fn parse_blink_length(input: &str) -> Result<BlinkLength, ParseError> {
+    let input = input.trim().to_lowercase();
+    let times = input
+        .strip_suffix('x')
+        .or_else(|| input.strip_suffix("times"));
+    if let Some(times) = times {
+        return times
+            .trim()
+            .parse()
+            .map(BlinkLength::Repetitions)
+            .map_err(|_| ParseError::Unrecognised(input.clone()));
+    }
+    Ok(BlinkLength::Duration(
+        parse_duration(&input)?.as_millis() as u64
+    ))
+}
+
+/// `led`, or `rgb` and a colour as [`parse_color`] reads it
+pub fn parse_blink_target(input: &str) -> Result<BlinkTarget, ParseError> {
+    let input = input.trim().to_lowercase();
+    match input.as_str() {
+        "" => Err(ParseError::Empty),
+        "led" => Ok(BlinkTarget::Led),
+        _ => match input.strip_prefix("rgb") {
+            Some(color) => parse_color(color).map(BlinkTarget::Rgb),
+            None => Err(ParseError::Unrecognised(input.clone())),
+        },
+    }
+}
+
+/// The way [`parse_blink_target`] reads it
+pub fn describe_target(target: &BlinkTarget) -> String {
+    match target {
+        BlinkTarget::Led => "led".to_string(),
+        BlinkTarget::Rgb(c) => format!("rgb #{:02x}{:02x}{:02x}", c.r, c.g, c.b),
+    }
+}
+
+/// `square`, `fade <in and out>` or `fade <in> <out>`
+pub fn parse_blink_shape(input: &str) -> Result<BlinkShape, ParseError> {
+    let input = input.trim().to_lowercase();
+    let ms =
+        |s: &str| u16::try_from(parse_duration(s)?.as_millis()).map_err(|_| ParseError::OutOfRange);
+    match input.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => Err(ParseError::Empty),
+        ["square"] => Ok(BlinkShape::Square),
+        ["fade", both] => Ok(BlinkShape::Fade {
+            in_ms: ms(both)?,
+            out_ms: ms(both)?,
+        }),
+        ["fade", fade_in, fade_out] => Ok(BlinkShape::Fade {
+            in_ms: ms(fade_in)?,
+            out_ms: ms(fade_out)?,
+        }),
+        _ => Err(ParseError::Unrecognised(input.clone())),
+    }
+}
+
+/// The way [`parse_blink_shape`] reads it
+pub fn describe_shape(shape: &BlinkShape) -> String {
+    match shape {
+        BlinkShape::Square => "square".to_string(),
+        BlinkShape::Fade { in_ms, out_ms } => format!("fade {}ms {}ms", in_ms, out_ms),
+    }
+}
+
+/// Short description of `spec`, periodic ones and patterns the way
+/// [`parse_blink_spec`] reads them
+pub fn describe_spec(spec: &BlinkSpec) -> String {
+    match *spec {
+        BlinkSpec::Periodic {
+            period_ms,
+            duty_percent,
+        } => format!("{} ms {}%", period_ms, duty_percent),
+        BlinkSpec::Pattern { steps, len } => {
+            let mut s = "pattern".to_string();
+            for step in &steps[..(len as usize).min(MAX_PATTERN_STEPS)] {
+                let _ = write!(s, " {}", step);
+            }
+            s
+        }
+        BlinkSpec::Morse { text, wpm } => format!(
+            "morse \"{}\" at {} wpm",
+            String::from_utf8_lossy(text.as_bytes()),
+            wpm
+        ),
+    }
+}
+
+#[test]
+fn blink_spec_parsing() {
+    let periodic = |period_ms, duty_percent| {
+        Ok(BlinkSpec::Periodic {
+            period_ms,
+            duty_percent,
+        })
+    };
+    assert_eq!(parse_blink_spec("2Hz"), periodic(500, 50));
+    assert_eq!(parse_blink_spec("0.5 hz 10%"), periodic(2_000, 10));
+    assert_eq!(parse_blink_spec("1.5s 25%"), periodic(1_500, 25));
+    assert_eq!(parse_blink_spec("250ms"), periodic(250, 50));
+    assert_eq!(
+        parse_blink_spec("pattern 100 100 300 500"),
+        Ok(BlinkSpec::pattern(&[100, 100, 300, 500]).unwrap())
+    );
+
+    assert_eq!(parse_blink_spec("1s 120%"), Err(ParseError::OutOfRange));
+    assert_eq!(parse_blink_spec("0hz"), Err(ParseError::OutOfRange));
+    assert_eq!(
+        parse_blink_spec("pattern 1 2 3 4 5 6 7 8 9"),
+        Err(ParseError::OutOfRange)
+    );
+    assert!(parse_blink_spec("fast").is_err());
+
+    let spec = parse_blink_spec("pattern 100 50").unwrap();
+    assert_eq!(parse_blink_spec(&describe_spec(&spec)), Ok(spec));
+    assert_eq!(describe_spec(&BlinkSpec::hz(4.0)), "250 ms 50%");
+}
+
+#[test]
+fn blink_length_parsing() {
+    assert_eq!(parse_blink_length("3x"), Ok(BlinkLength::Repetitions(3)));
+    assert_eq!(
+        parse_blink_length("10 times"),
+        Ok(BlinkLength::Repetitions(10))
+    );
+    assert_eq!(parse_blink_length("1m"), Ok(BlinkLength::Duration(60_000)));
+    assert!(parse_blink_length("many times").is_err());
+}
+
+#[test]
+fn blink_target_parsing() {
+    use shared::rgb::Color;
+
+    let red = BlinkTarget::Rgb(Color { r: 255, g: 0, b: 0 });
+    assert_eq!(parse_blink_target("LED"), Ok(BlinkTarget::Led));
+    assert_eq!(parse_blink_target("rgb #ff0000"), Ok(red));
+    assert_eq!(parse_blink_target("rgb 255 0 0"), Ok(red));
+    assert_eq!(parse_blink_target(&describe_target(&red)), Ok(red));
+
+    assert_eq!(parse_blink_target(""), Err(ParseError::Empty));
+    assert_eq!(parse_blink_target("rgb"), Err(ParseError::Empty));
+    assert!(parse_blink_target("strip").is_err());
+}
+
+#[test]
+fn blink_shape_parsing() {
+    let fade = |in_ms, out_ms| Ok(BlinkShape::Fade { in_ms, out_ms });
+    assert_eq!(parse_blink_shape("Square"), Ok(BlinkShape::Square));
+    assert_eq!(parse_blink_shape("fade 200ms"), fade(200, 200));
+    assert_eq!(parse_blink_shape("fade 0.1s 400ms"), fade(100, 400));
+    let shape = fade(100, 400).unwrap();
+    assert_eq!(parse_blink_shape(&describe_shape(&shape)), Ok(shape));
+
+    assert_eq!(parse_blink_shape("fade 2m"), Err(ParseError::OutOfRange));
+    assert_eq!(parse_blink_shape(""), Err(ParseError::Empty));
+    assert!(parse_blink_shape("fade").is_err());
+    assert!(parse_blink_shape("sine 1s").is_err());
+}
@@ -0,0 +1,915 @@
+//! Blocking client for the device protocol
+//!
+//! ```no_run
+//! let mut client = host::DeviceClient::new(host::open()?);
+//! client.set_datetime(shared::DateTime::Utc(1_700_000_000_000))?;
+//! client.rgb_on()?;
+//! # Ok::<(), host::ClientError>(())
+//! ```
+
+use crate::frame::{decode_frame, read_frame};
+use crate::host_time_ms;
+use crate::transport::Transport;
+use shared::{
+    deserialize_crc_cobs,
+    morse::MorseText,
+    night::{NightOverride, NightStatus, NightWindow},
+    rgb::{Animation, Color, Palette, RgbMode},
+    serialize_crc_cobs,
+    strip::PixelChunk,
+    sun::Location,
+    timesync::SyncSample,
+    tz::TimeZone,
+    Ack, BlinkJob, BlinkerOptions, Command, DateTime, DeserializeError, DeviceMessage, Drift,
+    Event, EventSeq, JobId, RequestId, RgbSchedule, Slew, IN_SIZE, OUT_SIZE,
+};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// How many times a request is sent before giving up
+pub const DEFAULT_ATTEMPTS: u32 = 3;
+
+/// How long to wait for each response by default
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// How many round trips a time sync measures
+pub const SYNC_SAMPLES: usize = 4;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// the transport failed, timeouts included
+    Io(io::Error),
+    /// the other end closed the connection
+    Disconnected,
+    /// the response had more bit errors than hamming can fix
+    Corrupted,
+    /// the response was decoded but failed the cobs, serde or crc checks
+    Decode(DeserializeError),
+    /// the device answered `NotOk` to every attempt
+    Rejected,
+    /// the device answered with a different kind of message than asked for
+    Unexpected,
+}
+
+impl ClientError {
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, ClientError::Io(e)
+            if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "transport error: {}", e),
+            ClientError::Disconnected => write!(f, "device disconnected"),
+            ClientError::Corrupted => write!(f, "response corrupted beyond repair"),
+            ClientError::Decode(e) => write!(f, "malformed response: {:?}", e),
+            ClientError::Rejected => write!(f, "device rejected the command"),
+            ClientError::Unexpected => write!(f, "unexpected response"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, ClientError>;
+
+/// A new [`RequestId`] for a command that schedules a blink, the same one
+/// goes out again on retries so the device makes the job only once
+pub(crate) fn next_request() -> RequestId {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    /* from the clock, so a restarted host doesn't start where it did before */
+    let _ = NEXT.compare_exchange(
+        0,
+        host_time_ms() as u32,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Remembered event sequence numbers, to drop retransmissions we already have
+const SEEN_EVENTS: usize = 16;
+
+/// Event bookkeeping of both clients, which events to ack and which are new
+pub(crate) struct EventTracker {
+    seen: VecDeque<EventSeq>,
+    acks_due: Vec<EventSeq>,
+}
+
+impl EventTracker {
+    pub(crate) fn new() -> Self {
+        EventTracker {
+            seen: VecDeque::with_capacity(SEEN_EVENTS),
+            acks_due: Vec::new(),
+        }
+    }
+
+    /// Schedule `seq` for acking, returns whether the event is new
+    pub(crate) fn accept(&mut self, seq: EventSeq, event: Event) -> bool {
+        /* ack again even if we've seen it, our previous ack was lost */
+        self.acks_due.push(seq);
+        if event == Event::Boot {
+            /* the device counts from 0 again, what we've seen is stale */
+            self.seen.clear();
+        }
+        if self.seen.contains(&seq) {
+            return false;
+        }
+        if self.seen.len() == SEEN_EVENTS {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(seq);
+        true
+    }
+
+    /// Next event to ack
+    pub(crate) fn ack_due(&mut self) -> Option<EventSeq> {
+        self.acks_due.pop()
+    }
+}
+
+/// Owns a transport plus the buffers and retry policy needed to talk to a device
+///
+/// Events the device sends in between responses are acknowledged and kept
+/// until collected with [`DeviceClient::next_event`].
+pub struct DeviceClient<T: Transport> {
+    port: T,
+    out_buf: [u8; OUT_SIZE],
+    raw_buf: [u8; IN_SIZE],
+    in_buf: [u8; IN_SIZE],
+    attempts: u32,
+    timeout: Duration,
+    bitflip_next: bool,
+    events: VecDeque<(EventSeq, Event)>,
+    tracker: EventTracker,
+}
+
+impl<T: Transport> DeviceClient<T> {
+    pub fn new(port: T) -> Self {
+        DeviceClient {
+            port,
+            out_buf: [0; OUT_SIZE],
+            raw_buf: [0; IN_SIZE],
+            in_buf: [0; IN_SIZE],
+            attempts: DEFAULT_ATTEMPTS,
+            timeout: DEFAULT_TIMEOUT,
+            bitflip_next: false,
+            events: VecDeque::new(),
+            tracker: EventTracker::new(),
+        }
+    }
+
+    /// Send every request at most `attempts` times (at least once)
+    pub fn set_attempts(&mut self, attempts: u32) {
+        self.attempts = attempts.max(1);
+    }
+
+    /// How long to wait for each response
+    pub fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        self.port.set_read_timeout(timeout)
+    }
+
+    /// Flip a bit in the next request, to see hamming recovery in action
+    pub fn corrupt_next(&mut self) {
+        self.bitflip_next = true;
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.port
+    }
+
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    pub fn rgb_on(&mut self) -> Result<Ack> {
+        self.request(&Command::RgbOn)
+    }
+
+    pub fn rgb_off(&mut self) -> Result<Ack> {
+        self.request(&Command::RgbOff)
+    }
+
+    /// Show `color` instead of the palette and turn the LED on
+    pub fn set_rgb(&mut self, color: Color, brightness: u8) -> Result<Ack> {
+        let Color { r, g, b } = color;
+        self.request(&Command::SetRgb {
+            r,
+            g,
+            b,
+            brightness,
+        })
+    }
+
+    pub fn set_brightness(&mut self, brightness: u8) -> Result<Ack> {
+        self.request(&Command::SetBrightness(brightness))
+    }
+
+    /// Back to the palette or to the last colour, the palette is rejected
+    /// until the time is set
+    pub fn set_rgb_mode(&mut self, mode: RgbMode) -> Result<Ack> {
+        self.request(&Command::SetRgbMode(mode))
+    }
+
+    /// Animate the LED and turn it on, the time doesn't need to be set
+    pub fn animate(&mut self, animation: Animation) -> Result<Ack> {
+        self.request(&Command::Animate(animation))
+    }
+
+    /// Number of pixels when the RGB LED is a strip
+    pub fn set_strip_length(&mut self, len: u16) -> Result<Ack> {
+        self.request(&Command::SetStripLength(len))
+    }
+
+    /// Colour `len` pixels from `start` on and show the strip's own pixels
+    pub fn fill_pixels(&mut self, start: u16, len: u16, color: Color) -> Result<Ack> {
+        self.request(&Command::FillPixels { start, len, color })
+    }
+
+    /// Show a whole frame, sent in chunks that each fit a command. Nothing
+    /// changes on the strip until the last one is in.
+    pub fn push_frame(&mut self, frame: &[Color]) -> Result<Ack> {
+        /* an empty frame would be shown as nothing at all */
+        let mut ack = Err(ClientError::Rejected);
+        for chunk in PixelChunk::split(frame) {
+            ack = Ok(self.request(&Command::PixelChunk(chunk))?);
+        }
+        ack
+    }
+
+    /// Where the device is, [`RgbMode::Sun`] is rejected until it is set
+    pub fn set_location(&mut self, location: Location) -> Result<Ack> {
+        self.request(&Command::SetLocation(location))
+    }
+
+    /// Replace the time of day palette, used in [`RgbMode::Palette`]
+    pub fn set_palette(&mut self, palette: Palette) -> Result<Ack> {
+        self.request(&Command::SetPalette(palette))
+    }
+
+    /// Schedule a blink, returns the ID of its job or `None` for
+    /// [`BlinkerOptions::Off`], which cancels every job
+    pub fn set_blinker(&mut self, options: BlinkerOptions) -> Result<Option<JobId>> {
+        let request = next_request();
+        match self.transact(&Command::SetBlinker { options, request })? {
+            DeviceMessage::BlinkScheduled(id) => Ok(Some(id)),
+            DeviceMessage::Ack(_) => Ok(None),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    /// Flash `text` once on the blink LED at `wpm` words per minute, returns
+    /// the ID of its blink job
+    pub fn morse(&mut self, date_time: DateTime, text: MorseText, wpm: u8) -> Result<JobId> {
+        match self.transact(&Command::Morse {
+            date_time,
+            text,
+            wpm,
+            request: next_request(),
+        })? {
+            DeviceMessage::BlinkScheduled(id) => Ok(id),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    /// When the RGB LED comes on by itself, replaces the previous schedule
+    pub fn schedule_rgb(&mut self, schedule: RgbSchedule) -> Result<Ack> {
+        self.request(&Command::ScheduleRgb(schedule))
+    }
+
+    /// Blink jobs waiting to start or in progress
+    pub fn blink_jobs(&mut self) -> Result<Vec<BlinkJob>> {
+        match self.transact(&Command::ListBlinks)? {
+            DeviceMessage::BlinkJobs(jobs) => Ok(jobs.into_iter().flatten().collect()),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    /// Drop a blink job, rejected if there is no such job (anymore)
+    pub fn cancel_blink(&mut self, id: JobId) -> Result<Ack> {
+        self.request(&Command::CancelBlink(id))
+    }
+
+    pub fn set_datetime(&mut self, date_time: DateTime) -> Result<Ack> {
+        self.request(&Command::SetDateTime(date_time))
+    }
+
+    /// Measure how far the device clock is off from ours and correct it
+    ///
+    /// Takes [`SYNC_SAMPLES`] round trips and trusts the one with the shortest
+    /// delay. Returns that sample, i.e. the offset before the correction.
+    pub fn sync_time(&mut self) -> Result<SyncSample> {
+        let mut samples = Vec::with_capacity(SYNC_SAMPLES);
+        let mut tag = 0;
+        for _ in 0..SYNC_SAMPLES {
+            let cmd = Command::SyncTime { t1: host_time_ms() };
+            /* t1 comes back too, a late answer to a retry still adds up */
+            match self.transact(&cmd)? {
+                DeviceMessage::TimeSync { t1, t2, t3 } => {
+                    tag = t1;
+                    samples.push(SyncSample::new(t1, t2, t3, host_time_ms()))
+                }
+                _ => return Err(ClientError::Unexpected),
+            }
+        }
+
+        let best = SyncSample::best(samples).unwrap();
+        /* tagged, so a retry after a lost ack doesn't correct twice */
+        let adjust = Command::AdjustTime {
+            offset: -best.offset_ms,
+            t1: tag,
+        };
+        self.request(&adjust)?;
+        Ok(best)
+    }
+
+    /// Zone the device uses for everything that goes by the hour of day
+    pub fn set_time_zone(&mut self, time_zone: TimeZone) -> Result<Ack> {
+        self.request(&Command::SetTimeZone(time_zone))
+    }
+
+    /// Spread small time corrections out instead of stepping the clock
+    pub fn set_slew(&mut self, slew: Slew) -> Result<Ack> {
+        self.request(&Command::SetSlew(slew))
+    }
+
+    /// Rate correction the device currently applies to its clock
+    pub fn drift(&mut self) -> Result<Drift> {
+        match self.transact(&Command::GetDrift)? {
+            DeviceMessage::Drift(drift) => Ok(drift),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    /// Dim or turn off the LEDs between two local times, `None` turns night
+    /// mode off
+    pub fn set_night_mode(&mut self, window: Option<NightWindow>) -> Result<Ack> {
+        self.request(&Command::SetNightMode(window))
+    }
+
+    /// Force night or day until the night window next starts or ends
+    pub fn night_override(&mut self, forced: NightOverride) -> Result<Ack> {
+        self.request(&Command::NightOverride(forced))
+    }
+
+    pub fn night_mode(&mut self) -> Result<NightStatus> {
+        match self.transact(&Command::GetNightMode)? {
+            DeviceMessage::NightMode(status) => Ok(status),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    /// Duty cycle in percent the blink LED is on at
+    pub fn set_led_brightness(&mut self, percent: u8) -> Result<Ack> {
+        self.request(&Command::SetLedBrightness(percent))
+    }
+
+    /// Send `cmd` and wait for it to be accepted
+    ///
+    /// Rejections, timeouts and damaged responses are retried. On success the
+    /// ack is either `Ok` or `Recovered`. Commands the device doesn't answer
+    /// are sent once and reported as `Ok`.
+    pub fn request(&mut self, cmd: &Command) -> Result<Ack> {
+        match self.transact(cmd)? {
+            DeviceMessage::Ack(ack) => Ok(ack),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    /// Like [`DeviceClient::request`], for commands answered with something
+    /// other than an ack
+    pub fn transact(&mut self, cmd: &Command) -> Result<DeviceMessage> {
+        let res = self.transact_inner(cmd);
+        /* only now, so our acks never land in the middle of an exchange */
+        self.send_acks()?;
+        res
+    }
+
+    fn transact_inner(&mut self, cmd: &Command) -> Result<DeviceMessage> {
+        let n = serialize_crc_cobs(cmd, &mut self.out_buf).len();
+        if self.bitflip_next {
+            self.out_buf[2] ^= 1 << 1;
+            self.bitflip_next = false;
+        }
+
+        if !cmd.expects_response() {
+            self.port.write_all(&self.out_buf[0..n])?;
+            self.port.flush()?;
+            return Ok(DeviceMessage::Ack(Ack::Ok));
+        }
+
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            let res = self.exchange(n);
+            match res {
+                Ok(DeviceMessage::Ack(Ack::NotOk)) if tries < self.attempts => {}
+                Ok(DeviceMessage::Ack(Ack::NotOk)) => return Err(ClientError::Rejected),
+                Ok(msg) => return Ok(msg),
+                Err(ClientError::Disconnected) => return res,
+                Err(_) if tries < self.attempts => {}
+                Err(_) => return res,
+            }
+        }
+    }
+
+    /// Next event that arrived during an earlier request, if any
+    pub fn take_event(&mut self) -> Option<(EventSeq, Event)> {
+        self.events.pop_front()
+    }
+
+    /// Wait up to `timeout` for the next event
+    ///
+    /// Returns events that arrived during earlier requests first. Afterwards the
+    /// response timeout is back at what was last passed to
+    /// [`DeviceClient::set_timeout`], one second if never called.
+    pub fn next_event(&mut self, timeout: Duration) -> Result<Option<(EventSeq, Event)>> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(Some(event));
+        }
+
+        self.port.set_read_timeout(timeout)?;
+        let res = loop {
+            match self.receive() {
+                Ok(DeviceMessage::Event { .. }) => {
+                    /* nothing new if it was a retransmission */
+                    if let Some(event) = self.events.pop_front() {
+                        break Ok(Some(event));
+                    }
+                }
+                /* a late answer to something that already timed out */
+                Ok(_) => {}
+                Err(e) if e.is_timeout() => break Ok(None),
+                Err(ClientError::Corrupted) | Err(ClientError::Decode(_)) => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.port.set_read_timeout(self.timeout)?;
+        self.send_acks()?;
+        res
+    }
+
+    fn exchange(&mut self, n: usize) -> Result<DeviceMessage> {
+        self.port.write_all(&self.out_buf[0..n])?;
+        self.port.flush()?;
+
+        loop {
+            let msg = self.receive()?;
+            if !matches!(msg, DeviceMessage::Event { .. }) {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Read the next message, events are queued and scheduled for acking
+    /// before being returned
+    fn receive(&mut self) -> Result<DeviceMessage> {
+        let raw = read_frame(&mut self.port, &mut self.raw_buf)?;
+        if raw == 0 {
+            return Err(ClientError::Disconnected);
+        }
+
+        let decoded = decode_frame(&self.raw_buf[0..raw], &mut self.in_buf);
+        if decoded.corrupted {
+            return Err(ClientError::Corrupted);
+        }
+
+        let msg = deserialize_crc_cobs::<DeviceMessage>(&mut self.in_buf[0..decoded.len])
+            .map_err(ClientError::Decode)?;
+
+        if let DeviceMessage::Event { seq, event } = msg {
+            if self.tracker.accept(seq, event) {
+                self.events.push_back((seq, event));
+            }
+        }
+
+        Ok(msg)
+    }
+
+    fn send_acks(&mut self) -> Result<()> {
+        while let Some(seq) = self.tracker.ack_due() {
+            let mut buf = [0u8; OUT_SIZE];
+            let to_write = serialize_crc_cobs(&Command::AckEvent(seq), &mut buf);
+            self.port.write_all(to_write)?;
+        }
+        self.port.flush()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn events_after_reboot() {
+    use crate::transport::memory_pipe;
+    use std::io::Write;
+
+    let (host, mut device) = memory_pipe();
+    let mut client = DeviceClient::new(host);
+    let mut buf = [0u8; IN_SIZE];
+    /* a boot, a press, then a reboot which starts the numbers over */
+    for (seq, event) in [
+        (0, Event::Boot),
+        (1, Event::ButtonPressed),
+        (1, Event::ButtonPressed),
+        (0, Event::Boot),
+        (1, Event::ButtonPressed),
+    ] {
+        let msg = DeviceMessage::Event { seq, event };
+        device
+            .write_all(serialize_crc_cobs(&msg, &mut buf))
+            .unwrap();
+    }
+
+    let mut events = Vec::new();
+    while let Some(event) = client.next_event(Duration::from_millis(50)).unwrap() {
+        events.push(event);
+    }
+    assert_eq!(
+        events,
+        [
+            (0, Event::Boot),
+            (1, Event::ButtonPressed),
+            (0, Event::Boot),
+            (1, Event::ButtonPressed)
+        ]
+    );
+}
+
+/// A client on a freshly booted simulated device
+#[cfg(test)]
+fn sim_client() -> DeviceClient<crate::transport::MemoryTransport> {
+    DeviceClient::new(crate::sim::spawn())
+}
+
+/// Twice at 2 Hz from `date_time`
+#[cfg(test)]
+fn twice(date_time: DateTime, target: shared::BlinkTarget) -> BlinkerOptions {
+    BlinkerOptions::On {
+        date_time,
+        spec: shared::BlinkSpec::hz(2.0),
+        length: shared::BlinkLength::Repetitions(2),
+        repeat: shared::recur::Recurrence::Once,
+        target,
+        shape: shared::pwm::BlinkShape::Square,
+    }
+}
+
+#[cfg(test)]
+const ORANGE: Color = Color {
+    r: 0xFF,
+    g: 0x88,
+    b: 0,
+};
+
+#[test]
+fn sim_boot_event() {
+    let mut client = sim_client();
+    assert_eq!(
+        client.next_event(DEFAULT_TIMEOUT).unwrap(),
+        Some((0, Event::Boot))
+    );
+    assert_eq!(client.next_event(Duration::from_millis(10)).unwrap(), None);
+}
+
+#[test]
+fn sim_rgb_modes() {
+    use shared::recur::Recurrence;
+
+    let mut client = sim_client();
+    /* rgb needs the time to be set first */
+    assert!(matches!(client.rgb_on(), Err(ClientError::Rejected)));
+    let daily = RgbSchedule::On {
+        date_time: DateTime::Now,
+        duration: 60_000,
+        repeat: Recurrence::Daily { at_ms: 0 },
+    };
+    assert!(matches!(
+        client.schedule_rgb(daily),
+        Err(ClientError::Rejected)
+    ));
+    /* a colour of its own doesn't need the time, the palette does */
+    assert_eq!(client.set_rgb(ORANGE, 40).unwrap(), Ack::Ok);
+    assert_eq!(client.rgb_on().unwrap(), Ack::Ok);
+    assert_eq!(client.set_brightness(10).unwrap(), Ack::Ok);
+    assert!(matches!(
+        client.set_rgb_mode(RgbMode::Palette),
+        Err(ClientError::Rejected)
+    ));
+    let rainbow = Animation::Rainbow { period_ms: 5_000 };
+    assert_eq!(client.animate(rainbow).unwrap(), Ack::Ok);
+    assert!(matches!(
+        client.animate(Animation::Rainbow { period_ms: 1 }),
+        Err(ClientError::Rejected)
+    ));
+
+    assert_eq!(
+        client
+            .set_datetime(DateTime::Utc(1_700_000_000_000))
+            .unwrap(),
+        Ack::Ok
+    );
+    assert_eq!(client.set_rgb_mode(RgbMode::Palette).unwrap(), Ack::Ok);
+    assert!(matches!(
+        client.set_rgb_mode(RgbMode::Sun),
+        Err(ClientError::Rejected)
+    ));
+    let london = Location {
+        latitude: 51.5,
+        longitude: -0.13,
+    };
+    assert_eq!(client.set_location(london).unwrap(), Ack::Ok);
+    assert_eq!(client.set_rgb_mode(RgbMode::Sun).unwrap(), Ack::Ok);
+    assert_eq!(client.set_palette(Palette::DEFAULT).unwrap(), Ack::Ok);
+    assert_eq!(client.rgb_on().unwrap(), Ack::Ok);
+    assert_eq!(client.schedule_rgb(daily).unwrap(), Ack::Ok);
+}
+
+#[test]
+fn sim_strip_frames() {
+    let mut client = sim_client();
+    /* a strip takes a frame longer than one command holds */
+    assert_eq!(client.set_strip_length(40).unwrap(), Ack::Ok);
+    assert_eq!(client.push_frame(&[ORANGE; 40]).unwrap(), Ack::Ok);
+    assert!(matches!(
+        client.push_frame(&[ORANGE; 41]),
+        Err(ClientError::Rejected)
+    ));
+    assert_eq!(client.fill_pixels(30, 10, Color::BLACK).unwrap(), Ack::Ok);
+    assert!(matches!(
+        client.fill_pixels(30, 11, Color::BLACK),
+        Err(ClientError::Rejected)
+    ));
+}
+
+#[test]
+fn sim_blink_jobs() {
+    use shared::BlinkTarget;
+
+    let mut client = sim_client();
+    /* blinks relative to boot don't need the time */
+    let after = |seconds| twice(DateTime::After { seconds }, BlinkTarget::Led);
+    let boot_relative = client.set_blinker(after(5)).unwrap().unwrap();
+    assert!(matches!(
+        client.set_blinker(twice(DateTime::Utc(1_700_000_000_000), BlinkTarget::Led)),
+        Err(ClientError::Rejected)
+    ));
+    assert_eq!(
+        client
+            .set_datetime(DateTime::Utc(1_700_000_000_000))
+            .unwrap(),
+        Ack::Ok
+    );
+
+    /* the boot relative job moved along with the clock */
+    let jobs = client.blink_jobs().unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].id, boot_relative);
+    assert!(jobs[0].start > 1_700_000_000_000);
+    /* two periods of half a second */
+    assert_eq!(jobs[0].duration, 1000);
+
+    let later = client.set_blinker(after(60)).unwrap().unwrap();
+    assert_ne!(later, boot_relative);
+    assert_eq!(client.cancel_blink(boot_relative).unwrap(), Ack::Ok);
+    assert!(matches!(
+        client.cancel_blink(boot_relative),
+        Err(ClientError::Rejected)
+    ));
+    let sos = MorseText::new("sos").unwrap();
+    let morse = client.morse(DateTime::Now, sos, 20).unwrap();
+    assert!(matches!(
+        client.morse(DateTime::Now, sos, 0),
+        Err(ClientError::Rejected)
+    ));
+    let mut jobs = client.blink_jobs().unwrap();
+    jobs.sort_by_key(|j| j.id);
+    let ids: Vec<JobId> = jobs.iter().map(|j| j.id).collect();
+    assert_eq!(ids, [later, morse]);
+    /* once through at 60 ms a unit */
+    assert_eq!(jobs[1].duration, 34 * 60);
+
+    assert_eq!(client.set_blinker(BlinkerOptions::Off).unwrap(), None);
+    assert!(client.blink_jobs().unwrap().is_empty());
+}
+
+#[test]
+fn sim_rgb_blink() {
+    use shared::BlinkTarget;
+
+    let mut client = sim_client();
+    /* the RGB LED blinks alongside the plain one */
+    let flash = twice(DateTime::Now, BlinkTarget::Rgb(ORANGE));
+    let flash = client.set_blinker(flash).unwrap().unwrap();
+    let jobs = client.blink_jobs().unwrap();
+    let flash = jobs.iter().find(|j| j.id == flash).unwrap();
+    assert_eq!(flash.target, BlinkTarget::Rgb(ORANGE));
+}
+
+#[test]
+fn sim_night_mode() {
+    use shared::night::NightAction;
+
+    let mut client = sim_client();
+    /* night mode is off until a window is set */
+    assert!(!client.night_mode().unwrap().active);
+    let always = NightWindow {
+        start_ms: 0,
+        end_ms: 24 * 3_600_000 - 1,
+        action: NightAction::Dim(5),
+    };
+    assert_eq!(client.set_night_mode(Some(always)).unwrap(), Ack::Ok);
+    assert!(matches!(
+        client.set_night_mode(Some(NightWindow {
+            end_ms: 0,
+            ..always
+        })),
+        Err(ClientError::Rejected)
+    ));
+    /* it goes by the time of day */
+    assert!(!client.night_mode().unwrap().active);
+    client
+        .set_datetime(DateTime::Utc(1_700_000_000_000))
+        .unwrap();
+    let status = client.night_mode().unwrap();
+    assert_eq!(status.window, Some(always));
+    assert!(status.active);
+    assert_eq!(client.night_override(NightOverride::Day).unwrap(), Ack::Ok);
+    let status = client.night_mode().unwrap();
+    assert_eq!(status.forced, NightOverride::Day);
+    assert!(!status.active);
+    assert_eq!(client.set_night_mode(None).unwrap(), Ack::Ok);
+}
+
+#[test]
+fn sim_led_brightness() {
+    use shared::pwm::BlinkShape;
+    use shared::recur::Recurrence;
+    use shared::{BlinkLength, BlinkSpec, BlinkTarget};
+
+    let mut client = sim_client();
+    /* the blink LED dims and fades */
+    assert_eq!(client.set_led_brightness(40).unwrap(), Ack::Ok);
+    assert!(matches!(
+        client.set_led_brightness(101),
+        Err(ClientError::Rejected)
+    ));
+    let breathe = BlinkShape::Fade {
+        in_ms: 200,
+        out_ms: 200,
+    };
+    let breathing = BlinkerOptions::On {
+        date_time: DateTime::Now,
+        spec: BlinkSpec::hz(1.0),
+        length: BlinkLength::Repetitions(3),
+        repeat: Recurrence::Once,
+        target: BlinkTarget::Led,
+        shape: breathe,
+    };
+    let breathing = client.set_blinker(breathing).unwrap().unwrap();
+    let jobs = client.blink_jobs().unwrap();
+    let breathing = jobs.iter().find(|j| j.id == breathing).unwrap();
+    assert_eq!(breathing.shape, breathe);
+}
+
+#[test]
+fn sim_recovered() {
+    let mut client = sim_client();
+    /* a single flipped bit is fixed by the device and reported */
+    client.corrupt_next();
+    assert_eq!(client.set_rgb(ORANGE, 40).unwrap(), Ack::Recovered);
+}
+
+#[test]
+fn sim_time_sync() {
+    let mut client = sim_client();
+    /* set a second ahead, the sync takes it back out */
+    client
+        .set_datetime(DateTime::Utc(host_time_ms() + 1000))
+        .unwrap();
+    let sample = client.sync_time().unwrap();
+    assert!((950..=1050).contains(&sample.offset_ms), "{:?}", sample);
+    assert!(client.sync_time().unwrap().offset_ms.abs() < 50);
+
+    /* the syncs were too close together to tell */
+    assert_eq!(client.drift().unwrap().estimates, 0);
+}
+
+/// Loses the `lose`th frame the device sends, counting from 0
+#[cfg(test)]
+struct LoseFrame<T> {
+    port: T,
+    lose: usize,
+    frames: usize,
+    frame: Vec<u8>,
+}
+
+#[cfg(test)]
+impl<T: Transport> io::Read for LoseFrame<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.frame.is_empty() {
+            let mut raw = [0u8; IN_SIZE];
+            let n = read_frame(&mut self.port, &mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            if self.frames != self.lose {
+                self.frame = raw[0..n].to_vec();
+            }
+            self.frames += 1;
+        }
+        let n = buf.len().min(self.frame.len());
+        buf[0..n].copy_from_slice(&self.frame[0..n]);
+        self.frame.drain(0..n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+impl<T: Transport> io::Write for LoseFrame<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+#[cfg(test)]
+impl<T: Transport> Transport for LoseFrame<T> {
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.port.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.port.set_write_timeout(timeout)
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        self.port.reconnect()
+    }
+}
+
+#[test]
+fn sim_sync_lost_ack() {
+    /* the boot event, the ack of the time, the samples, then the correction */
+    let mut client = DeviceClient::new(LoseFrame {
+        port: crate::sim::spawn(),
+        lose: 2 + SYNC_SAMPLES,
+        frames: 0,
+        frame: Vec::new(),
+    });
+    client.set_timeout(Duration::from_millis(100)).unwrap();
+    client
+        .set_datetime(DateTime::Utc(host_time_ms() + 1000))
+        .unwrap();
+
+    /* the correction is sent again, but only made once */
+    let sample = client.sync_time().unwrap();
+    assert!((950..=1050).contains(&sample.offset_ms), "{:?}", sample);
+    let sample = client.sync_time().unwrap();
+    assert!(sample.offset_ms.abs() < 50, "{:?}", sample);
+}
+
+#[test]
+fn sim_blink_lost_ack() {
+    use shared::BlinkTarget;
+
+    /* the boot event, then the job id */
+    let mut client = DeviceClient::new(LoseFrame {
+        port: crate::sim::spawn(),
+        lose: 1,
+        frames: 0,
+        frame: Vec::new(),
+    });
+    client.set_timeout(Duration::from_millis(100)).unwrap();
+
+    /* the request is sent again, but the job only made once */
+    let options = twice(DateTime::After { seconds: 60 }, BlinkTarget::Led);
+    let id = client.set_blinker(options).unwrap().unwrap();
+    let jobs = client.blink_jobs().unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].id, id);
+
+    /* a new request is a new job */
+    assert_ne!(client.set_blinker(options).unwrap().unwrap(), id);
+    assert_eq!(client.blink_jobs().unwrap().len(), 2);
+}
@@ -0,0 +1,135 @@
+//! `tokio_util` codec for the hamming + cobs + crc frames from `shared`
+//!
+//! The codec is generic over what it sends and receives so the same code can
+//! sit on either end of the link, [`HostCodec`] is the one a host wants.
+
+use crate::client::ClientError;
+use crate::frame::decode_frame;
+use bytes::{BufMut, BytesMut};
+use corncobs::ZERO;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use shared::{
+    deserialize_crc_cobs, hamming::decode_hamming, serialize_crc_cobs, Command, DeviceMessage,
+    IN_SIZE, OUT_SIZE,
+};
+use std::io;
+use std::marker::PhantomData;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Large enough for a frame in either direction
+const BUF_SIZE: usize = if IN_SIZE > OUT_SIZE {
+    IN_SIZE
+} else {
+    OUT_SIZE
+};
+
+/// A decoded frame
+#[derive(Debug)]
+pub struct Frame<D> {
+    pub msg: D,
+    /// hamming had to fix at least one bit
+    pub corrected: bool,
+}
+
+/// Sends `E`s and receives `D`s
+///
+/// Frames that arrive damaged are yielded as `Err` items instead of ending the
+/// stream, the `Error` of the codec is reserved for I/O errors.
+pub struct FrameCodec<E, D> {
+    /// even offset up to which the buffered input holds no frame end
+    scanned: usize,
+    _types: PhantomData<fn(E) -> D>,
+}
+
+pub type HostCodec = FrameCodec<Command, DeviceMessage>;
+
+impl<E, D> FrameCodec<E, D> {
+    pub fn new() -> Self {
+        FrameCodec {
+            scanned: 0,
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<E, D> Default for FrameCodec<E, D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E, D: DeserializeOwned> Decoder for FrameCodec<E, D> {
+    type Item = Result<Frame<D>, ClientError>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        let mut idx = self.scanned;
+        while idx + 2 <= src.len() {
+            let end = match (decode_hamming(src[idx]), decode_hamming(src[idx + 1])) {
+                (Some((b0, _)), Some((b1, _))) => b0 | b1 << 4 == ZERO,
+                /* same as the firmware, give up on the frame at the damage */
+                _ => true,
+            };
+            idx += 2;
+
+            if end || idx >= BUF_SIZE {
+                let raw = src.split_to(idx);
+                self.scanned = 0;
+
+                let mut cobs = [0u8; BUF_SIZE];
+                let decoded = decode_frame(&raw, &mut cobs);
+                if decoded.corrupted {
+                    return Ok(Some(Err(ClientError::Corrupted)));
+                }
+
+                let res = deserialize_crc_cobs::<D>(&mut cobs[0..decoded.len])
+                    .map(|msg| Frame {
+                        msg,
+                        corrected: decoded.corrected,
+                    })
+                    .map_err(ClientError::Decode);
+                return Ok(Some(res));
+            }
+        }
+
+        self.scanned = idx;
+        Ok(None)
+    }
+}
+
+impl<E: Serialize, D> Encoder<&E> for FrameCodec<E, D> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &E, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let mut buf = [0u8; BUF_SIZE];
+        dst.put_slice(serialize_crc_cobs(item, &mut buf));
+        Ok(())
+    }
+}
+
+#[test]
+fn codec_roundtrip() {
+    let mut host = HostCodec::new();
+    let mut device = FrameCodec::<DeviceMessage, Command>::new();
+
+    let mut wire = BytesMut::new();
+    host.encode(&Command::RgbOff, &mut wire).unwrap();
+    host.encode(&Command::RgbOn, &mut wire).unwrap();
+    wire[0] ^= 1;
+
+    /* feed the bytes in one at a time, like a slow serial port would */
+    let mut input = BytesMut::new();
+    let mut received = Vec::new();
+    for b in wire {
+        input.put_u8(b);
+        if let Some(frame) = device.decode(&mut input).unwrap() {
+            received.push(frame.unwrap());
+        }
+    }
+
+    assert_eq!(received.len(), 2);
+    assert!(matches!(received[0].msg, Command::RgbOff) && received[0].corrected);
+    assert!(matches!(received[1].msg, Command::RgbOn) && !received[1].corrected);
+    assert!(input.is_empty());
+}
@@ -0,0 +1,186 @@
+//! Framing of the raw byte stream
+//!
+//! On the wire every byte of a cobs packet is split into two hamming encoded
+//! nibbles, so a packet ends at the first byte pair that decodes to `ZERO`.
+//! These helpers find packet boundaries without caring about the payload type,
+//! which lets the bridge forward packets untouched.
+
+use corncobs::ZERO;
+use shared::hamming::decode_hamming;
+use std::io::{ErrorKind, Read, Result};
+
+/// Read timeouts in a row a started frame survives, a noise byte or a device
+/// reset part way through a frame must not hold up the reader for good
+pub const FRAME_STALLS: u32 = 3;
+
+/// Outcome of [`decode_frame`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decoded {
+    /// number of cobs bytes written to the output buffer
+    pub len: usize,
+    /// at least one single bit error was fixed
+    pub corrected: bool,
+    /// at least one byte had more errors than hamming can fix
+    pub corrupted: bool,
+}
+
+/// Read one raw (still hamming encoded) frame into `buf`
+///
+/// Like the firmware, a byte pair that can't be decoded also ends the frame, as
+/// does running out of buffer. Returns the number of raw bytes read, `0` means
+/// the other end closed the stream between frames. Once a frame has started a
+/// read timeout is waited out, up to [`FRAME_STALLS`] of them in a row, then
+/// the partial frame is dropped and the timeout passed on.
+pub fn read_frame<R: Read + ?Sized>(r: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let idle = |kind| matches!(kind, ErrorKind::TimedOut | ErrorKind::WouldBlock);
+    let mut stalls = 0;
+    let mut idx = 0;
+    while idx + 2 <= buf.len() {
+        let pair = idx + 2;
+        while idx < pair {
+            match r.read(&mut buf[idx..idx + 1]) {
+                /* only a clean end of stream if nothing of the frame was read yet */
+                Ok(0) if idx == 0 => return Ok(0),
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(_) => {
+                    idx += 1;
+                    stalls = 0;
+                }
+                /* timeouts tell the caller the link is idle, this one isn't yet */
+                Err(e) if idx > 0 && idle(e.kind()) && stalls < FRAME_STALLS => stalls += 1,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        match (decode_hamming(buf[idx - 2]), decode_hamming(buf[idx - 1])) {
+            (Some((b0, _)), Some((b1, _))) if b0 | b1 << 4 != ZERO => {}
+            _ => break,
+        }
+    }
+
+    Ok(idx)
+}
+
+/// Hamming decode a raw frame into cobs bytes
+pub fn decode_frame(raw: &[u8], out: &mut [u8]) -> Decoded {
+    let mut decoded = Decoded {
+        len: 0,
+        corrected: false,
+        corrupted: false,
+    };
+
+    for (pair, o) in raw.chunks_exact(2).zip(out.iter_mut()) {
+        let mut nibble = |b| match decode_hamming(b) {
+            Some((v, f)) => {
+                decoded.corrected |= f;
+                v
+            }
+            None => {
+                decoded.corrupted = true;
+                0
+            }
+        };
+        let b0 = nibble(pair[0]);
+        let b1 = nibble(pair[1]);
+        *o = b0 | b1 << 4;
+        decoded.len += 1;
+    }
+
+    decoded
+}
+
+#[test]
+fn frame_roundtrip() {
+    use shared::{deserialize_crc_cobs, serialize_crc_cobs, Command, OUT_SIZE};
+
+    let mut out_buf = [0u8; OUT_SIZE];
+    let raw = serialize_crc_cobs(&Command::RgbOn, &mut out_buf).to_vec();
+
+    /* two frames back to back, the first with a single bit error */
+    let mut stream = raw.clone();
+    stream[2] ^= 1 << 3;
+    stream.extend_from_slice(&raw);
+    let mut stream = &stream[..];
+
+    let mut buf = [0u8; OUT_SIZE];
+    for expect_corrected in [true, false] {
+        let n = read_frame(&mut stream, &mut buf).unwrap();
+        assert_eq!(n, raw.len());
+
+        let mut cobs = [0u8; OUT_SIZE];
+        let d = decode_frame(&buf[0..n], &mut cobs);
+        assert_eq!(d.corrected, expect_corrected);
+        assert!(!d.corrupted);
+        let cmd = deserialize_crc_cobs::<Command>(&mut cobs[0..d.len]).unwrap();
+        assert!(matches!(cmd, Command::RgbOn));
+    }
+
+    assert_eq!(read_frame(&mut stream, &mut buf).unwrap(), 0);
+}
+
+/// A link with a read timeout that times out every third read, and for good
+/// once it runs out of data
+#[cfg(test)]
+struct Slow<'a> {
+    data: &'a [u8],
+    reads: usize,
+}
+
+#[cfg(test)]
+impl Read for Slow<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.reads += 1;
+        if self.reads.is_multiple_of(3) || self.data.is_empty() {
+            return Err(std::io::Error::new(ErrorKind::TimedOut, "read timed out"));
+        }
+        self.data.read(buf)
+    }
+}
+
+#[test]
+fn frame_across_timeouts() {
+    use shared::{serialize_crc_cobs, Command, OUT_SIZE};
+
+    let mut out_buf = [0u8; OUT_SIZE];
+    let raw = serialize_crc_cobs(&Command::RgbOn, &mut out_buf).to_vec();
+    let mut stream = Slow {
+        data: &raw,
+        reads: 0,
+    };
+    let mut buf = [0u8; OUT_SIZE];
+    let n = loop {
+        match read_frame(&mut stream, &mut buf) {
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            res => break res.unwrap(),
+        }
+    };
+    assert_eq!(&buf[0..n], &raw[..]);
+}
+
+#[test]
+fn frame_stalls_for_good() {
+    use shared::{serialize_crc_cobs, Command, OUT_SIZE};
+
+    let mut out_buf = [0u8; OUT_SIZE];
+    let raw = serialize_crc_cobs(&Command::RgbOn, &mut out_buf).to_vec();
+    /* half a frame, then nothing */
+    let mut stream = Slow {
+        data: &raw[0..6],
+        reads: 0,
+    };
+    let mut buf = [0u8; OUT_SIZE];
+    let err = read_frame(&mut stream, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(stream.data.is_empty());
+
+    /* the half frame is gone, the next one reads whole */
+    stream.data = &raw;
+    let n = loop {
+        match read_frame(&mut stream, &mut buf) {
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            res => break res.unwrap(),
+        }
+    };
+    assert_eq!(&buf[0..n], &raw[..]);
+}
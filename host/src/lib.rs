@@ -1,7 +1,24 @@
-use serial2::SerialPort;
 use std::io::Result;
 use std::time::Duration;
 
+pub mod backoff;
+pub mod blink;
+pub mod client;
+#[cfg(feature = "tokio")]
+pub mod codec;
+pub mod frame;
+#[cfg(feature = "tokio")]
+pub mod nonblocking;
+pub mod rgb;
+pub mod sim;
+pub mod transport;
+pub mod when;
+
+pub use client::{ClientError, DeviceClient};
+#[cfg(unix)]
+pub use transport::UnixTransport;
+pub use transport::{memory_pipe, MemoryTransport, SerialTransport, TcpTransport, Transport};
+
 // On Windows, use something like "COM1".
 // For COM ports above COM9, you need to use the win32 device namespace, for example "\\.\COM10" (or "\\\\.\\COM10" with string escaping).
 // For more details, see: https://learn.microsoft.com/en-us/windows/win32/fileio/naming-a-file?redirectedfrom=MSDN#win32-device-namespaces
@@ -14,13 +31,30 @@ static COM_PATH: &str = "COM3";
 // A one second timeout
 const TIME_OUT: Duration = Duration::from_millis(1000);
 
-pub fn open() -> Result<SerialPort> {
-    let mut port = SerialPort::open(COM_PATH, 115200)?;
-    // Needed for windows, but should not hurt on Linux
-    port.set_dtr(true)?;
-    port.set_rts(true)?;
-    port.set_write_timeout(TIME_OUT)?;
-    port.set_read_timeout(TIME_OUT)?;
+/// Our clock in ms since the epoch, UTC like the device clock
+pub fn host_time_ms() -> u64 {
+    chrono::Utc::now().timestamp_millis() as u64
+}
+
+pub fn open() -> Result<SerialTransport> {
+    open_path(COM_PATH)
+}
+
+pub fn open_path(path: &str) -> Result<SerialTransport> {
+    SerialTransport::open(path, 115200, TIME_OUT)
+}
+
+/// Connect to a device given either a serial port path, `tcp://host:port` for
+/// a remote `bridge` or (on unix) `unix:///path/to/socket`
+pub fn connect(target: &str) -> Result<Box<dyn Transport + Send>> {
+    if let Some(addr) = target.strip_prefix("tcp://") {
+        return Ok(Box::new(TcpTransport::connect(addr, TIME_OUT)?));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = target.strip_prefix("unix://") {
+        return Ok(Box::new(UnixTransport::connect(path, TIME_OUT)?));
+    }
 
-    Ok(port)
+    Ok(Box::new(open_path(target)?))
 }
@@ -8,36 +8,336 @@
 //!
 //! cargo run
 //!
-
-// Rust dependencies
-use std::io::Read;
+//! or, to go through a `bridge` running on another machine
+//!
+//! cargo run -- tcp://benchpc:7878
+//!
+//! Print events from the device instead of showing the menu
+//!
+//! cargo run -- monitor
+//!
+//! Keep the device clock in sync for an hour and report how much it drifted
+//!
+//! cargo run -- soak --minutes 60 --interval 300
+//!
+//! Keep the device clock in sync until stopped
+//!
+//! cargo run -- sync --interval 600
+//!
 
 // Libraries
-use corncobs::ZERO;
-use dateparser::{parse_with_timezone};
-use serial2::SerialPort;
+use chrono::{Datelike, TimeZone as _, Utc};
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand};
+use std::fmt::Display;
 use std::io;
 use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
 
 // Application dependencies
-use host::open;
+use host::backoff::Backoff;
+use host::blink::{
+    describe_shape, describe_spec, describe_target, parse_blink_length, parse_blink_shape,
+    parse_blink_spec, parse_blink_target,
+};
+use host::rgb::{
+    parse_animation, parse_color, parse_interpolation, parse_location, parse_night_window,
+    parse_palette, parse_pixels,
+};
+use host::when::{parse_duration, parse_recurrence, parse_when, ParseError};
+use host::{connect, host_time_ms, open, ClientError, DeviceClient, Transport};
+use shared::morse::{MorseText, MAX_MORSE_LEN};
+use shared::night::{NightAction, NightOverride, NightStatus};
+use shared::pwm::{BlinkShape, MAX_DUTY};
+use shared::recur::Recurrence;
+use shared::rgb::{Color, Palette, RgbMode};
+use shared::strip::MAX_PIXELS;
+use shared::tz::{DstRule, TimeZone};
 use shared::{
-    deserialize_crc_cobs, hamming::decode_hamming, serialize_crc_cobs, Ack, BlinkerOptions,
-    Command, DateTime, IN_SIZE, OUT_SIZE,
+    BlinkJob, BlinkTarget, BlinkerOptions, Command, DateTime, Event, JobId, RgbSchedule, Slew,
 };
-// local library
 
-type InBuf = [u8; IN_SIZE];
-type OutBuf = [u8; OUT_SIZE];
+#[derive(Parser)]
+#[command(about = "Send commands to the device")]
+struct Args {
+    /// Serial port of the device, or tcp://host:port of a bridge
+    device: Option<String>,
+
+    /// Zone for times typed without one, e.g. Europe/Stockholm, defaults to
+    /// the system zone
+    #[arg(long)]
+    tz: Option<Tz>,
+
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// Print events from the device as they arrive
+    Monitor,
+    /// Sync the device clock periodically, then report how it drifted
+    Soak {
+        /// How long to run, in minutes
+        #[arg(long, default_value_t = 60)]
+        minutes: u64,
+        /// Seconds between syncs
+        #[arg(long, default_value_t = 300)]
+        interval: u64,
+    },
+    /// Keep the device clock in sync, setting it again after reboots
+    Sync {
+        /// Seconds between syncs
+        #[arg(long, default_value_t = 600)]
+        interval: u64,
+    },
+}
+
+/// How soon a failed sync is retried, doubling up to the sync interval
+const RETRY_FIRST: Duration = Duration::from_secs(2);
+
+/// A device clock before 2000 is counting from boot, it was never set
+const UNSET_BEFORE_MS: u64 = 946_684_800_000;
+
+type Client = DeviceClient<Box<dyn Transport + Send>>;
 
 fn main() -> Result<(), std::io::Error> {
-    let mut port = open()?;
+    let args = Args::parse();
+    let port = match args.device {
+        Some(device) => connect(&device)?,
+        None => Box::new(open()?),
+    };
+    let mut client = DeviceClient::new(port);
+
+    match args.mode {
+        None => menu(&mut client, args.tz.unwrap_or_else(local_zone)),
+        Some(Mode::Monitor) => monitor(&mut client),
+        Some(Mode::Soak { minutes, interval }) => soak(
+            &mut client,
+            Duration::from_secs(minutes * 60),
+            Duration::from_secs(interval.max(1)),
+        ),
+        Some(Mode::Sync { interval }) => {
+            sync_daemon(&mut client, Duration::from_secs(interval.max(1)))
+        }
+    }
+}
+
+/// The system time zone, UTC if it can't be figured out
+fn local_zone() -> Tz {
+    match iana_time_zone::get_timezone().map(|name| name.parse::<Tz>()) {
+        Ok(Ok(tz)) => tz,
+        _ => {
+            println!("Could not tell the system time zone, using UTC, see --tz");
+            Tz::UTC
+        }
+    }
+}
+
+fn log(msg: std::fmt::Arguments) {
+    println!(
+        "[{}] {}",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+        msg
+    );
+}
+
+fn sync_daemon(client: &mut Client, interval: Duration) -> Result<(), std::io::Error> {
+    log(format_args!(
+        "syncing every {}s, ctrl-c to quit",
+        interval.as_secs()
+    ));
+    let mut backoff = Backoff::new(RETRY_FIRST, interval);
 
     loop {
-        let mut bitflip_payload = false;
-        let mut out_buf = [0u8; OUT_SIZE];
-        let mut in_buf = [0u8; IN_SIZE];
+        let wait = match client.sync_time() {
+            Ok(sample) => {
+                backoff.success();
+                /* a boot that came in before or during the sync is handled */
+                while let Some((seq, event)) = client.take_event() {
+                    if event != Event::Boot {
+                        log(format_args!("event #{}: {}", seq, describe(event)));
+                    }
+                }
+                /* the boot event may have gone to another host or been lost */
+                if host_time_ms().saturating_add_signed(sample.offset_ms) < UNSET_BEFORE_MS {
+                    /* the sync set it, measure again now that it's close */
+                    log(format_args!("device time was not set, syncing again"));
+                    Duration::ZERO
+                } else {
+                    log(format_args!(
+                        "device was {} ms off, round trip {} ms",
+                        sample.offset_ms, sample.delay_ms
+                    ));
+                    interval
+                }
+            }
+            Err(e) => {
+                let delay = backoff.failure();
+                log(format_args!(
+                    "sync failed: {}, retrying in {}s",
+                    e,
+                    delay.as_secs()
+                ));
+                /* timeouts may just be a busy link, anything else needs a new one */
+                if !e.is_timeout() || backoff.failures() > 1 {
+                    if let Err(e) = client.transport().reconnect() {
+                        log(format_args!("reconnect failed: {}", e));
+                    }
+                }
+                delay
+            }
+        };
 
+        /* a rebooted device lost its time, don't wait for the next round */
+        let next = Instant::now() + wait;
+        while let Some(left) = next.checked_duration_since(Instant::now()) {
+            match client.next_event(left) {
+                Ok(Some((_, Event::Boot))) => {
+                    log(format_args!("device rebooted, setting the time"));
+                    break;
+                }
+                Ok(Some((seq, event))) => log(format_args!("event #{}: {}", seq, describe(event))),
+                Ok(None) => {}
+                Err(e) if e.is_timeout() => {}
+                Err(ClientError::Corrupted) | Err(ClientError::Decode(_)) => {}
+                Err(_) => {
+                    /* link is down, the next sync reports it */
+                    thread::sleep(left);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// One sync of a soak run
+struct SoakRow {
+    elapsed: Duration,
+    offset_ms: i64,
+    /// drift since the previous sync that the device did not correct for
+    residual_ppm: Option<f64>,
+    estimate_ppm: Option<i32>,
+}
+
+fn soak(client: &mut Client, length: Duration, interval: Duration) -> Result<(), std::io::Error> {
+    println!(
+        "Syncing every {}s for {} minutes",
+        interval.as_secs(),
+        length.as_secs() / 60
+    );
+    println!(
+        "{:>8} {:>10} {:>12} {:>12}",
+        "time", "offset ms", "residual ppm", "device ppm"
+    );
+
+    let start = Instant::now();
+    let mut rows: Vec<SoakRow> = Vec::new();
+    loop {
+        let now = Instant::now();
+        match client.sync_time() {
+            Ok(sample) => {
+                let residual_ppm = rows.last().map(|prev| {
+                    let secs = (now - start - prev.elapsed).as_secs_f64();
+                    /* the device was behind by offset, so it ran slow */
+                    -sample.offset_ms as f64 * 1_000.0 / secs
+                });
+                let estimate_ppm = client
+                    .drift()
+                    .ok()
+                    .filter(|d| d.estimates > 0)
+                    .map(|d| d.ppm);
+                let row = SoakRow {
+                    elapsed: now - start,
+                    offset_ms: sample.offset_ms,
+                    residual_ppm,
+                    estimate_ppm,
+                };
+                print_row(&row);
+                rows.push(row);
+            }
+            Err(ClientError::Disconnected) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Err(e) => println!("Sync failed: {}", e),
+        }
+
+        if now - start >= length {
+            break;
+        }
+
+        /* keep acking events while we wait */
+        let next = now + interval;
+        while let Some(left) = next.checked_duration_since(Instant::now()) {
+            match client.next_event(left) {
+                Ok(Some((seq, event))) => println!("Event #{}: {}", seq, describe(event)),
+                Ok(None) => {}
+                Err(ClientError::Disconnected) => {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Err(e) => println!("Bad message: {}", e),
+            }
+        }
+    }
+
+    /* the first sync only sets the clock, it says nothing about drift */
+    let residuals: Vec<f64> = rows.iter().filter_map(|r| r.residual_ppm).collect();
+    let worst = rows.iter().skip(1).map(|r| r.offset_ms.abs()).max();
+    println!("\nDrift report");
+    println!(" syncs: {}", rows.len());
+    match worst {
+        Some(worst) => println!(" worst offset after the first sync: {} ms", worst),
+        None => println!(" worst offset after the first sync: -"),
+    }
+    if !residuals.is_empty() {
+        let mean = residuals.iter().sum::<f64>() / residuals.len() as f64;
+        println!(" mean uncorrected drift: {:.1} ppm", mean);
+    }
+    match rows.last().and_then(|r| r.estimate_ppm) {
+        Some(ppm) => println!(" device correction: {} ppm", ppm),
+        None => println!(" device correction: no estimate yet"),
+    }
+    Ok(())
+}
+
+fn print_row(row: &SoakRow) {
+    let opt = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+    println!(
+        "{:>7}s {:>10} {:>12} {:>12}",
+        row.elapsed.as_secs(),
+        row.offset_ms,
+        opt(row.residual_ppm.map(|p| format!("{:.1}", p))),
+        opt(row.estimate_ppm.map(|p| p.to_string())),
+    );
+}
+
+fn monitor(client: &mut Client) -> Result<(), std::io::Error> {
+    println!("Waiting for events, ctrl-c to quit");
+    loop {
+        match client.next_event(Duration::from_secs(1)) {
+            Ok(Some((seq, event))) => {
+                let time = chrono::Local::now().format("%H:%M:%S");
+                println!("[{}] #{} {}", time, seq, describe(event));
+            }
+            Ok(None) => {}
+            Err(ClientError::Io(e)) => return Err(e),
+            Err(ClientError::Disconnected) => {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Err(e) => println!("Bad message: {}", e),
+        }
+    }
+}
+
+fn describe(event: Event) -> String {
+    match event {
+        Event::Boot => "device booted, time needs to be set again".to_string(),
+        Event::BlinkFinished(id) => format!("blink job {} finished", id),
+        Event::ButtonPressed => "button pressed".to_string(),
+    }
+}
+
+fn menu(client: &mut Client, tz: Tz) -> Result<(), std::io::Error> {
+    loop {
         println!(
             "\nTASKS:\n \
             1. Toggle RGB on\n \
@@ -45,34 +345,156 @@ fn main() -> Result<(), std::io::Error> {
             3. Set blink data\n \
             4. Set date time\n \
             5. Bit flip on payload\n \
-            6. Quit\n"
+            6. Sync date time\n \
+            7. Set slew window\n \
+            8. Set time zone\n \
+            9. List blink jobs\n \
+            10. Cancel blink job\n \
+            11. Schedule RGB\n \
+            12. Flash Morse message\n \
+            13. Set RGB colour\n \
+            14. Set RGB brightness\n \
+            15. Set RGB palette\n \
+            16. Set location\n \
+            17. Animate RGB\n \
+            18. Set strip length\n \
+            19. Set strip pixels\n \
+            20. Night mode\n \
+            21. Set LED brightness\n \
+            22. Quit\n"
         );
-        print!(" > ");
-        io::stdout().flush().unwrap();
-
-        let mut input = String::new();
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Failed to read input");
-
-        let command: u32 = match input.trim().parse() {
-            Ok(num) => num,
-            Err(_) => {
-                println!("Invalid input");
-                continue;
-            }
+        let Some(command) = prompt("", |s| s.parse::<u32>()) else {
+            break;
         };
 
         let task = match command {
             1 => Command::RgbOn,
             2 => Command::RgbOff,
-            3 => Command::SetBlinker(get_blink_data()),
-            4 => Command::SetDateTime(set_datetime()),
+            3 => {
+                let Some(options) = get_blink_data(tz) else {
+                    break;
+                };
+                match client.set_blinker(options) {
+                    Ok(Some(id)) => println!("Scheduled as blink job {}", id),
+                    Ok(None) => println!("All blink jobs cancelled"),
+                    Err(e) => println!("Request failed: {}", e),
+                }
+                continue;
+            }
+            4 => match set_datetime(tz) {
+                Some(date_time) => Command::SetDateTime(date_time),
+                None => break,
+            },
             5 => {
-                bitflip_payload = true;
+                client.corrupt_next();
                 Command::RgbOn
             }
             6 => {
+                match client.sync_time() {
+                    Ok(s) => println!(
+                        "Device was {} ms off, corrected (round trip {} ms)",
+                        s.offset_ms, s.delay_ms
+                    ),
+                    Err(e) => println!("Sync failed: {}", e),
+                }
+                continue;
+            }
+            7 => match get_slew() {
+                Some(slew) => Command::SetSlew(slew),
+                None => break,
+            },
+            8 => match get_time_zone() {
+                Some(time_zone) => Command::SetTimeZone(time_zone),
+                None => break,
+            },
+            9 => {
+                match client.blink_jobs() {
+                    Ok(jobs) if jobs.is_empty() => println!("No blink jobs"),
+                    Ok(jobs) => {
+                        for job in jobs {
+                            println!("{}", describe_job(&job, tz));
+                        }
+                    }
+                    Err(e) => println!("Request failed: {}", e),
+                }
+                continue;
+            }
+            10 => match prompt("Insert blink job ID", |s| s.parse::<JobId>()) {
+                Some(id) => Command::CancelBlink(id),
+                None => break,
+            },
+            11 => match get_rgb_schedule(tz) {
+                Some(schedule) => Command::ScheduleRgb(schedule),
+                None => break,
+            },
+            12 => {
+                let Some((date_time, text, wpm)) = get_morse(tz) else {
+                    break;
+                };
+                match client.morse(date_time, text, wpm) {
+                    Ok(id) => println!("Scheduled as blink job {}", id),
+                    Err(e) => println!("Request failed: {}", e),
+                }
+                continue;
+            }
+            13 => match get_rgb_color() {
+                Some(command) => command,
+                None => break,
+            },
+            14 => match get_brightness() {
+                Some(brightness) => Command::SetBrightness(brightness),
+                None => break,
+            },
+            15 => match get_palette() {
+                Some(palette) => Command::SetPalette(palette),
+                None => break,
+            },
+            16 => match prompt(
+                "Insert latitude and longitude in degrees, north and east positive",
+                parse_location,
+            ) {
+                Some(location) => Command::SetLocation(location),
+                None => break,
+            },
+            17 => match prompt(
+                "Insert animation <fade colour colour period>, <pulse colour period> \
+                or <rainbow period>",
+                parse_animation,
+            ) {
+                Some(animation) => Command::Animate(animation),
+                None => break,
+            },
+            18 => match prompt(
+                &format!("Insert number of pixels, 1 to {}", MAX_PIXELS),
+                |s| s.parse::<u16>(),
+            ) {
+                Some(len) => Command::SetStripLength(len),
+                None => break,
+            },
+            19 => match prompt(
+                "Insert pixels and colour <pixel colour> or <first-last colour>, from 0",
+                parse_pixels,
+            ) {
+                Some((start, len, color)) => Command::FillPixels { start, len, color },
+                None => break,
+            },
+            20 => {
+                match client.night_mode() {
+                    Ok(status) => println!("{}", describe_night(&status)),
+                    Err(e) => println!("Request failed: {}", e),
+                }
+                match get_night_mode() {
+                    Some(command) => command,
+                    None => break,
+                }
+            }
+            21 => match prompt(&format!("Insert LED brightness, 0 to {}%", MAX_DUTY), |s| {
+                s.trim_end_matches('%').trim().parse::<u8>()
+            }) {
+                Some(percent) => Command::SetLedBrightness(percent),
+                None => break,
+            },
+            22 => {
                 break;
             }
             _ => {
@@ -81,130 +503,338 @@ fn main() -> Result<(), std::io::Error> {
             }
         };
 
-        request(&task, &mut port, &mut out_buf, &mut in_buf, bitflip_payload)?;
+        match client.request(&task) {
+            Ok(ack) => println!("Response: {:?}", ack),
+            Err(e) => println!("Request failed: {}", e),
+        }
+
+        while let Some((seq, event)) = client.take_event() {
+            println!("Event #{}: {}", seq, describe(event));
+        }
     }
 
     Ok(())
 }
 
-fn get_blink_data() -> BlinkerOptions {
-    println!("\nInput \n <hh:mm:ss>, <off>, <now>\n <frequency>\n <duration>\n");
-
-    let mut date_time_string = String::new();
-    let mut frequency = String::new();
-    let mut duration = String::new();
+fn describe_job(job: &BlinkJob, tz: Tz) -> String {
+    /* jobs scheduled before the time was set still count from boot */
+    let start = match Utc.timestamp_millis_opt(job.start as i64).single() {
+        Some(t) if t.year() > 2000 => t
+            .with_timezone(&tz)
+            .format("%Y-%m-%d %H:%M:%S%.3f %Z")
+            .to_string(),
+        _ => format!("{} ms after boot", job.start),
+    };
+    let repeat = match job.repeat {
+        Recurrence::Once => String::new(),
+        repeat => format!(", {:?}", repeat),
+    };
+    let target = match job.target {
+        BlinkTarget::Led => String::new(),
+        target => format!(" on {}", describe_target(&target)),
+    };
+    let shape = match job.shape {
+        BlinkShape::Square => String::new(),
+        shape => format!(", {}", describe_shape(&shape)),
+    };
+    format!(
+        "#{}: {}{}{} for {} ms from {}{}",
+        job.id,
+        describe_spec(&job.spec),
+        shape,
+        target,
+        job.duration,
+        start,
+        repeat
+    )
+}
 
-    println!("Insert date time <hh:mm:ss> or 'off' to set led off\n");
-    print!(" > ");
-    io::stdout().flush().unwrap();
-    let _ = io::stdin().read_line(&mut date_time_string);
+/// Ask until `parse` accepts the answer, `None` once stdin is closed
+fn prompt<T, E: Display>(question: &str, mut parse: impl FnMut(&str) -> Result<T, E>) -> Option<T> {
+    loop {
+        if !question.is_empty() {
+            println!("\n{}\n", question);
+        }
+        print!(" > ");
+        io::stdout().flush().ok()?;
 
-    if date_time_string.trim().to_lowercase() == "off" {
-        return BlinkerOptions::Off;
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).ok()? == 0 {
+            return None;
+        }
+        match parse(input.trim()) {
+            Ok(t) => return Some(t),
+            Err(e) => println!("Invalid input: {}", e),
+        }
     }
+}
+
+/// Parse a point in time and show what it resolved to
+fn when(input: &str, tz: Tz) -> Result<u64, ParseError> {
+    let t = parse_when(input, Utc::now(), tz)?;
+    println!(
+        "  = {} ({})",
+        t.format("%Y-%m-%d %H:%M:%S%.3f UTC"),
+        t.with_timezone(&tz).format("%H:%M:%S%.3f %Z")
+    );
+    u64::try_from(t.timestamp_millis()).map_err(|_| ParseError::OutOfRange)
+}
+
+/// Start of a schedule, `Some(None)` if it is to be turned off instead
+fn get_start(tz: Tz) -> Option<Option<DateTime>> {
+    prompt(
+        "Insert start time, e.g. 14:00, tomorrow 07:30, in 10m, 2024-05-01 12:00 +02:00, \
+        'now', 'after 90s' to go by the device clock even if it isn't set, or 'off' to turn \
+        it off",
+        |s| match s.to_lowercase().as_str() {
+            "off" => Ok(None),
+            "now" => Ok(Some(DateTime::Now)),
+            s => match s.strip_prefix("after ") {
+                Some(rest) => parse_duration(rest).map(|d| {
+                    Some(DateTime::After {
+                        seconds: d.as_secs_f64().round() as u64,
+                    })
+                }),
+                None => when(s, tz).map(|t| Some(DateTime::Utc(t))),
+            },
+        },
+    )
+}
+
+fn get_repeat() -> Option<Recurrence> {
+    prompt(
+        "Insert repeat: once, daily 07:30, weekdays 07:00, mon,wed,fri 18:00 or every 15m, \
+        times of day in the device's zone",
+        parse_recurrence,
+    )
+}
 
-    // let date_time;
+fn get_duration() -> Option<u64> {
+    let duration = prompt(
+        "Insert duration, e.g. 30, 1.5s, 10m, 1h 30m",
+        parse_duration,
+    )?;
+    Some(duration.as_millis() as u64)
+}
 
-    let date_time = if date_time_string.trim().to_lowercase() == "now" {
-        shared::DateTime::Now
-    } else {
-        // Using UTC timezone to pretend that our local timezone is UTC0.
-        let date_time_ = parse_with_timezone(date_time_string.trim(), &chrono::Utc).unwrap();
-        shared::DateTime::Utc(date_time_.naive_local().timestamp() as u64)
+fn get_blink_data(tz: Tz) -> Option<BlinkerOptions> {
+    let Some(date_time) = get_start(tz)? else {
+        return Some(BlinkerOptions::Off);
     };
 
-    println!("\nInsert frequency (Hz)\n");
-    print!(" > ");
-    io::stdout().flush().unwrap();
-    let _ = io::stdin().read_line(&mut frequency);
+    let spec = prompt(
+        "Insert blink, e.g. 2hz, 0.5hz 10%, 1.5s 25%, or on/off ms as pattern 100 100 300 500",
+        parse_blink_spec,
+    )?;
+    let length = prompt(
+        "Insert duration, e.g. 30, 1.5s, 10m, 1h 30m, or repetitions as 3x",
+        parse_blink_length,
+    )?;
 
-    let freq = frequency.trim().parse::<u64>().unwrap();
+    let repeat = get_repeat()?;
+    let target = prompt(
+        "Insert LED to blink: led, or rgb and a colour like rgb #ff0000, which shows over \
+        the RGB LED's own colour while the job runs",
+        parse_blink_target,
+    )?;
+    /* the RGB LED only blinks square */
+    let shape = match target {
+        BlinkTarget::Led => prompt(
+            "Insert shape: square, or fade in and out like fade 200ms, or fade 100ms 400ms",
+            parse_blink_shape,
+        )?,
+        BlinkTarget::Rgb(_) => BlinkShape::Square,
+    };
+
+    Some(BlinkerOptions::On {
+        date_time,
+        spec,
+        length,
+        repeat,
+        target,
+        shape,
+    })
+}
 
-    println!("\nInsert duration in seconds\n");
-    print!(" > ");
-    io::stdout().flush().unwrap();
-    let _ = io::stdin().read_line(&mut duration);
+fn get_morse(tz: Tz) -> Option<(DateTime, MorseText, u8)> {
+    let text = prompt(
+        &format!(
+            "Insert message, up to {} letters, digits and .,?/=-+@",
+            MAX_MORSE_LEN
+        ),
+        |s| MorseText::new(s).ok_or("can't send that in Morse"),
+    )?;
+    let wpm = prompt("Insert speed in words per minute, e.g. 15", |s| {
+        match s.parse::<u8>() {
+            Ok(0) => Err("must be at least 1".to_string()),
+            Ok(wpm) => Ok(wpm),
+            Err(e) => Err(e.to_string()),
+        }
+    })?;
+    /* 'off' makes no sense here, ask again */
+    let date_time = loop {
+        match get_start(tz)? {
+            Some(date_time) => break date_time,
+            None => println!("Invalid input: a message can't be turned off"),
+        }
+    };
+    Some((date_time, text, wpm))
+}
 
-    let duration = duration.trim().parse::<u64>().unwrap();
+fn get_rgb_schedule(tz: Tz) -> Option<RgbSchedule> {
+    let Some(date_time) = get_start(tz)? else {
+        return Some(RgbSchedule::Off);
+    };
 
-    BlinkerOptions::On {
+    Some(RgbSchedule::On {
         date_time,
-        freq,
-        duration,
-    }
+        duration: get_duration()?,
+        repeat: get_repeat()?,
+    })
 }
 
-fn set_datetime() -> DateTime {
-    let mut date_time_string = String::new();
+/// A colour and its brightness, or back to one of the time of day modes
+fn get_rgb_color() -> Option<Command> {
+    let color = prompt(
+        "Insert colour <#rrggbb> or <r g b>, 'palette' for the time of day colours, \
+        'sun' to follow the sun, 'animation' for the last animation or 'pixels' for the \
+        strip's own pixels",
+        |s| match s {
+            "palette" => Ok(Err(RgbMode::Palette)),
+            "sun" => Ok(Err(RgbMode::Sun)),
+            "animation" => Ok(Err(RgbMode::Animated)),
+            "pixels" => Ok(Err(RgbMode::Pixels)),
+            s => parse_color(s).map(Ok),
+        },
+    )?;
+    Some(match color {
+        Ok(Color { r, g, b }) => Command::SetRgb {
+            r,
+            g,
+            b,
+            brightness: get_brightness()?,
+        },
+        Err(mode) => Command::SetRgbMode(mode),
+    })
+}
 
-    println!("Insert date time <hh:mm:ss> or 'now' to set current time\n");
-    print!(" > ");
-    io::stdout().flush().unwrap();
-    let _ = io::stdin().read_line(&mut date_time_string);
+fn get_palette() -> Option<Palette> {
+    let interpolation = prompt(
+        "Insert how to go from one colour to the next: step, linear or hsv",
+        parse_interpolation,
+    )?;
+    prompt(
+        "Insert colours by local time of day <hh:mm colour; hh:mm colour; ...>",
+        |s| parse_palette(s, interpolation),
+    )
+}
 
-    // Use naive_local time to ignore timezone and pretend that our local timzone is UTC0.
-    if date_time_string.trim().to_lowercase() == "now" {
-        let utc_timestamp = chrono::Local::now().naive_local().timestamp();
-        return shared::DateTime::Utc(utc_timestamp as u64);
-    }
-    // Using UTC timezone to pretend that our local timezone is UTC0.
-    let date_time_ = parse_with_timezone(date_time_string.trim(), &chrono::Utc).unwrap();
-    shared::DateTime::Utc(date_time_.naive_local().timestamp() as u64)
-}
-
-fn request(
-    cmd: &Command,
-    port: &mut SerialPort,
-    out_buf: &mut OutBuf,
-    in_buf: &mut InBuf,
-    bitflip_payload: bool,
-) -> Result<Ack, std::io::Error> {
-    println!("out_buf {}", out_buf.len());
-    let to_write = serialize_crc_cobs(cmd, out_buf);
-    println!("Actual : {:?}", to_write);
-    if bitflip_payload {
-        to_write[2] ^= 1 << 1;
-        println!("Corrup : {:?}", to_write);
-    }
-    // println!("{:?}", to_write);
-    let mut tries = 0;
-    loop {
-        port.write_all(to_write)?;
+/// A night window, or an override of the one there is
+fn get_night_mode() -> Option<Command> {
+    prompt(
+        "Insert night window <hh:mm-hh:mm dim brightness> or <hh:mm-hh:mm off> in local \
+        time, 'none' to turn night mode off, or 'night', 'day' or 'auto' to override it \
+        until the window next starts or ends",
+        |s| match s.trim().to_lowercase().as_str() {
+            "none" => Ok(Command::SetNightMode(None)),
+            "night" => Ok(Command::NightOverride(NightOverride::Night)),
+            "day" => Ok(Command::NightOverride(NightOverride::Day)),
+            "auto" => Ok(Command::NightOverride(NightOverride::Auto)),
+            s => parse_night_window(s).map(|window| Command::SetNightMode(Some(window))),
+        },
+    )
+}
 
-        let mut index: usize = 0;
-        loop {
-            let slice = &mut in_buf[index..index + 1];
-            if index < IN_SIZE {
-                index += 1;
-            }
+fn describe_night(status: &NightStatus) -> String {
+    let Some(window) = status.window else {
+        return "Night mode is off".to_string();
+    };
+    let time = |ms: u32| format!("{:02}:{:02}", ms / 3_600_000, ms / 60_000 % 60);
+    let action = match window.action {
+        NightAction::Dim(brightness) => format!("dim to {}", brightness),
+        NightAction::Off => "off".to_string(),
+    };
+    let forced = match status.forced {
+        NightOverride::Auto => "",
+        NightOverride::Night => ", forced night",
+        NightOverride::Day => ", forced day",
+    };
+    format!(
+        "Night mode {}-{} {}, it is {}{}",
+        time(window.start_ms),
+        time(window.end_ms),
+        action,
+        if status.active { "night" } else { "day" },
+        forced
+    )
+}
 
-            let mut b = [0u8; 2];
-            port.read_exact(&mut b[0..1])?;
-            // println!("Host received : {}", b[0]);
-            port.read_exact(&mut b[1..2])?;
-            // println!("Host received : {}", b[1]);
+fn get_brightness() -> Option<u8> {
+    prompt("Insert brightness, 0 to 255", |s| s.parse::<u8>())
+}
 
-            let (b0, _) = decode_hamming(b[0]).unwrap();
-            let (b1, _) = decode_hamming(b[1]).unwrap();
+fn get_slew() -> Option<Slew> {
+    let window_ms = prompt(
+        "Insert slew window in ms, 0 to always step the clock",
+        |s| s.parse::<u64>(),
+    )?;
+    let max_offset_ms = prompt(
+        "Insert largest correction to slew in ms, larger ones step",
+        |s| s.parse::<u64>(),
+    )?;
 
-            slice[0] = b0 | b1 << 4;
-            if slice[0] == ZERO {
-                println!("-- cobs package received --");
-                break;
-            }
-        }
-        println!("cobs index {}", index);
-        let res = deserialize_crc_cobs::<Ack>(in_buf).unwrap();
-        match res {
-            Ack::Ok => return Ok(res),
-            Ack::Recovered => return Ok(res),
-            Ack::NotOk => {
-                if tries >= 2 {
-                    return Ok(res);
-                }
-                tries += 1;
-            }
-        }
-        // Ok(deserialize_crc_cobs(in_buf).unwrap())
+    Some(Slew {
+        window_ms,
+        max_offset_ms,
+    })
+}
+
+fn get_time_zone() -> Option<TimeZone> {
+    let offset_minutes = prompt(
+        "Insert standard time offset from UTC <[-]hh[:mm]>",
+        parse_utc_offset,
+    )?;
+    let dst = prompt(
+        "Insert daylight saving rule <none>, <eu>, <us>",
+        |s| match s.to_lowercase().as_str() {
+            "none" | "" => Ok(DstRule::None),
+            "eu" => Ok(DstRule::Eu),
+            "us" => Ok(DstRule::Us),
+            _ => Err("expected none, eu or us"),
+        },
+    )?;
+
+    Some(TimeZone {
+        offset_minutes,
+        dst,
+    })
+}
+
+fn parse_utc_offset(s: &str) -> Result<i16, &'static str> {
+    let invalid = "expected something like 1, -5 or 5:30";
+    let (sign, offset) = match s.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, s.trim_start_matches('+')),
+    };
+    let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+    let hours = hours.parse::<i16>().map_err(|_| invalid)?;
+    let minutes = minutes.parse::<i16>().map_err(|_| invalid)?;
+    if !(0..=14).contains(&hours) || !(0..60).contains(&minutes) {
+        return Err("offsets go up to 14 hours");
     }
+    Ok(sign * (hours * 60 + minutes))
+}
+
+fn set_datetime(tz: Tz) -> Option<DateTime> {
+    let t = prompt(
+        "Insert date time, e.g. 14:00:05, 2024-05-01 12:00, or 'now' to set current time",
+        |s| {
+            if s.eq_ignore_ascii_case("now") {
+                Ok(host_time_ms())
+            } else {
+                when(s, tz)
+            }
+        },
+    )?;
+    Some(DateTime::Utc(t))
 }
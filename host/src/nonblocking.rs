@@ -0,0 +1,492 @@
+//! Async client for tokio based applications
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), host::ClientError> {
+//! let (client, _unsolicited) = host::nonblocking::connect("tcp://benchpc:7878").await?;
+//! client.set_datetime(shared::DateTime::Utc(1_700_000_000_000)).await?;
+//! let (a, b) = tokio::join!(client.rgb_on(), client.rgb_off());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The device handles one command at a time, so requests from any number of
+//! tasks are queued and exchanged one by one by a background task which owns
+//! the link. Events the device sends are acknowledged and handed out on the
+//! [`Unsolicited`] stream.
+
+use crate::client::{
+    next_request, ClientError, EventTracker, Result, DEFAULT_ATTEMPTS, DEFAULT_TIMEOUT,
+    SYNC_SAMPLES,
+};
+use crate::codec::HostCodec;
+use crate::host_time_ms;
+use futures::{SinkExt, Stream, StreamExt};
+use shared::morse::MorseText;
+use shared::night::{NightOverride, NightStatus, NightWindow};
+use shared::rgb::{Animation, Color, Palette, RgbMode};
+use shared::strip::PixelChunk;
+use shared::sun::Location;
+use shared::timesync::SyncSample;
+use shared::tz::TimeZone;
+use shared::{
+    Ack, BlinkJob, BlinkerOptions, Command, DateTime, DeviceMessage, Drift, Event, EventSeq, JobId,
+    RgbSchedule, Slew,
+};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant};
+use tokio_util::codec::Framed;
+
+/// Requests that can be queued before callers start waiting for room
+const QUEUE_DEPTH: usize = 32;
+
+struct Pending {
+    cmd: Command,
+    reply: oneshot::Sender<Result<DeviceMessage>>,
+}
+
+/// Handle to a device, cheap to clone and share between tasks
+#[derive(Clone)]
+pub struct DeviceClient {
+    requests: mpsc::Sender<Pending>,
+}
+
+/// Stream of events the device sent, already acknowledged and with
+/// retransmissions removed
+pub struct Unsolicited {
+    rx: mpsc::Receiver<(EventSeq, Event)>,
+}
+
+impl Stream for Unsolicited {
+    type Item = (EventSeq, Event);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Connect like [`crate::connect`] does, but with async I/O
+pub async fn connect(target: &str) -> io::Result<(DeviceClient, Unsolicited)> {
+    if let Some(addr) = target.strip_prefix("tcp://") {
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        return Ok(DeviceClient::spawn(stream));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = target.strip_prefix("unix://") {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        return Ok(DeviceClient::spawn(stream));
+    }
+
+    let port = serial2_tokio::SerialPort::open(target, 115200)?;
+    // Needed for windows, but should not hurt on Linux
+    port.set_dtr(true)?;
+    port.set_rts(true)?;
+    Ok(DeviceClient::spawn(port))
+}
+
+impl DeviceClient {
+    /// Start talking to a device over `io`, must be called within a tokio runtime
+    pub fn spawn<T>(io: T) -> (Self, Unsolicited)
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::spawn_with(io, DEFAULT_TIMEOUT, DEFAULT_ATTEMPTS)
+    }
+
+    /// Like [`DeviceClient::spawn`], with a custom response timeout and number
+    /// of attempts per request
+    pub fn spawn_with<T>(io: T, timeout: Duration, attempts: u32) -> (Self, Unsolicited)
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (requests, pending) = mpsc::channel(QUEUE_DEPTH);
+        let (unsolicited, rx) = mpsc::channel(QUEUE_DEPTH);
+        let link = Link {
+            framed: Framed::new(io, HostCodec::new()),
+            timeout,
+            attempts: attempts.max(1),
+            unsolicited,
+            tracker: EventTracker::new(),
+        };
+        tokio::spawn(link.run(pending));
+
+        (DeviceClient { requests }, Unsolicited { rx })
+    }
+
+    pub async fn rgb_on(&self) -> Result<Ack> {
+        self.request(Command::RgbOn).await
+    }
+
+    pub async fn rgb_off(&self) -> Result<Ack> {
+        self.request(Command::RgbOff).await
+    }
+
+    pub async fn set_rgb(&self, color: Color, brightness: u8) -> Result<Ack> {
+        let Color { r, g, b } = color;
+        self.request(Command::SetRgb {
+            r,
+            g,
+            b,
+            brightness,
+        })
+        .await
+    }
+
+    pub async fn set_brightness(&self, brightness: u8) -> Result<Ack> {
+        self.request(Command::SetBrightness(brightness)).await
+    }
+
+    pub async fn set_rgb_mode(&self, mode: RgbMode) -> Result<Ack> {
+        self.request(Command::SetRgbMode(mode)).await
+    }
+
+    pub async fn animate(&self, animation: Animation) -> Result<Ack> {
+        self.request(Command::Animate(animation)).await
+    }
+
+    pub async fn set_strip_length(&self, len: u16) -> Result<Ack> {
+        self.request(Command::SetStripLength(len)).await
+    }
+
+    pub async fn fill_pixels(&self, start: u16, len: u16, color: Color) -> Result<Ack> {
+        self.request(Command::FillPixels { start, len, color })
+            .await
+    }
+
+    /// Show a whole frame, like the blocking [`crate::DeviceClient::push_frame`]
+    pub async fn push_frame(&self, frame: &[Color]) -> Result<Ack> {
+        /* an empty frame would be shown as nothing at all */
+        let mut ack = Err(ClientError::Rejected);
+        for chunk in PixelChunk::split(frame) {
+            ack = Ok(self.request(Command::PixelChunk(chunk)).await?);
+        }
+        ack
+    }
+
+    pub async fn set_location(&self, location: Location) -> Result<Ack> {
+        self.request(Command::SetLocation(location)).await
+    }
+
+    pub async fn set_palette(&self, palette: Palette) -> Result<Ack> {
+        self.request(Command::SetPalette(palette)).await
+    }
+
+    /// Schedule a blink, like the blocking [`crate::DeviceClient::set_blinker`]
+    pub async fn set_blinker(&self, options: BlinkerOptions) -> Result<Option<JobId>> {
+        let request = next_request();
+        match self
+            .transact(Command::SetBlinker { options, request })
+            .await?
+        {
+            DeviceMessage::BlinkScheduled(id) => Ok(Some(id)),
+            DeviceMessage::Ack(_) => Ok(None),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    /// Flash `text` once on the blink LED, like the blocking
+    /// [`crate::DeviceClient::morse`]
+    pub async fn morse(&self, date_time: DateTime, text: MorseText, wpm: u8) -> Result<JobId> {
+        let cmd = Command::Morse {
+            date_time,
+            text,
+            wpm,
+            request: next_request(),
+        };
+        match self.transact(cmd).await? {
+            DeviceMessage::BlinkScheduled(id) => Ok(id),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    /// When the RGB LED comes on by itself, replaces the previous schedule
+    pub async fn schedule_rgb(&self, schedule: RgbSchedule) -> Result<Ack> {
+        self.request(Command::ScheduleRgb(schedule)).await
+    }
+
+    /// Blink jobs waiting to start or in progress
+    pub async fn blink_jobs(&self) -> Result<Vec<BlinkJob>> {
+        match self.transact(Command::ListBlinks).await? {
+            DeviceMessage::BlinkJobs(jobs) => Ok(jobs.into_iter().flatten().collect()),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    pub async fn cancel_blink(&self, id: JobId) -> Result<Ack> {
+        self.request(Command::CancelBlink(id)).await
+    }
+
+    pub async fn set_datetime(&self, date_time: DateTime) -> Result<Ack> {
+        self.request(Command::SetDateTime(date_time)).await
+    }
+
+    /// Measure and correct the device clock, like the blocking
+    /// [`crate::DeviceClient::sync_time`]
+    pub async fn sync_time(&self) -> Result<SyncSample> {
+        let mut samples = Vec::with_capacity(SYNC_SAMPLES);
+        let mut tag = 0;
+        for _ in 0..SYNC_SAMPLES {
+            let cmd = Command::SyncTime { t1: host_time_ms() };
+            match self.transact(cmd).await? {
+                DeviceMessage::TimeSync { t1, t2, t3 } => {
+                    tag = t1;
+                    samples.push(SyncSample::new(t1, t2, t3, host_time_ms()))
+                }
+                _ => return Err(ClientError::Unexpected),
+            }
+        }
+
+        let best = SyncSample::best(samples).unwrap();
+        /* tagged, so a retry after a lost ack doesn't correct twice */
+        let adjust = Command::AdjustTime {
+            offset: -best.offset_ms,
+            t1: tag,
+        };
+        self.request(adjust).await?;
+        Ok(best)
+    }
+
+    /// Zone the device uses for everything that goes by the hour of day
+    pub async fn set_time_zone(&self, time_zone: TimeZone) -> Result<Ack> {
+        self.request(Command::SetTimeZone(time_zone)).await
+    }
+
+    /// Spread small time corrections out instead of stepping the clock
+    pub async fn set_slew(&self, slew: Slew) -> Result<Ack> {
+        self.request(Command::SetSlew(slew)).await
+    }
+
+    /// Rate correction the device currently applies to its clock
+    pub async fn drift(&self) -> Result<Drift> {
+        match self.transact(Command::GetDrift).await? {
+            DeviceMessage::Drift(drift) => Ok(drift),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    pub async fn set_night_mode(&self, window: Option<NightWindow>) -> Result<Ack> {
+        self.request(Command::SetNightMode(window)).await
+    }
+
+    pub async fn night_override(&self, forced: NightOverride) -> Result<Ack> {
+        self.request(Command::NightOverride(forced)).await
+    }
+
+    pub async fn night_mode(&self) -> Result<NightStatus> {
+        match self.transact(Command::GetNightMode).await? {
+            DeviceMessage::NightMode(status) => Ok(status),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    /// Duty cycle in percent the blink LED is on at
+    pub async fn set_led_brightness(&self, percent: u8) -> Result<Ack> {
+        self.request(Command::SetLedBrightness(percent)).await
+    }
+
+    /// Queue `cmd` and wait for it to be accepted, retried like the blocking
+    /// [`crate::DeviceClient::request`]
+    pub async fn request(&self, cmd: Command) -> Result<Ack> {
+        match self.transact(cmd).await? {
+            DeviceMessage::Ack(ack) => Ok(ack),
+            _ => Err(ClientError::Unexpected),
+        }
+    }
+
+    /// Like [`DeviceClient::request`], for commands answered with something
+    /// other than an ack
+    pub async fn transact(&self, cmd: Command) -> Result<DeviceMessage> {
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .send(Pending { cmd, reply })
+            .await
+            .map_err(|_| ClientError::Disconnected)?;
+        /* the link task is gone if it dropped our reply */
+        response.await.unwrap_or(Err(ClientError::Disconnected))
+    }
+}
+
+struct Link<T> {
+    framed: Framed<T, HostCodec>,
+    timeout: Duration,
+    attempts: u32,
+    unsolicited: mpsc::Sender<(EventSeq, Event)>,
+    tracker: EventTracker,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Link<T> {
+    async fn run(mut self, mut pending: mpsc::Receiver<Pending>) {
+        loop {
+            tokio::select! {
+                p = pending.recv() => {
+                    /* every handle is gone */
+                    let Some(p) = p else { return };
+                    /* nobody is waiting for this one anymore */
+                    if p.reply.is_closed() {
+                        continue;
+                    }
+
+                    let res = self.exchange(&p.cmd).await;
+                    let gone = matches!(res, Err(ClientError::Disconnected));
+                    let _ = p.reply.send(res);
+                    if gone {
+                        return;
+                    }
+                }
+                frame = self.framed.next() => match frame {
+                    /* a late answer is of no use to anyone */
+                    Some(Ok(Ok(frame))) => {
+                        self.receive(frame.msg);
+                    }
+                    Some(Ok(Err(_))) => {}
+                    Some(Err(_)) | None => return,
+                },
+            }
+
+            /* between exchanges, so our acks never land in the middle of one */
+            if self.send_acks().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn exchange(&mut self, cmd: &Command) -> Result<DeviceMessage> {
+        if !cmd.expects_response() {
+            self.framed.send(cmd).await?;
+            return Ok(DeviceMessage::Ack(Ack::Ok));
+        }
+
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            self.framed.send(cmd).await?;
+
+            let deadline = Instant::now() + self.timeout;
+            let res = loop {
+                match time::timeout_at(deadline, self.framed.next()).await {
+                    Err(_) => break Err(ClientError::Io(io::ErrorKind::TimedOut.into())),
+                    Ok(None) => return Err(ClientError::Disconnected),
+                    Ok(Some(Err(e))) => return Err(ClientError::Io(e)),
+                    Ok(Some(Ok(Err(e)))) => break Err(e),
+                    Ok(Some(Ok(Ok(frame)))) => {
+                        if let Some(msg) = self.receive(frame.msg) {
+                            break Ok(msg);
+                        }
+                    }
+                }
+            };
+
+            match res {
+                Ok(DeviceMessage::Ack(Ack::NotOk)) if tries < self.attempts => {}
+                Ok(DeviceMessage::Ack(Ack::NotOk)) => return Err(ClientError::Rejected),
+                Ok(msg) => return Ok(msg),
+                Err(_) if tries < self.attempts => {}
+                Err(_) => return res,
+            }
+        }
+    }
+
+    /// Hand out new events, returns `msg` if it was a response instead
+    fn receive(&mut self, msg: DeviceMessage) -> Option<DeviceMessage> {
+        match msg {
+            DeviceMessage::Event { seq, event } => {
+                if self.tracker.accept(seq, event) {
+                    /* drop them if the application isn't keeping up */
+                    let _ = self.unsolicited.try_send((seq, event));
+                }
+                None
+            }
+            _ => Some(msg),
+        }
+    }
+
+    async fn send_acks(&mut self) -> io::Result<()> {
+        while let Some(seq) = self.tracker.ack_due() {
+            self.framed.send(&Command::AckEvent(seq)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn concurrent_requests() {
+    use crate::codec::FrameCodec;
+    use crate::sim::SimDevice;
+    use shared::pwm::BlinkShape;
+    use shared::recur::Recurrence;
+    use shared::{BlinkLength, BlinkSpec, BlinkTarget};
+
+    let (host, device) = tokio::io::duplex(256);
+
+    /* run the simulated device logic on the other end of the pipe */
+    tokio::spawn(async move {
+        let mut sim = SimDevice::new();
+        let mut framed = Framed::new(device, FrameCodec::<DeviceMessage, Command>::new());
+        framed.send(&sim.event(Event::Boot)).await.unwrap();
+        while let Some(Ok(frame)) = framed.next().await {
+            if let Some(msg) = sim.handle(frame.ok().map(|f| f.msg)) {
+                framed.send(&msg).await.unwrap();
+            }
+        }
+    });
+
+    let (client, mut unsolicited) = DeviceClient::spawn(host);
+    assert_eq!(unsolicited.next().await, Some((0, Event::Boot)));
+    client
+        .set_datetime(DateTime::Utc(1_700_000_000_000))
+        .await
+        .unwrap();
+
+    let other = client.clone();
+    let (a, b, c) = tokio::join!(
+        client.rgb_on(),
+        other.rgb_off(),
+        client.set_blinker(BlinkerOptions::Off)
+    );
+    assert_eq!(a.unwrap(), Ack::Ok);
+    assert_eq!(b.unwrap(), Ack::Ok);
+    assert_eq!(c.unwrap(), None);
+
+    let never_on = BlinkerOptions::On {
+        date_time: DateTime::Now,
+        spec: BlinkSpec::pattern(&[0, 0]).unwrap(),
+        length: BlinkLength::Duration(1000),
+        repeat: Recurrence::Once,
+        target: BlinkTarget::Led,
+        shape: BlinkShape::Square,
+    };
+    assert!(matches!(
+        client.set_blinker(never_on).await,
+        Err(ClientError::Rejected)
+    ));
+
+    client.sync_time().await.unwrap();
+    assert!(client.sync_time().await.unwrap().offset_ms.abs() < 50);
+}
+
+#[tokio::test]
+async fn events_after_reboot() {
+    use crate::codec::FrameCodec;
+    use crate::sim::SimDevice;
+
+    let (host, device) = tokio::io::duplex(256);
+    let mut framed = Framed::new(device, FrameCodec::<DeviceMessage, Command>::new());
+    /* a reboot starts the numbers over */
+    for _ in 0..2 {
+        let mut sim = SimDevice::new();
+        framed.send(&sim.event(Event::Boot)).await.unwrap();
+        framed.send(&sim.event(Event::ButtonPressed)).await.unwrap();
+    }
+
+    let (_client, mut unsolicited) = DeviceClient::spawn(host);
+    for _ in 0..2 {
+        assert_eq!(unsolicited.next().await, Some((0, Event::Boot)));
+        assert_eq!(unsolicited.next().await, Some((1, Event::ButtonPressed)));
+    }
+}
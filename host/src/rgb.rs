@@ -0,0 +1,326 @@
+//! Reading colours the way people type them
+//!
+//! A colour is hex like `#ff8800` or `ff8800`, or three decimal components
+//! like `255 136 0`, commas allowed between them. A palette is a list of
+//! times of day and colours separated by semicolons, like
+//! `06:00 #f8f32b; 12:00 #9cfffa; 21:00 49 8 31`. The sun goes by a
+//! location in degrees, like `51.5 -0.13`. Animations are `fade`, `pulse` or
+//! `rainbow` with their colours and how long a cycle takes, like
+//! `fade #ff0000 #0000ff 10s`, `pulse #ff8800 4s` or `rainbow 1m`. Pixels
+//! of a strip are a number or a range and a colour, like `3 #ff0000` or
+//! `0-9 #00ff00`. A night window is a range of local times and whether to dim
+//! or turn off, like `22:00-07:00 dim 5` or `23:30-06:00 off`.
+
+use crate::when::{parse_duration, parse_ms_of_day, ParseError};
+use shared::night::{NightAction, NightWindow};
+use shared::rgb::{Animation, Color, Interpolation, Keyframe, Palette};
+use shared::sun::Location;
+
+/// `#rrggbb`, `rrggbb` or `r g b`
+pub fn parse_color(input: &str) -> Result<Color, ParseError> {
+    let input = input.trim();
+    let unrecognised = || ParseError::Unrecognised(input.to_string());
+    if input.is_empty() {
+        return Err(ParseError::Empty);
+    }
+
+    let parts: Vec<&str> = input
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if let [r, g, b] = parts[..] {
+        let component = |s: &str| {
+            s.parse::<u16>()
+                .map_err(|_| unrecognised())
+                .and_then(|c| u8::try_from(c).map_err(|_| ParseError::OutOfRange))
+        };
+        return Ok(Color {
+            r: component(r)?,
+            g: component(g)?,
+            b: component(b)?,
+        });
+    }
+
+    let hex = input.strip_prefix('#').unwrap_or(input);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(unrecognised());
+    }
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| unrecognised());
+    Ok(Color {
+        r: component(0)?,
+        g: component(2)?,
+        b: component(4)?,
+    })
+}
+
+/// `hh:mm colour; hh:mm colour; ...`, in any order
+pub fn parse_palette(input: &str, interpolation: Interpolation) -> Result<Palette, ParseError> {
+    if input.trim().is_empty() {
+        return Err(ParseError::Empty);
+    }
+    let mut keys = input
+        .split(';')
+        .map(|key| {
+            let key = key.trim();
+            let (at, color) = key
+                .split_once(' ')
+                .ok_or_else(|| ParseError::Unrecognised(key.to_string()))?;
+            Ok(Keyframe {
+                at_ms: parse_ms_of_day(at)?,
+                color: parse_color(color)?,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    keys.sort_by_key(|key| key.at_ms);
+    Palette::new(&keys, interpolation).ok_or(ParseError::OutOfRange)
+}
+
+/// `step`, `linear` or `hsv`
+pub fn parse_interpolation(input: &str) -> Result<Interpolation, ParseError> {
+    match input.trim().to_lowercase().as_str() {
+        "" => Err(ParseError::Empty),
+        "step" => Ok(Interpolation::Step),
+        "linear" => Ok(Interpolation::Linear),
+        "hsv" => Ok(Interpolation::Hsv),
+        input => Err(ParseError::Unrecognised(input.to_string())),
+    }
+}
+
+/// `lat lon` in degrees, north and east positive, commas allowed
+pub fn parse_location(input: &str) -> Result<Location, ParseError> {
+    let unrecognised = || ParseError::Unrecognised(input.trim().to_string());
+    let parts: Vec<&str> = input
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .collect();
+    let [latitude, longitude] = parts[..] else {
+        return Err(if parts.is_empty() {
+            ParseError::Empty
+        } else {
+            unrecognised()
+        });
+    };
+    let location = Location {
+        latitude: latitude.parse().map_err(|_| unrecognised())?,
+        longitude: longitude.parse().map_err(|_| unrecognised())?,
+    };
+    if !location.is_valid() {
+        return Err(ParseError::OutOfRange);
+    }
+    Ok(location)
+}
+
+/// `fade <colour> <colour> <period>`, `pulse <colour> <period>` or
+/// `rainbow <period>`
+pub fn parse_animation(input: &str) -> Result<Animation, ParseError> {
+    let input = input.trim().to_lowercase();
+    let unrecognised = || ParseError::Unrecognised(input.clone());
+    let words: Vec<&str> = input.split_whitespace().collect();
+    let [kind, colors @ .., period] = &words[..] else {
+        return Err(if words.is_empty() {
+            ParseError::Empty
+        } else {
+            unrecognised()
+        });
+    };
+
+    let period_ms = parse_duration(period)?.as_millis();
+    let period_ms = u32::try_from(period_ms).map_err(|_| ParseError::OutOfRange)?;
+    /* colours are one word each in hex, three in decimal */
+    let per_color = if colors.len() > 2 { 3 } else { 1 };
+    let colors = colors
+        .chunks(per_color)
+        .map(|c| parse_color(&c.join(" ")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let animation = match (*kind, &colors[..]) {
+        ("fade", &[from, to]) => Animation::Fade {
+            from,
+            to,
+            period_ms,
+        },
+        ("pulse", &[color]) => Animation::Pulse { color, period_ms },
+        ("rainbow", &[]) => Animation::Rainbow { period_ms },
+        _ => return Err(unrecognised()),
+    };
+    if !animation.is_valid() {
+        return Err(ParseError::OutOfRange);
+    }
+    Ok(animation)
+}
+
+/// `<pixel> <colour>` or `<first>-<last> <colour>`, as start, count and
+/// colour
+pub fn parse_pixels(input: &str) -> Result<(u16, u16, Color), ParseError> {
+    let input = input.trim();
+    let unrecognised = || ParseError::Unrecognised(input.to_string());
+    if input.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    let (pixels, color) = input.split_once(' ').ok_or_else(unrecognised)?;
+    let pixel = |s: &str| s.trim().parse::<u16>().map_err(|_| unrecognised());
+    let (first, last) = match pixels.split_once('-') {
+        Some((first, last)) => (pixel(first)?, pixel(last)?),
+        None => (pixel(pixels)?, pixel(pixels)?),
+    };
+    if last < first {
+        return Err(ParseError::OutOfRange);
+    }
+    Ok((first, last - first + 1, parse_color(color)?))
+}
+
+/// `<hh:mm>-<hh:mm> dim <brightness>` or `<hh:mm>-<hh:mm> off`
+pub fn parse_night_window(input: &str) -> Result<NightWindow, ParseError> {
+    let input = input.trim().to_lowercase();
+    let unrecognised = || ParseError::Unrecognised(input.clone());
+    let words: Vec<&str> = input.split_whitespace().collect();
+    let (times, action) = match words[..] {
+        [] => return Err(ParseError::Empty),
+        [times, "off"] => (times, NightAction::Off),
+        [times, "dim", brightness] => (
+            times,
+            NightAction::Dim(brightness.parse().map_err(|_| unrecognised())?),
+        ),
+        _ => return Err(unrecognised()),
+    };
+    let (start, end) = times.split_once('-').ok_or_else(unrecognised)?;
+    let window = NightWindow {
+        start_ms: parse_ms_of_day(start)?,
+        end_ms: parse_ms_of_day(end)?,
+        action,
+    };
+    if !window.is_valid() {
+        return Err(ParseError::OutOfRange);
+    }
+    Ok(window)
+}
+
+#[test]
+fn color_parsing() {
+    let orange = Color {
+        r: 0xFF,
+        g: 0x88,
+        b: 0,
+    };
+    assert_eq!(parse_color("#ff8800"), Ok(orange));
+    assert_eq!(parse_color("FF8800"), Ok(orange));
+    assert_eq!(parse_color("255 136 0"), Ok(orange));
+    assert_eq!(parse_color("255, 136, 0"), Ok(orange));
+
+    assert_eq!(parse_color("256 0 0"), Err(ParseError::OutOfRange));
+    assert_eq!(parse_color(""), Err(ParseError::Empty));
+    assert!(parse_color("#ff88").is_err());
+    assert!(parse_color("orange").is_err());
+    assert!(parse_color("1 2").is_err());
+}
+
+#[test]
+fn palette_parsing() {
+    let palette = parse_palette(
+        "21:00 49 8 31; 06:00 #f8f32b;12:00 9cfffa",
+        Interpolation::Hsv,
+    )
+    .unwrap();
+    let times: Vec<u32> = palette.keys().iter().map(|k| k.at_ms).collect();
+    assert_eq!(times, [6 * 3_600_000, 12 * 3_600_000, 21 * 3_600_000]);
+    assert_eq!(palette.keys()[2].color, Color { r: 49, g: 8, b: 31 });
+    assert_eq!(palette.interpolation, Interpolation::Hsv);
+
+    /* two colours for the same time */
+    assert_eq!(
+        parse_palette("06:00 #ffffff; 06:00 #000000", Interpolation::Step),
+        Err(ParseError::OutOfRange)
+    );
+    assert_eq!(
+        parse_palette(" ", Interpolation::Step),
+        Err(ParseError::Empty)
+    );
+    assert!(parse_palette("06:00", Interpolation::Step).is_err());
+    assert!(parse_palette("25:00 #ffffff", Interpolation::Step).is_err());
+
+    assert_eq!(parse_interpolation("HSV"), Ok(Interpolation::Hsv));
+    assert!(parse_interpolation("cubic").is_err());
+}
+
+#[test]
+fn location_parsing() {
+    assert_eq!(
+        parse_location("51.5, -0.13"),
+        Ok(Location {
+            latitude: 51.5,
+            longitude: -0.13
+        })
+    );
+    assert_eq!(parse_location("95 10"), Err(ParseError::OutOfRange));
+    assert_eq!(parse_location(""), Err(ParseError::Empty));
+    assert!(parse_location("51.5").is_err());
+    assert!(parse_location("north 10").is_err());
+}
+
+#[test]
+fn animation_parsing() {
+    let red = Color { r: 255, g: 0, b: 0 };
+    let blue = Color { r: 0, g: 0, b: 255 };
+    assert_eq!(
+        parse_animation("fade #ff0000 #0000ff 10s"),
+        Ok(Animation::Fade {
+            from: red,
+            to: blue,
+            period_ms: 10_000
+        })
+    );
+    assert_eq!(
+        parse_animation("fade 255 0 0 0 0 255 10s"),
+        parse_animation("fade #ff0000 #0000ff 10s")
+    );
+    assert_eq!(
+        parse_animation("Pulse ff0000 1.5s"),
+        Ok(Animation::Pulse {
+            color: red,
+            period_ms: 1_500
+        })
+    );
+    assert_eq!(
+        parse_animation("rainbow 1m"),
+        Ok(Animation::Rainbow { period_ms: 60_000 })
+    );
+
+    assert_eq!(parse_animation("rainbow 10ms"), Err(ParseError::OutOfRange));
+    assert_eq!(parse_animation(""), Err(ParseError::Empty));
+    assert!(parse_animation("fade #ff0000 10s").is_err());
+    assert!(parse_animation("sparkle 10s").is_err());
+    assert!(parse_animation("rainbow").is_err());
+}
+
+#[test]
+fn pixel_parsing() {
+    let red = Color { r: 255, g: 0, b: 0 };
+    assert_eq!(parse_pixels("3 #ff0000"), Ok((3, 1, red)));
+    assert_eq!(parse_pixels("0-9 255 0 0"), Ok((0, 10, red)));
+    assert_eq!(parse_pixels("9-0 #ff0000"), Err(ParseError::OutOfRange));
+    assert!(parse_pixels("3").is_err());
+    assert!(parse_pixels("a-b #ff0000").is_err());
+}
+
+#[test]
+fn night_window_parsing() {
+    assert_eq!(
+        parse_night_window("22:00-07:00 dim 5"),
+        Ok(NightWindow {
+            start_ms: 22 * 3_600_000,
+            end_ms: 7 * 3_600_000,
+            action: NightAction::Dim(5)
+        })
+    );
+    assert_eq!(
+        parse_night_window("23:30-06:00 OFF").map(|w| w.action),
+        Ok(NightAction::Off)
+    );
+    assert_eq!(
+        parse_night_window("07:00-07:00 off"),
+        Err(ParseError::OutOfRange)
+    );
+    assert_eq!(parse_night_window(""), Err(ParseError::Empty));
+    assert!(parse_night_window("22:00-07:00 dim").is_err());
+    assert!(parse_night_window("22:00 off").is_err());
+    assert!(parse_night_window("22:00-07:00 dim 300").is_err());
+}
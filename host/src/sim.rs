@@ -0,0 +1,301 @@
+//! Simulated device
+//!
+//! Speaks the same protocol as the firmware and follows the same acceptance
+//! rules as its `broker`, but has no LEDs. Good enough to exercise the host
+//! tools without a board, e.g. `bridge --simulate`.
+
+use crate::frame::{decode_frame, read_frame};
+use crate::transport::{memory_pipe, MemoryTransport, Transport};
+use shared::{
+    blink::JobTable,
+    clock::Clock,
+    deserialize_crc_cobs,
+    night::NightMode,
+    pwm::MAX_DUTY,
+    recur::first_start,
+    rgb::{Color, RgbMode, RgbSettings},
+    serialize_crc_cobs,
+    strip::Strip,
+    tz::TimeZone,
+    Ack, BlinkerOptions, Command, DateTime, DeviceMessage, Drift, Event, EventSeq, RgbSchedule,
+    IN_SIZE, MAX_BLINK_JOBS, OUT_SIZE,
+};
+use std::io::Result;
+use std::thread;
+use std::time::Instant;
+
+/// The simulated tick counter runs in µs
+const TICKS_PER_SECOND: u64 = 1_000_000;
+
+#[derive(Debug)]
+pub struct SimDevice {
+    clock: Clock,
+    /// `t1` of the last [`Command::AdjustTime`] applied
+    last_adjust: Option<u64>,
+    booted: Instant,
+    next_seq: EventSeq,
+    /// nothing blinks, jobs just run out
+    blink_jobs: JobTable<MAX_BLINK_JOBS>,
+    /// recurring schedules go by it
+    time_zone: TimeZone,
+    /// no LED to show them on, kept to answer like the firmware
+    rgb_settings: RgbSettings,
+    strip: Strip,
+    night: NightMode,
+}
+
+impl Default for SimDevice {
+    fn default() -> Self {
+        SimDevice {
+            clock: Clock::new(TICKS_PER_SECOND),
+            last_adjust: None,
+            booted: Instant::now(),
+            next_seq: 0,
+            blink_jobs: JobTable::new(),
+            time_zone: TimeZone::UTC,
+            rgb_settings: RgbSettings::new(),
+            strip: Strip::new(),
+            night: NightMode::new(),
+        }
+    }
+}
+
+impl SimDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn ticks(&self) -> u64 {
+        self.booted.elapsed().as_micros() as u64
+    }
+
+    /// Device clock in ms, counting from creation until set
+    pub fn time_ms(&self) -> u64 {
+        self.clock.time_ms(self.ticks())
+    }
+
+    /// Set the clock like the firmware does, jobs scheduled before the time
+    /// was known move along with it
+    fn set_clock(&mut self, utc: u64) {
+        let now = self.ticks();
+        if !self.clock.is_set() {
+            self.blink_jobs
+                .shift(utc as i64 - self.clock.time_ms(now) as i64);
+        }
+        self.clock.set(now, utc);
+    }
+
+    /// Decide on the response to a command, `None` if it could not be decoded.
+    /// Returns `None` for commands that are not answered.
+    pub fn handle(&mut self, cmd: Option<Command>) -> Option<DeviceMessage> {
+        let datetime_set = self.clock.is_set();
+        let now = self.time_ms();
+        while self.blink_jobs.expire(now, &self.time_zone).is_some() {}
+
+        if let Some((
+            request,
+            options @ BlinkerOptions::On {
+                date_time,
+                spec,
+                repeat,
+                ..
+            },
+        )) = cmd.as_ref().and_then(|cmd| cmd.blink())
+        {
+            /* a retry gets the job the first try made */
+            let id = self.blink_jobs.added_for(request).or_else(|| {
+                first_start(date_time, repeat, now, datetime_set, &self.time_zone)
+                    .filter(|_| spec.is_valid())
+                    .and_then(|start| self.blink_jobs.add(start, &options, request))
+            });
+            return Some(match id {
+                Some(id) => DeviceMessage::BlinkScheduled(id),
+                None => DeviceMessage::Ack(Ack::NotOk),
+            });
+        }
+
+        let ack = match cmd {
+            Some(Command::SetDateTime(DateTime::Utc(t))) => {
+                self.set_clock(t);
+                Ack::Ok
+            }
+            Some(Command::SyncTime { t1 }) => {
+                let t = self.time_ms();
+                return Some(DeviceMessage::TimeSync { t1, t2: t, t3: t });
+            }
+            Some(Command::AdjustTime { offset, t1 }) => {
+                /* a retry of one already made, its ack got lost */
+                if self.last_adjust != Some(t1) {
+                    self.last_adjust = Some(t1);
+                    self.set_clock(now.saturating_add_signed(offset));
+                }
+                Ack::Ok
+            }
+            Some(Command::SetTimeZone(time_zone)) => {
+                self.time_zone = time_zone;
+                Ack::Ok
+            }
+            Some(Command::SetSlew(slew)) => {
+                self.clock.set_slew(slew);
+                Ack::Ok
+            }
+            Some(Command::GetDrift) => {
+                return Some(DeviceMessage::Drift(Drift {
+                    ppm: self.clock.ppm(),
+                    estimates: self.clock.estimates(),
+                }));
+            }
+            Some(Command::SetDateTime(DateTime::Now | DateTime::After { .. })) => Ack::NotOk,
+            Some(Command::SetBlinker {
+                options: BlinkerOptions::Off,
+                ..
+            }) => {
+                self.blink_jobs.clear();
+                Ack::Ok
+            }
+            /* taken care of above */
+            Some(Command::SetBlinker { .. } | Command::Morse { .. }) => unreachable!(),
+            /* no LED to switch, only whether the device would take it */
+            Some(Command::ScheduleRgb(RgbSchedule::Off)) => Ack::Ok,
+            Some(Command::ScheduleRgb(RgbSchedule::On {
+                date_time, repeat, ..
+            })) => match first_start(date_time, repeat, now, datetime_set, &self.time_zone) {
+                Some(_) => Ack::Ok,
+                None => Ack::NotOk,
+            },
+            Some(Command::ListBlinks) => {
+                return Some(DeviceMessage::BlinkJobs(self.blink_jobs.jobs()));
+            }
+            Some(Command::CancelBlink(id)) => {
+                if self.blink_jobs.cancel(id) {
+                    Ack::Ok
+                } else {
+                    Ack::NotOk
+                }
+            }
+            Some(Command::RgbOn) | Some(Command::RgbOff) => {
+                if datetime_set || !self.rgb_settings.needs_time() {
+                    Ack::Ok
+                } else {
+                    Ack::NotOk
+                }
+            }
+            Some(Command::SetRgb {
+                r,
+                g,
+                b,
+                brightness,
+            }) => {
+                self.rgb_settings.mode = RgbMode::Fixed;
+                self.rgb_settings.color = Color { r, g, b };
+                self.rgb_settings.brightness = brightness;
+                Ack::Ok
+            }
+            Some(Command::SetBrightness(brightness)) => {
+                self.rgb_settings.brightness = brightness;
+                Ack::Ok
+            }
+            Some(Command::SetPalette(palette)) if palette.is_valid() => {
+                self.rgb_settings.palette = palette;
+                Ack::Ok
+            }
+            Some(Command::SetPalette(_)) => Ack::NotOk,
+            Some(Command::SetRgbMode(mode)) if self.rgb_settings.accepts(mode, datetime_set) => {
+                self.rgb_settings.mode = mode;
+                Ack::Ok
+            }
+            Some(Command::SetRgbMode(_)) => Ack::NotOk,
+            Some(Command::SetLocation(location)) if location.is_valid() => {
+                self.rgb_settings.location = Some(location);
+                Ack::Ok
+            }
+            Some(Command::SetLocation(_)) => Ack::NotOk,
+            Some(Command::Animate(animation)) if animation.is_valid() => {
+                self.rgb_settings.mode = RgbMode::Animated;
+                self.rgb_settings.animation = animation;
+                Ack::Ok
+            }
+            Some(Command::Animate(_)) => Ack::NotOk,
+            Some(Command::SetStripLength(len)) if self.strip.set_len(len) => Ack::Ok,
+            Some(Command::FillPixels { start, len, color })
+                if self.strip.fill(start, len, color) =>
+            {
+                self.rgb_settings.mode = RgbMode::Pixels;
+                Ack::Ok
+            }
+            Some(Command::PixelChunk(chunk)) if self.strip.load(&chunk) => {
+                if chunk.last {
+                    self.rgb_settings.mode = RgbMode::Pixels;
+                }
+                Ack::Ok
+            }
+            Some(
+                Command::SetStripLength(_) | Command::FillPixels { .. } | Command::PixelChunk(_),
+            ) => Ack::NotOk,
+            Some(Command::SetLedBrightness(percent)) if percent <= MAX_DUTY => Ack::Ok,
+            Some(Command::SetLedBrightness(_)) => Ack::NotOk,
+            Some(Command::SetNightMode(window)) if window.is_none_or(|w| w.is_valid()) => {
+                self.night.set_window(window);
+                Ack::Ok
+            }
+            Some(Command::SetNightMode(_)) => Ack::NotOk,
+            Some(Command::NightOverride(forced)) => {
+                self.night.force(forced, now, &self.time_zone, datetime_set);
+                Ack::Ok
+            }
+            Some(Command::GetNightMode) => {
+                return Some(DeviceMessage::NightMode(self.night.status(
+                    now,
+                    &self.time_zone,
+                    datetime_set,
+                )));
+            }
+            /* events are sent once, there is nothing to stop retransmitting */
+            Some(Command::AckEvent(_)) => return None,
+            None => Ack::NotOk,
+        };
+        Some(DeviceMessage::Ack(ack))
+    }
+
+    /// Event message with the next sequence number
+    pub fn event(&mut self, event: Event) -> DeviceMessage {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        DeviceMessage::Event { seq, event }
+    }
+
+    /// Announce a boot, then answer frames from `port` until it is closed
+    pub fn serve<T: Transport + ?Sized>(&mut self, port: &mut T) -> Result<()> {
+        let mut buf = [0u8; IN_SIZE];
+        let boot = self.event(Event::Boot);
+        port.write_all(serialize_crc_cobs(&boot, &mut buf))?;
+
+        let mut raw = [0u8; OUT_SIZE];
+        loop {
+            let n = read_frame(port, &mut raw)?;
+            if n == 0 {
+                return Ok(());
+            }
+
+            let mut cmd = [0u8; OUT_SIZE];
+            let decoded = decode_frame(&raw[0..n], &mut cmd);
+            let Some(mut msg) =
+                self.handle(deserialize_crc_cobs::<Command>(&mut cmd[0..decoded.len]).ok())
+            else {
+                continue;
+            };
+            if decoded.corrected && msg == DeviceMessage::Ack(Ack::Ok) {
+                msg = DeviceMessage::Ack(Ack::Recovered);
+            }
+
+            port.write_all(serialize_crc_cobs(&msg, &mut buf))?;
+        }
+    }
+}
+
+/// Run a simulated device on a background thread, returns our end of the link
+pub fn spawn() -> MemoryTransport {
+    let (host, mut device) = memory_pipe();
+    thread::spawn(move || SimDevice::new().serve(&mut device));
+    host
+}
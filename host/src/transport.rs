@@ -0,0 +1,407 @@
+//! Byte transports between the host and the device
+//!
+//! The protocol only needs a reliable-ish byte pipe, so everything above this
+//! module is generic over [`Transport`]. Besides the real UART we can talk to a
+//! device over TCP, a Unix-domain socket or an in-memory pipe (handy for tests).
+
+use serial2::SerialPort;
+use std::collections::VecDeque;
+use std::io::{self, Read, Result, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// A byte stream the host can send frames over
+///
+/// `read`, `write` and `flush` come from [`Read`] and [`Write`]. Reads and
+/// writes that exceed their timeout fail with [`io::ErrorKind::TimedOut`] or
+/// [`io::ErrorKind::WouldBlock`] depending on the backend.
+pub trait Transport: Read + Write {
+    fn set_read_timeout(&mut self, timeout: Duration) -> Result<()>;
+    fn set_write_timeout(&mut self, timeout: Duration) -> Result<()>;
+    /// Drop the current connection and establish a new one to the same peer
+    fn reconnect(&mut self) -> Result<()>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn set_read_timeout(&mut self, timeout: Duration) -> Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Duration) -> Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        (**self).reconnect()
+    }
+}
+
+/// Serial port, remembers its path so it can be reopened
+pub struct SerialTransport {
+    path: String,
+    baud_rate: u32,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    port: SerialPort,
+}
+
+impl SerialTransport {
+    pub fn open(path: &str, baud_rate: u32, timeout: Duration) -> Result<Self> {
+        let port = Self::open_port(path, baud_rate, timeout, timeout)?;
+        Ok(SerialTransport {
+            path: path.to_string(),
+            baud_rate,
+            read_timeout: timeout,
+            write_timeout: timeout,
+            port,
+        })
+    }
+
+    fn open_port(
+        path: &str,
+        baud_rate: u32,
+        read_timeout: Duration,
+        write_timeout: Duration,
+    ) -> Result<SerialPort> {
+        let mut port = SerialPort::open(path, baud_rate)?;
+        // Needed for windows, but should not hurt on Linux
+        port.set_dtr(true)?;
+        port.set_rts(true)?;
+        port.set_write_timeout(write_timeout)?;
+        port.set_read_timeout(read_timeout)?;
+        Ok(port)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Read for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+impl Write for SerialTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.port.flush()
+    }
+}
+
+impl Transport for SerialTransport {
+    fn set_read_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.read_timeout = timeout;
+        self.port.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.write_timeout = timeout;
+        self.port.set_write_timeout(timeout)
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.port = Self::open_port(
+            &self.path,
+            self.baud_rate,
+            self.read_timeout,
+            self.write_timeout,
+        )?;
+        Ok(())
+    }
+}
+
+/// TCP connection, e.g. to a serial bridge on another machine
+pub struct TcpTransport {
+    addr: String,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    pub fn connect(addr: &str, timeout: Duration) -> Result<Self> {
+        let stream = Self::connect_stream(addr, timeout, timeout)?;
+        Ok(TcpTransport {
+            addr: addr.to_string(),
+            read_timeout: timeout,
+            write_timeout: timeout,
+            stream,
+        })
+    }
+
+    fn connect_stream(
+        addr: &str,
+        read_timeout: Duration,
+        write_timeout: Duration,
+    ) -> Result<TcpStream> {
+        let stream = TcpStream::connect(addr)?;
+        // frames are small, don't let Nagle sit on them
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(read_timeout))?;
+        stream.set_write_timeout(Some(write_timeout))?;
+        Ok(stream)
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn set_read_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.read_timeout = timeout;
+        self.stream.set_read_timeout(Some(timeout))
+    }
+
+    fn set_write_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.write_timeout = timeout;
+        self.stream.set_write_timeout(Some(timeout))
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.stream = Self::connect_stream(&self.addr, self.read_timeout, self.write_timeout)?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::UnixTransport;
+
+#[cfg(unix)]
+mod unix {
+    use super::Transport;
+    use std::io::{Read, Result, Write};
+    use std::os::unix::net::UnixStream;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    /// Unix-domain socket connection
+    pub struct UnixTransport {
+        path: PathBuf,
+        read_timeout: Duration,
+        write_timeout: Duration,
+        stream: UnixStream,
+    }
+
+    impl UnixTransport {
+        pub fn connect(path: impl AsRef<Path>, timeout: Duration) -> Result<Self> {
+            let path = path.as_ref().to_path_buf();
+            let stream = Self::connect_stream(&path, timeout, timeout)?;
+            Ok(UnixTransport {
+                path,
+                read_timeout: timeout,
+                write_timeout: timeout,
+                stream,
+            })
+        }
+
+        fn connect_stream(
+            path: &Path,
+            read_timeout: Duration,
+            write_timeout: Duration,
+        ) -> Result<UnixStream> {
+            let stream = UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(read_timeout))?;
+            stream.set_write_timeout(Some(write_timeout))?;
+            Ok(stream)
+        }
+    }
+
+    impl Read for UnixTransport {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.stream.read(buf)
+        }
+    }
+
+    impl Write for UnixTransport {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.stream.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    impl Transport for UnixTransport {
+        fn set_read_timeout(&mut self, timeout: Duration) -> Result<()> {
+            self.read_timeout = timeout;
+            self.stream.set_read_timeout(Some(timeout))
+        }
+
+        fn set_write_timeout(&mut self, timeout: Duration) -> Result<()> {
+            self.write_timeout = timeout;
+            self.stream.set_write_timeout(Some(timeout))
+        }
+
+        fn reconnect(&mut self) -> Result<()> {
+            self.stream = Self::connect_stream(&self.path, self.read_timeout, self.write_timeout)?;
+            Ok(())
+        }
+    }
+}
+
+#[derive(Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+/// One direction of a [`memory_pipe`]
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    ready: Condvar,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// One end of an in-memory duplex pipe, see [`memory_pipe`]
+pub struct MemoryTransport {
+    rx: Arc<Pipe>,
+    tx: Arc<Pipe>,
+    read_timeout: Option<Duration>,
+}
+
+/// Create a connected pair of in-memory transports
+///
+/// Bytes written to one end can be read from the other. Dropping an end makes
+/// reads on the other end return EOF once the buffered data is consumed.
+pub fn memory_pipe() -> (MemoryTransport, MemoryTransport) {
+    let a = Arc::new(Pipe::default());
+    let b = Arc::new(Pipe::default());
+    (
+        MemoryTransport {
+            rx: a.clone(),
+            tx: b.clone(),
+            read_timeout: None,
+        },
+        MemoryTransport {
+            rx: b,
+            tx: a,
+            read_timeout: None,
+        },
+    )
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let state = self.rx.state.lock().unwrap();
+        let waiting = |s: &mut PipeState| s.data.is_empty() && !s.closed;
+        let mut state = match self.read_timeout {
+            Some(timeout) => {
+                let (state, res) = self
+                    .rx
+                    .ready
+                    .wait_timeout_while(state, timeout, waiting)
+                    .unwrap();
+                if res.timed_out() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+                }
+                state
+            }
+            None => self.rx.ready.wait_while(state, waiting).unwrap(),
+        };
+
+        let n = buf.len().min(state.data.len());
+        for (dst, src) in buf.iter_mut().zip(state.data.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut state = self.tx.state.lock().unwrap();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.data.extend(buf);
+        self.tx.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn set_read_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.read_timeout = Some(timeout);
+        Ok(())
+    }
+
+    /// Writes never block, so there is nothing to time out
+    fn set_write_timeout(&mut self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    /// There is no connection to re-establish, but throw away anything stale
+    /// still sitting in the receive buffer like a reopened port would
+    fn reconnect(&mut self) -> Result<()> {
+        self.rx.state.lock().unwrap().data.clear();
+        Ok(())
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.rx.close();
+        self.tx.close();
+    }
+}
+
+#[test]
+fn memory_pipe_duplex() {
+    let (mut a, mut b) = memory_pipe();
+    a.write_all(&[1, 2, 3]).unwrap();
+    b.write_all(&[4]).unwrap();
+
+    let mut buf = [0u8; 3];
+    b.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3]);
+    a.read_exact(&mut buf[0..1]).unwrap();
+    assert_eq!(buf[0], 4);
+}
+
+#[test]
+fn memory_pipe_timeout_and_eof() {
+    let (mut a, b) = memory_pipe();
+    a.set_read_timeout(Duration::from_millis(10)).unwrap();
+
+    let mut buf = [0u8; 1];
+    let err = a.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    drop(b);
+    assert_eq!(a.read(&mut buf).unwrap(), 0);
+}
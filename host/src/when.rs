@@ -0,0 +1,263 @@
+//! Reading points in time and durations the way people type them
+//!
+//! Besides anything `dateparser` understands (full dates, RFC 3339, explicit
+//! offsets and zone abbreviations) this accepts `now`, `in 10m`,
+//! `in 1h 30m`, `today 14:00`, `tomorrow 07:30` and a bare `14:00:05.250`.
+//! Times without a zone are taken to be in the zone passed in, the result is
+//! always UTC. Recurrence rules read like `daily 07:30`, `mon,wed,fri 18:00`,
+//! `weekdays 07:00` or `every 15m`.
+
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use shared::recur::Recurrence;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    Empty,
+    /// nothing we know of looks like this
+    Unrecognised(String),
+    /// a local time skipped by a daylight saving change
+    NoSuchLocalTime,
+    OutOfRange,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "nothing entered"),
+            ParseError::Unrecognised(s) => write!(f, "can't make sense of \"{}\"", s),
+            ParseError::NoSuchLocalTime => {
+                write!(f, "that local time is skipped by daylight saving")
+            }
+            ParseError::OutOfRange => write!(f, "out of range"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The instant `input` refers to, seen from `now` in zone `tz`
+pub fn parse_when(input: &str, now: DateTime<Utc>, tz: Tz) -> Result<DateTime<Utc>, ParseError> {
+    let input = input.trim();
+    let lower = input.to_lowercase();
+    if lower.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    if lower == "now" {
+        return Ok(now);
+    }
+
+    if let Some(rest) = lower.strip_prefix("in ") {
+        let duration = parse_duration(rest)?;
+        let duration = chrono::Duration::from_std(duration).map_err(|_| ParseError::OutOfRange)?;
+        return now
+            .checked_add_signed(duration)
+            .ok_or(ParseError::OutOfRange);
+    }
+
+    let today = now.with_timezone(&tz).date_naive();
+    let (day, rest) = if let Some(rest) = lower.strip_prefix("today ") {
+        (Some(today), rest)
+    } else if let Some(rest) = lower.strip_prefix("tomorrow ") {
+        let tomorrow = today.checked_add_days(Days::new(1));
+        (Some(tomorrow.ok_or(ParseError::OutOfRange)?), rest)
+    } else {
+        (None, lower.as_str())
+    };
+
+    match (day, parse_time_of_day(rest)) {
+        (Some(day), Some(time)) => local(day, time, tz),
+        (None, Some(time)) => local(today, time, tz),
+        (Some(_), None) => Err(ParseError::Unrecognised(input.to_string())),
+        (None, None) => dateparser::parse_with_timezone(input, &tz)
+            .map_err(|_| ParseError::Unrecognised(input.to_string())),
+    }
+}
+
+/// `90`, `1.5` (seconds), or with units like `250ms`, `10s`, `5m`, `1h 30m`
+pub fn parse_duration(input: &str) -> Result<Duration, ParseError> {
+    let input = input.trim().to_lowercase();
+    if input.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    let unrecognised = || ParseError::Unrecognised(input.clone());
+
+    let mut total = 0f64;
+    let mut rest = input.as_str();
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(split);
+        let number: f64 = number.parse().map_err(|_| unrecognised())?;
+
+        let tail = tail.trim_start();
+        let unit_len = tail
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        let scale = match unit {
+            "ms" => 0.001,
+            "" | "s" | "sec" | "secs" | "second" | "seconds" => 1.0,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60.0,
+            "h" | "hour" | "hours" => 3600.0,
+            "d" | "day" | "days" => 86400.0,
+            _ => return Err(unrecognised()),
+        };
+        total += number * scale;
+        rest = tail.trim_start();
+    }
+
+    Duration::try_from_secs_f64(total).map_err(|_| ParseError::OutOfRange)
+}
+
+/// `once`, `daily 07:30`, `weekdays 07:00`, `weekends 09:00`,
+/// `mon,wed,fri 18:00` or `every 15m`. Times of day are in the device's zone.
+pub fn parse_recurrence(input: &str) -> Result<Recurrence, ParseError> {
+    let input = input.trim().to_lowercase();
+    let unrecognised = || ParseError::Unrecognised(input.clone());
+    if input.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    if input == "once" {
+        return Ok(Recurrence::Once);
+    }
+    if let Some(rest) = input.strip_prefix("every ") {
+        let minutes = (parse_duration(rest)?.as_secs_f64() / 60.0).round();
+        if minutes < 1.0 {
+            return Err(ParseError::OutOfRange);
+        }
+        let minutes = u32::try_from(minutes as u64).map_err(|_| ParseError::OutOfRange)?;
+        return Ok(Recurrence::Every { minutes });
+    }
+
+    let (days, time) = input.rsplit_once(' ').ok_or_else(unrecognised)?;
+    let at_ms = parse_ms_of_day(time)?;
+    let days = match days {
+        "daily" => return Ok(Recurrence::Daily { at_ms }),
+        "weekdays" => 0b011_1110,
+        "weekends" => 0b100_0001,
+        days => days.split(',').try_fold(0u8, |mask, day| {
+            let bit = match day.trim() {
+                "sun" => 0,
+                "mon" => 1,
+                "tue" => 2,
+                "wed" => 3,
+                "thu" => 4,
+                "fri" => 5,
+                "sat" => 6,
+                _ => return Err(unrecognised()),
+            };
+            Ok(mask | 1 << bit)
+        })?,
+    };
+    Ok(Recurrence::Weekly { days, at_ms })
+}
+
+/// `hh:mm` or `hh:mm:ss.sss` as ms after midnight
+pub fn parse_ms_of_day(input: &str) -> Result<u32, ParseError> {
+    let time =
+        parse_time_of_day(input).ok_or_else(|| ParseError::Unrecognised(input.to_string()))?;
+    Ok(time.num_seconds_from_midnight() * 1000 + time.nanosecond() / 1_000_000)
+}
+
+fn parse_time_of_day(input: &str) -> Option<NaiveTime> {
+    let input = input.trim();
+    NaiveTime::parse_from_str(input, "%H:%M:%S%.f")
+        .or_else(|_| NaiveTime::parse_from_str(input, "%H:%M"))
+        .ok()
+}
+
+fn local(day: NaiveDate, time: NaiveTime, tz: Tz) -> Result<DateTime<Utc>, ParseError> {
+    /* when the clocks go back the earlier of the two is meant */
+    tz.from_local_datetime(&day.and_time(time))
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+        .ok_or(ParseError::NoSuchLocalTime)
+}
+
+#[test]
+fn when_relative_and_local() {
+    let tz: Tz = "Europe/Stockholm".parse().unwrap();
+    let now = Utc.with_ymd_and_hms(2024, 3, 30, 21, 0, 0).unwrap();
+    let utc = |d, h, m, s| Utc.with_ymd_and_hms(2024, 3, d, h, m, s).unwrap();
+
+    assert_eq!(parse_when("now", now, tz), Ok(now));
+    assert_eq!(parse_when("in 10m", now, tz), Ok(utc(30, 21, 10, 0)));
+    assert_eq!(parse_when("In 1h 30m", now, tz), Ok(utc(30, 22, 30, 0)));
+
+    /* 22:00 in Stockholm is still CET */
+    assert_eq!(parse_when("14:00", now, tz), Ok(utc(30, 13, 0, 0)));
+    assert_eq!(
+        parse_when("14:00:05.250", now, tz)
+            .unwrap()
+            .timestamp_millis(),
+        utc(30, 13, 0, 5).timestamp_millis() + 250
+    );
+    /* the clocks go forward overnight */
+    assert_eq!(parse_when("tomorrow 07:30", now, tz), Ok(utc(31, 5, 30, 0)));
+    assert_eq!(
+        parse_when("tomorrow 02:30", now, tz),
+        Err(ParseError::NoSuchLocalTime)
+    );
+
+    /* explicit zones win */
+    assert_eq!(
+        parse_when("2024-03-31T12:00:00Z", now, tz),
+        Ok(utc(31, 12, 0, 0))
+    );
+    assert_eq!(
+        parse_when("2024-03-31 12:00:00", now, tz),
+        Ok(utc(31, 10, 0, 0))
+    );
+
+    assert!(matches!(
+        parse_when("tomorow 7", now, tz),
+        Err(ParseError::Unrecognised(_))
+    ));
+    assert_eq!(parse_when("  ", now, tz), Err(ParseError::Empty));
+}
+
+#[test]
+fn when_durations() {
+    assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_duration("1.5"), Ok(Duration::from_millis(1500)));
+    assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+    assert_eq!(parse_duration("1h 30m"), Ok(Duration::from_secs(5400)));
+    assert_eq!(parse_duration("2 minutes"), Ok(Duration::from_secs(120)));
+    assert!(parse_duration("ten").is_err());
+    assert!(parse_duration("5 fortnights").is_err());
+}
+
+#[test]
+fn when_recurrences() {
+    let at = |h: u32, m: u32| (h * 60 + m) * 60 * 1000;
+    assert_eq!(parse_recurrence("once"), Ok(Recurrence::Once));
+    assert_eq!(
+        parse_recurrence("Daily 07:30"),
+        Ok(Recurrence::Daily { at_ms: at(7, 30) })
+    );
+    assert_eq!(
+        parse_recurrence("weekdays 07:00"),
+        Ok(Recurrence::Weekly {
+            days: 0b011_1110,
+            at_ms: at(7, 0)
+        })
+    );
+    assert_eq!(
+        parse_recurrence("mon,wed, fri 18:00:30"),
+        Ok(Recurrence::Weekly {
+            days: 0b010_1010,
+            at_ms: at(18, 0) + 30_000
+        })
+    );
+    assert_eq!(
+        parse_recurrence("every 1h 30m"),
+        Ok(Recurrence::Every { minutes: 90 })
+    );
+    assert_eq!(parse_recurrence("every 10s"), Err(ParseError::OutOfRange));
+    assert!(parse_recurrence("mon,funday 18:00").is_err());
+    assert!(parse_recurrence("daily").is_err());
+}
@@ -0,0 +1,616 @@
+//! Blink jobs scheduled on the device
+//!
+//! The device keeps a fixed number of jobs, each with its own ID. A job
+//! blinks either the plain LED or the RGB LED, each LED going by its own jobs.
+//! While jobs for the same LED overlap the one that started first blinks and
+//! the others wait their turn or run out unseen. On the RGB LED a running job
+//! comes before the RGB mode, off steps included, and the mode is back once
+//! the job is over. Times are device clock ms,
+//! which count from boot until the time is set. Recurring jobs are put back
+//! in for their next run when a run is over.
+//!
+//! Rather than toggling, the LED level is worked out from how far into its
+//! [`BlinkSpec`] a job is, together with how long until the level changes.
+//! Late timer interrupts therefore never shift the rest of the pattern.
+
+use crate::morse::unit_ms;
+#[cfg(test)]
+use crate::recur::Recurrence;
+use crate::tz::TimeZone;
+use crate::{
+    BlinkJob, BlinkLength, BlinkSpec, BlinkTarget, BlinkerOptions, JobId, RequestId,
+    MAX_PATTERN_STEPS,
+};
+
+/// Requests the table remembers the job of, a retry comes within a second or
+/// two so only the last few matter
+const RECENT_REQUESTS: usize = 4;
+
+/// The LEDs blink jobs can go to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    Led,
+    Rgb,
+}
+
+impl BlinkTarget {
+    pub fn output(&self) -> Output {
+        match self {
+            BlinkTarget::Led => Output::Led,
+            BlinkTarget::Rgb(_) => Output::Rgb,
+        }
+    }
+}
+
+/// One on or off step of a [`BlinkSpec`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub on: bool,
+    /// ms since the step started
+    pub into_ms: u64,
+    /// ms until it ends
+    pub left_ms: u64,
+}
+
+/// What an LED should be doing right now
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schedule {
+    /// no jobs left
+    Idle,
+    /// LED off until the next job starts in this many ms
+    Wait(u64),
+    /// job `id` is blinking, the LED is `on` for the next `for_ms`
+    Blink {
+        id: JobId,
+        on: bool,
+        for_ms: u64,
+        target: BlinkTarget,
+    },
+}
+
+#[derive(Debug)]
+pub struct JobTable<const N: usize> {
+    slots: [Option<BlinkJob>; N],
+    next_id: JobId,
+    /// the jobs the last requests made, oldest first
+    recent: [Option<(RequestId, JobId)>; RECENT_REQUESTS],
+}
+
+impl<const N: usize> JobTable<N> {
+    pub const fn new() -> Self {
+        JobTable {
+            slots: [None; N],
+            next_id: 0,
+            recent: [None; RECENT_REQUESTS],
+        }
+    }
+
+    /// The job `request` made already, when it comes again because the answer
+    /// got lost. The job may be over or cancelled by now.
+    pub fn added_for(&self, request: RequestId) -> Option<JobId> {
+        self.recent
+            .iter()
+            .flatten()
+            .find(|(r, _)| *r == request)
+            .map(|(_, id)| *id)
+    }
+
+    /// Schedule the blink in `options` from `start`, which its `date_time`
+    /// resolves to, for `request`. Returns the job's ID, or `None` if the
+    /// table is full or the options turn blinking off.
+    pub fn add(
+        &mut self,
+        start: u64,
+        options: &BlinkerOptions,
+        request: RequestId,
+    ) -> Option<JobId> {
+        let BlinkerOptions::On {
+            spec,
+            length,
+            repeat,
+            target,
+            shape,
+            ..
+        } = *options
+        else {
+            return None;
+        };
+        let slot = self.slots.iter_mut().find(|s| s.is_none())?;
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        *slot = Some(BlinkJob {
+            id,
+            start,
+            spec,
+            duration: length.ms(&spec),
+            repeat,
+            target,
+            shape,
+        });
+        self.recent.rotate_left(1);
+        self.recent[RECENT_REQUESTS - 1] = Some((request, id));
+        Some(id)
+    }
+
+    /// Drop job `id`, false if there is no such job
+    pub fn cancel(&mut self, id: JobId) -> bool {
+        match self
+            .slots
+            .iter_mut()
+            .find(|s| s.is_some_and(|j| j.id == id))
+        {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.slots = [None; N];
+    }
+
+    pub fn job(&self, id: JobId) -> Option<BlinkJob> {
+        self.slots.iter().flatten().find(|j| j.id == id).copied()
+    }
+
+    /// All jobs, in no particular order
+    pub fn jobs(&self) -> [Option<BlinkJob>; N] {
+        self.slots
+    }
+
+    /// Move every job by `ms`, for when the clock they were scheduled on jumps
+    pub fn shift(&mut self, ms: i64) {
+        for job in self.slots.iter_mut().flatten() {
+            job.start = job.start.saturating_add_signed(ms);
+        }
+    }
+
+    /// Take care of one job whose run is over by `now`, call until `None`.
+    /// Recurring jobs move on to their next run, the others are removed.
+    pub fn expire(&mut self, now: u64, tz: &TimeZone) -> Option<JobId> {
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|s| s.is_some_and(|j| now >= j.start.saturating_add(j.duration)))?;
+        let job = slot.as_mut()?;
+        let id = job.id;
+        match job.repeat.next(job.start, now, tz) {
+            Some(start) => job.start = start,
+            None => *slot = None,
+        }
+        Some(id)
+    }
+
+    /// What to do with `output` at `now`, assuming finished jobs have been
+    /// expired
+    pub fn schedule(&self, now: u64, output: Output) -> Schedule {
+        let jobs = self
+            .slots
+            .iter()
+            .flatten()
+            .filter(|j| j.target.output() == output);
+        let Some(first) = jobs.min_by_key(|j| j.start) else {
+            return Schedule::Idle;
+        };
+
+        if first.start > now {
+            return Schedule::Wait(first.start - now);
+        }
+        /* stop right at the end rather than at the next change */
+        let end = first.start.saturating_add(first.duration);
+        let (on, for_ms) = first.spec.level_at(now - first.start);
+        Schedule::Blink {
+            id: first.id,
+            on,
+            for_ms: for_ms.min(end.saturating_sub(now)).max(1),
+            target: first.target,
+        }
+    }
+}
+
+impl<const N: usize> Default for JobTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlinkSpec {
+    /// `hz` at 50% duty, the period in whole ms, 0 gives an invalid spec
+    pub fn hz(hz: f32) -> Self {
+        BlinkSpec::Periodic {
+            period_ms: if hz > 0.0 { (1000.0 / hz) as u32 } else { 0 },
+            duty_percent: 50,
+        }
+    }
+
+    /// Pattern of up to [`MAX_PATTERN_STEPS`] on/off steps in ms, `None` if
+    /// there are more
+    pub fn pattern(steps: &[u16]) -> Option<Self> {
+        let mut buf = [0; MAX_PATTERN_STEPS];
+        buf.get_mut(..steps.len())?.copy_from_slice(steps);
+        Some(BlinkSpec::Pattern {
+            steps: buf,
+            len: steps.len() as u8,
+        })
+    }
+
+    /// Something the LED can actually follow
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            BlinkSpec::Periodic {
+                period_ms,
+                duty_percent,
+            } => period_ms > 0 && duty_percent <= 100,
+            BlinkSpec::Pattern { len, .. } => {
+                (1..=MAX_PATTERN_STEPS).contains(&(len as usize)) && self.cycle_ms() > 0
+            }
+            BlinkSpec::Morse { text, wpm } => text.is_valid() && wpm > 0,
+        }
+    }
+
+    /// ms for one period or one time through the pattern
+    pub fn cycle_ms(&self) -> u64 {
+        match self {
+            BlinkSpec::Periodic { period_ms, .. } => *period_ms as u64,
+            BlinkSpec::Pattern { .. } => self.steps().iter().map(|&s| s as u64).sum(),
+            BlinkSpec::Morse { text, wpm } => text.units() * unit_ms(*wpm),
+        }
+    }
+
+    /// Whether the LED is on `elapsed` ms after the start, and for how much
+    /// longer it stays that way
+    pub fn level_at(&self, elapsed: u64) -> (bool, u64) {
+        let step = self.step_at(elapsed);
+        (step.on, step.left_ms)
+    }
+
+    /// The step `elapsed` ms after the start
+    pub fn step_at(&self, elapsed: u64) -> Step {
+        let cycle = self.cycle_ms().max(1);
+        let phase = elapsed % cycle;
+        match *self {
+            BlinkSpec::Periodic {
+                period_ms,
+                duty_percent,
+            } => {
+                let on_ms = period_ms as u64 * duty_percent.min(100) as u64 / 100;
+                if phase < on_ms {
+                    Step {
+                        on: true,
+                        into_ms: phase,
+                        left_ms: on_ms - phase,
+                    }
+                } else {
+                    Step {
+                        on: false,
+                        into_ms: phase - on_ms,
+                        left_ms: cycle - phase,
+                    }
+                }
+            }
+            BlinkSpec::Pattern { .. } => {
+                let mut step_end = 0;
+                for (i, &step) in self.steps().iter().enumerate() {
+                    step_end += step as u64;
+                    if phase < step_end {
+                        return Step {
+                            on: i % 2 == 0,
+                            into_ms: phase + step as u64 - step_end,
+                            left_ms: step_end - phase,
+                        };
+                    }
+                }
+                Step {
+                    on: false,
+                    into_ms: phase - step_end,
+                    left_ms: cycle - phase,
+                }
+            }
+            BlinkSpec::Morse { text, wpm } => text.step_at(wpm, elapsed),
+        }
+    }
+
+    fn steps(&self) -> &[u16] {
+        match self {
+            BlinkSpec::Pattern { steps, len } => &steps[..(*len as usize).min(MAX_PATTERN_STEPS)],
+            BlinkSpec::Periodic { .. } | BlinkSpec::Morse { .. } => &[],
+        }
+    }
+}
+
+impl BlinkLength {
+    /// ms a job following `spec` runs for
+    pub fn ms(&self, spec: &BlinkSpec) -> u64 {
+        match *self {
+            BlinkLength::Duration(ms) => ms,
+            BlinkLength::Repetitions(n) => spec.cycle_ms().saturating_mul(n as u64),
+        }
+    }
+}
+
+/// `spec` on `target` for `ms`
+#[cfg(test)]
+fn blink(spec: BlinkSpec, ms: u64, repeat: Recurrence, target: BlinkTarget) -> BlinkerOptions {
+    BlinkerOptions::On {
+        date_time: crate::DateTime::Now,
+        spec,
+        length: BlinkLength::Duration(ms),
+        repeat,
+        target,
+        shape: crate::pwm::BlinkShape::Square,
+    }
+}
+
+#[test]
+fn blink_table_ids_and_cancel() {
+    let mut t = JobTable::<2>::new();
+    assert_eq!(t.schedule(0, Output::Led), Schedule::Idle);
+
+    let a = t
+        .add(
+            5_000,
+            &blink(
+                BlinkSpec::hz(2.0),
+                1_000,
+                Recurrence::Once,
+                BlinkTarget::Led,
+            ),
+            1,
+        )
+        .unwrap();
+    let b = t
+        .add(
+            1_000,
+            &blink(
+                BlinkSpec::hz(2.0),
+                1_000,
+                Recurrence::Once,
+                BlinkTarget::Led,
+            ),
+            2,
+        )
+        .unwrap();
+    assert_ne!(a, b);
+    assert_eq!(
+        t.add(
+            0,
+            &blink(BlinkSpec::hz(1.0), 1, Recurrence::Once, BlinkTarget::Led),
+            3
+        ),
+        None
+    );
+
+    assert_eq!(t.schedule(0, Output::Led), Schedule::Wait(1_000));
+    assert!(t.cancel(b));
+    assert!(!t.cancel(b));
+    assert_eq!(t.schedule(0, Output::Led), Schedule::Wait(5_000));
+    assert_eq!(t.jobs().iter().flatten().count(), 1);
+
+    /* a freed slot can be reused */
+    assert!(t
+        .add(
+            0,
+            &blink(BlinkSpec::hz(1.0), 1, Recurrence::Once, BlinkTarget::Led),
+            4
+        )
+        .is_some());
+    t.clear();
+    assert_eq!(t.schedule(0, Output::Led), Schedule::Idle);
+}
+
+#[test]
+fn blink_table_runs_jobs_in_order() {
+    let mut t = JobTable::<4>::new();
+    let late = t
+        .add(
+            10_000,
+            &blink(
+                BlinkSpec::hz(1.0),
+                5_000,
+                Recurrence::Once,
+                BlinkTarget::Led,
+            ),
+            5,
+        )
+        .unwrap();
+    let early = t
+        .add(
+            2_000,
+            &blink(
+                BlinkSpec::hz(4.0),
+                1_100,
+                Recurrence::Once,
+                BlinkTarget::Led,
+            ),
+            6,
+        )
+        .unwrap();
+
+    assert_eq!(t.expire(2_000, &TimeZone::UTC), None);
+    assert_eq!(
+        t.schedule(2_000, Output::Led),
+        Schedule::Blink {
+            id: early,
+            on: true,
+            for_ms: 125,
+            target: BlinkTarget::Led
+        }
+    );
+    assert_eq!(
+        t.schedule(2_130, Output::Led),
+        Schedule::Blink {
+            id: early,
+            on: false,
+            for_ms: 120,
+            target: BlinkTarget::Led
+        }
+    );
+    /* the last one is cut short */
+    assert_eq!(
+        t.schedule(3_000, Output::Led),
+        Schedule::Blink {
+            id: early,
+            on: true,
+            for_ms: 100,
+            target: BlinkTarget::Led
+        }
+    );
+
+    assert_eq!(t.expire(3_100, &TimeZone::UTC), Some(early));
+    assert_eq!(t.expire(3_100, &TimeZone::UTC), None);
+    assert_eq!(t.schedule(3_100, Output::Led), Schedule::Wait(6_900));
+
+    /* set up before the time was known, then the clock is set */
+    t.shift(1_700_000_000_000);
+    assert_eq!(
+        t.schedule(1_700_000_003_100, Output::Led),
+        Schedule::Wait(6_900)
+    );
+    assert_eq!(t.expire(1_700_000_015_000, &TimeZone::UTC), Some(late));
+}
+
+#[test]
+fn blink_table_repeats_jobs() {
+    let mut t = JobTable::<1>::new();
+    let id = t
+        .add(
+            1_000,
+            &blink(
+                BlinkSpec::hz(1.0),
+                10_000,
+                Recurrence::Every { minutes: 1 },
+                BlinkTarget::Led,
+            ),
+            7,
+        )
+        .unwrap();
+
+    /* the same job comes back for the next run */
+    assert_eq!(t.expire(11_000, &TimeZone::UTC), Some(id));
+    assert_eq!(t.expire(11_000, &TimeZone::UTC), None);
+    assert_eq!(t.schedule(11_000, Output::Led), Schedule::Wait(50_000));
+    assert_eq!(t.jobs()[0].map(|j| j.start), Some(61_000));
+}
+
+#[test]
+fn blink_table_keeps_leds_apart() {
+    let red = BlinkTarget::Rgb(crate::rgb::Color { r: 255, g: 0, b: 0 });
+    let mut t = JobTable::<2>::new();
+    let led = t
+        .add(
+            0,
+            &blink(
+                BlinkSpec::hz(1.0),
+                5_000,
+                Recurrence::Once,
+                BlinkTarget::Led,
+            ),
+            8,
+        )
+        .unwrap();
+    let rgb = t
+        .add(
+            1_000,
+            &blink(BlinkSpec::hz(2.0), 2_000, Recurrence::Once, red),
+            9,
+        )
+        .unwrap();
+
+    /* the later RGB job doesn't wait for the LED job */
+    assert_eq!(
+        t.schedule(1_000, Output::Led),
+        Schedule::Blink {
+            id: led,
+            on: true,
+            for_ms: 500,
+            target: BlinkTarget::Led
+        }
+    );
+    assert_eq!(
+        t.schedule(1_000, Output::Rgb),
+        Schedule::Blink {
+            id: rgb,
+            on: true,
+            for_ms: 250,
+            target: red
+        }
+    );
+    assert_eq!(t.schedule(0, Output::Rgb), Schedule::Wait(1_000));
+
+    assert_eq!(t.expire(3_000, &TimeZone::UTC), Some(rgb));
+    assert_eq!(t.schedule(3_000, Output::Rgb), Schedule::Idle);
+    assert!(matches!(
+        t.schedule(3_000, Output::Led),
+        Schedule::Blink { id, .. } if id == led
+    ));
+}
+
+#[test]
+fn blink_specs() {
+    /* half a hertz at 25% duty */
+    let slow = BlinkSpec::Periodic {
+        period_ms: 2_000,
+        duty_percent: 25,
+    };
+    assert!(slow.is_valid());
+    assert_eq!(slow.level_at(0), (true, 500));
+    assert_eq!(slow.level_at(499), (true, 1));
+    assert_eq!(slow.level_at(500), (false, 1_500));
+    assert_eq!(slow.level_at(4_100), (true, 400));
+    assert_eq!(BlinkLength::Repetitions(3).ms(&slow), 6_000);
+
+    let dark = BlinkSpec::Periodic {
+        period_ms: 1_000,
+        duty_percent: 0,
+    };
+    assert_eq!(dark.level_at(10), (false, 990));
+
+    /* short short long */
+    let sos = BlinkSpec::pattern(&[100, 100, 100, 100, 300, 500]).unwrap();
+    assert!(sos.is_valid());
+    assert_eq!(sos.cycle_ms(), 1_200);
+    assert_eq!(sos.level_at(150), (false, 50));
+    assert_eq!(sos.level_at(450), (true, 250));
+    assert_eq!(sos.level_at(1_300), (false, 100));
+    assert_eq!(BlinkLength::Repetitions(2).ms(&sos), 2_400);
+
+    let morse = BlinkSpec::Morse {
+        text: crate::morse::MorseText::new("SOS").unwrap(),
+        wpm: 20,
+    };
+    assert!(morse.is_valid());
+    assert_eq!(BlinkLength::Repetitions(1).ms(&morse), 34 * 60);
+    assert_eq!(morse.level_at(90), (false, 30));
+
+    assert!(BlinkSpec::pattern(&[1; MAX_PATTERN_STEPS + 1]).is_none());
+    assert!(!BlinkSpec::pattern(&[]).unwrap().is_valid());
+    assert!(!BlinkSpec::pattern(&[0, 0]).unwrap().is_valid());
+    assert!(!BlinkSpec::hz(0.0).is_valid());
+    assert_eq!(
+        BlinkSpec::hz(0.5),
+        BlinkSpec::Periodic {
+            period_ms: 2_000,
+            duty_percent: 50
+        }
+    );
+}
+
+#[test]
+fn blink_table_remembers_requests() {
+    let mut t = JobTable::<2>::new();
+    let once = blink(BlinkSpec::hz(1.0), 1_000, Recurrence::Once, BlinkTarget::Led);
+    let id = t.add(0, &once, 7).unwrap();
+    assert_eq!(t.added_for(7), Some(id));
+    assert_eq!(t.added_for(8), None);
+
+    /* still known once the job is gone, until enough requests came after */
+    assert!(t.cancel(id));
+    assert_eq!(t.added_for(7), Some(id));
+    for request in 8..8 + RECENT_REQUESTS as RequestId {
+        let id = t.add(0, &once, request).unwrap();
+        assert!(t.cancel(id));
+    }
+    assert_eq!(t.added_for(7), None);
+}
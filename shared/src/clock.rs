@@ -0,0 +1,226 @@
+//! Wall clock kept on top of a free running tick counter
+//!
+//! The clock is set from the host now and then. Every time it is, the ticks
+//! counted since the previous setting are compared with how much time passed
+//! on the host, which gives the rate error of the tick source. That error is
+//! then corrected for when converting ticks to time.
+//!
+//! Settings normally step the clock. With a [`Slew`] window configured, small
+//! corrections are instead spread over the window by running the clock a bit
+//! faster or slower, so the time never jumps and never goes backwards.
+
+use crate::Slew;
+
+/// Settings closer together than this say too little about the rate
+pub const MIN_DRIFT_INTERVAL_MS: u64 = 60 * 1000;
+
+/// Anything beyond this is not crystal error but someone setting a different
+/// time, the estimate starts over from there
+pub const MAX_DRIFT_PPM: i64 = 500;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    ticks_per_second: u64,
+    /// ms, at `sys_reference`
+    utc_reference: u64,
+    sys_reference: u64,
+    /// rate correction in parts per million, positive if the ticks run slow
+    ppm: i32,
+    /// how many settings went into `ppm`
+    estimates: u32,
+    /// ticks and host time at the setting drift is measured from
+    baseline: Option<(u64, u64)>,
+    slew: Slew,
+    /// ms still being worked in since `sys_reference`, and over how long
+    slewing: Option<(i64, i64)>,
+}
+
+/// What a setting did to the clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Correction {
+    /// the time jumped, whatever is scheduled on it needs another look
+    Step,
+    /// the time is being moved over the slew window
+    Slew,
+}
+
+impl Clock {
+    pub const fn new(ticks_per_second: u64) -> Self {
+        Clock {
+            ticks_per_second,
+            utc_reference: 0,
+            sys_reference: 0,
+            ppm: 0,
+            estimates: 0,
+            baseline: None,
+            slew: Slew {
+                window_ms: 0,
+                max_offset_ms: 0,
+            },
+            slewing: None,
+        }
+    }
+
+    /// Slew corrections up to `slew.max_offset_ms` from now on, a window of 0
+    /// steps every correction
+    pub fn set_slew(&mut self, slew: Slew) {
+        self.slew = slew;
+    }
+
+    /// Time has been set at least once, assuming nobody sets it to the epoch
+    pub fn is_set(&self) -> bool {
+        self.utc_reference != 0
+    }
+
+    /// Time in ms at tick count `now`
+    pub fn time_ms(&self, now: u64) -> u64 {
+        let elapsed = self.ticks_ms(now.saturating_sub(self.sys_reference)) as i64;
+        let corrected = elapsed + elapsed * self.ppm as i64 / 1_000_000;
+        let slewed = match self.slewing {
+            Some((offset, window)) if corrected < window => offset * corrected / window,
+            Some((offset, _)) => offset,
+            None => 0,
+        };
+        self.utc_reference.saturating_add_signed(corrected + slewed)
+    }
+
+    /// The host says it is `utc` ms at tick count `now`
+    pub fn set(&mut self, now: u64, utc: u64) -> Correction {
+        self.estimate(now, utc);
+
+        let current = self.time_ms(now);
+        let offset = utc as i64 - current as i64;
+        let window = self.slew.window_ms as i64;
+        /* the clock has to keep moving forward while slewing back */
+        let slew = self.is_set()
+            && offset.unsigned_abs() <= self.slew.max_offset_ms
+            && offset.abs() < window;
+
+        self.sys_reference = now;
+        if slew {
+            self.utc_reference = current;
+            self.slewing = Some((offset, window));
+            Correction::Slew
+        } else {
+            self.utc_reference = utc;
+            self.slewing = None;
+            Correction::Step
+        }
+    }
+
+    /// The host says we are `offset` ms off at tick count `now`
+    pub fn adjust(&mut self, now: u64, offset: i64) -> Correction {
+        let utc = self.time_ms(now).saturating_add_signed(offset);
+        self.set(now, utc)
+    }
+
+    /// Estimated rate correction in parts per million
+    pub fn ppm(&self) -> i32 {
+        self.ppm
+    }
+
+    /// Number of settings the estimate is based on, 0 if there is none yet
+    pub fn estimates(&self) -> u32 {
+        self.estimates
+    }
+
+    /// ticks to ms, seconds and the rest separately so nothing overflows
+    fn ticks_ms(&self, ticks: u64) -> u64 {
+        let per_s = self.ticks_per_second;
+        ticks / per_s * 1000 + ticks % per_s * 1000 / per_s
+    }
+
+    fn estimate(&mut self, now: u64, utc: u64) {
+        let Some((ticks, host)) = self.baseline else {
+            self.baseline = Some((now, utc));
+            return;
+        };
+
+        let host_elapsed = utc as i64 - host as i64;
+        if host_elapsed < MIN_DRIFT_INTERVAL_MS as i64 {
+            /* keep measuring from the older setting */
+            if host_elapsed < 0 {
+                self.baseline = Some((now, utc));
+            }
+            return;
+        }
+        self.baseline = Some((now, utc));
+
+        let local_elapsed = self.ticks_ms(now.saturating_sub(ticks)) as i64;
+        let ppm = (host_elapsed - local_elapsed) * 1_000_000 / local_elapsed.max(1);
+        if ppm.abs() > MAX_DRIFT_PPM {
+            return;
+        }
+
+        /* smooth out the jitter of the individual settings */
+        self.ppm = if self.estimates == 0 {
+            ppm as i32
+        } else {
+            ((self.ppm as i64 * 3 + ppm) / 4) as i32
+        };
+        self.estimates = self.estimates.saturating_add(1);
+    }
+}
+
+#[test]
+fn clock_converts_ticks() {
+    let mut c = Clock::new(16_000_000);
+    assert!(!c.is_set());
+    c.set(16_000_000, 1_000);
+    assert_eq!(c.time_ms(16_000_000 + 8_000_000), 1_500);
+
+    /* years worth of ticks */
+    let far = 16_000_000 * 60 * 60 * 24 * 365 * 30;
+    assert_eq!(c.time_ms(16_000_000 + far), 1_000 + far / 16_000);
+
+    assert_eq!(c.adjust(32_000_000, -500), Correction::Step);
+    assert_eq!(c.time_ms(32_000_000), 1_500);
+}
+
+#[test]
+fn clock_slews_small_corrections() {
+    let mut c = Clock::new(1_000);
+    c.set_slew(Slew {
+        window_ms: 10_000,
+        max_offset_ms: 1_000,
+    });
+    assert_eq!(c.set(0, 100_000), Correction::Step);
+
+    /* half a second back, spread over ten */
+    assert_eq!(c.adjust(1_000, -500), Correction::Slew);
+    assert_eq!(c.time_ms(1_000), 101_000);
+    assert_eq!(c.time_ms(6_000), 105_750);
+    assert_eq!(c.time_ms(11_000), 110_500);
+    assert_eq!(c.time_ms(12_000), 111_500);
+    let times: Vec<u64> = (1_000..12_000).map(|t| c.time_ms(t)).collect();
+    assert!(times.windows(2).all(|w| w[0] <= w[1]));
+
+    /* a large one still steps */
+    assert_eq!(c.adjust(12_000, 5_000), Correction::Step);
+    assert_eq!(c.time_ms(12_000), 116_500);
+}
+
+#[test]
+fn clock_estimates_drift() {
+    /* ticks run 100 ppm slow */
+    let tps = 1_000_000;
+    let ticks = |ms: u64| ms * 1000 * 9_999 / 10_000;
+    let mut c = Clock::new(tps);
+
+    let start = 1_700_000_000_000;
+    c.set(ticks(0), start);
+    c.set(ticks(10_000), start + 10_000);
+    assert_eq!(c.estimates(), 0);
+
+    c.set(ticks(3_600_000), start + 3_600_000);
+    assert_eq!(c.estimates(), 1);
+    assert_eq!(c.ppm(), 100);
+
+    /* corrected from here on */
+    let later = 7_200_000;
+    assert!(c.time_ms(ticks(later)).abs_diff(start + later) < 5);
+
+    /* someone sets a completely different time, the estimate stays */
+    c.set(ticks(2 * later), start);
+    assert_eq!((c.ppm(), c.estimates()), (100, 1));
+}
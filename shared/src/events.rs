@@ -0,0 +1,126 @@
+//! Outgoing event bookkeeping for the device
+//!
+//! Events are kept until the host acknowledges their sequence number and are
+//! retransmitted every `interval` until then. When the queue is full the
+//! oldest event is dropped to make room.
+
+use crate::{Event, EventSeq};
+
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    seq: EventSeq,
+    event: Event,
+    /// time of the last transmission, `None` if never sent
+    sent_at: Option<u64>,
+}
+
+pub struct EventQueue<const N: usize> {
+    slots: [Option<Pending>; N],
+    next_seq: EventSeq,
+}
+
+impl<const N: usize> EventQueue<N> {
+    pub const fn new() -> Self {
+        EventQueue {
+            slots: [None; N],
+            next_seq: 0,
+        }
+    }
+
+    /// Queue `event` for sending, returns its sequence number
+    pub fn push(&mut self, event: Event) -> EventSeq {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        let pending = Some(Pending {
+            seq,
+            event,
+            sent_at: None,
+        });
+
+        if let Some(slot) = self.slots.iter_mut().find(|s| s.is_none()) {
+            *slot = pending;
+            return seq;
+        }
+
+        /* full, overwrite the oldest one */
+        let oldest = self
+            .slots
+            .iter_mut()
+            .max_by_key(|s| s.map(|p| seq.wrapping_sub(p.seq)))
+            .unwrap();
+        *oldest = pending;
+        seq
+    }
+
+    /// The host received `seq`, stop sending it. Returns false for unknown (or
+    /// already acknowledged) sequence numbers.
+    pub fn ack(&mut self, seq: EventSeq) -> bool {
+        for slot in self.slots.iter_mut() {
+            if matches!(slot, Some(p) if p.seq == seq) {
+                *slot = None;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Oldest event that was never sent or was last sent at least `interval`
+    /// before `now`. It is marked as sent at `now`.
+    pub fn due(&mut self, now: u64, interval: u64) -> Option<(EventSeq, Event)> {
+        let next = self.next_seq;
+        let slot = self
+            .slots
+            .iter_mut()
+            .flatten()
+            .filter(|p| match p.sent_at {
+                None => true,
+                Some(t) => now.wrapping_sub(t) >= interval,
+            })
+            .max_by_key(|p| next.wrapping_sub(p.seq))?;
+
+        slot.sent_at = Some(now);
+        Some((slot.seq, slot.event))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.iter().all(|s| s.is_none())
+    }
+}
+
+impl<const N: usize> Default for EventQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn events_retransmit_until_acked() {
+    let mut q = EventQueue::<4>::new();
+    let boot = q.push(Event::Boot);
+    let button = q.push(Event::ButtonPressed);
+
+    /* oldest first, each sent once per interval */
+    assert_eq!(q.due(0, 10), Some((boot, Event::Boot)));
+    assert_eq!(q.due(1, 10), Some((button, Event::ButtonPressed)));
+    assert_eq!(q.due(5, 10), None);
+
+    assert!(q.ack(boot));
+    assert!(!q.ack(boot));
+    assert_eq!(q.due(11, 10), Some((button, Event::ButtonPressed)));
+
+    assert!(q.ack(button));
+    assert!(q.is_empty());
+}
+
+#[test]
+fn events_drop_oldest_when_full() {
+    let mut q = EventQueue::<2>::new();
+    q.push(Event::Boot);
+    let a = q.push(Event::ButtonPressed);
+    let b = q.push(Event::BlinkFinished(0));
+
+    assert_eq!(q.due(0, 10), Some((a, Event::ButtonPressed)));
+    assert_eq!(q.due(0, 10), Some((b, Event::BlinkFinished(0))));
+    assert_eq!(q.due(0, 10), None);
+}
@@ -77,7 +77,7 @@ fn hamming_no_flips() {
         let h = encode_hamming(i);
         let v = decode_hamming(h);
 
-        assert!(!v.is_none());
+        assert!(v.is_some());
         let (v, f) = v.unwrap();
 
         if i != v {
@@ -85,7 +85,7 @@ fn hamming_no_flips() {
         }
 
         assert_eq!(i, v);
-        assert_eq!(false, f);
+        assert!(!f);
     }
 }
 
@@ -98,7 +98,7 @@ fn hamming_one_flip() {
             h ^= 1 << j;
             let v = decode_hamming(h);
 
-            assert!(!v.is_none());
+            assert!(v.is_some());
             let (v, f) = v.unwrap();
 
             /* help debugging */
@@ -107,7 +107,7 @@ fn hamming_one_flip() {
             }
 
             assert_eq!(i, v);
-            assert_eq!(true, f);
+            assert!(f);
         }
     }
 }
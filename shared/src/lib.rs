@@ -1,27 +1,142 @@
 #![cfg_attr(not(test), no_std)]
-#![feature(iter_array_chunks)]
 use hamming::{encode_hamming};
 use serde_derive::{Deserialize, Serialize};
+pub mod blink;
+pub mod clock;
+pub mod events;
 pub mod hamming;
+pub mod morse;
+pub mod night;
+pub mod pwm;
+pub mod recur;
+pub mod rgb;
+pub mod strip;
+pub mod sun;
+pub mod timesync;
+pub mod tz;
 
 // we could use new-type pattern here but let's keep it simple
 pub type Id = u32;
 pub type DevId = u32;
 pub type Parameter = u32;
+pub type EventSeq = u16;
+pub type JobId = u16;
+/// Tells the requests that schedule a blink apart, see [`Command::SetBlinker`]
+pub type RequestId = u32;
+
+/// Blink jobs the device can hold at once
+pub const MAX_BLINK_JOBS: usize = 8;
+/// Longest on/off sequence a [`BlinkSpec::Pattern`] can hold
+pub const MAX_PATTERN_STEPS: usize = 8;
 
 use core::mem::size_of;
 use corncobs::max_encoded_len;
 
-pub const IN_SIZE: usize = max_encoded_len(size_of::<Ack>() + size_of::<u32>()) * 2;
+pub const IN_SIZE: usize = max_encoded_len(size_of::<DeviceMessage>() + size_of::<u32>()) * 2;
 pub const OUT_SIZE: usize = max_encoded_len(size_of::<Command>() + size_of::<u32>()) * 2;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[repr(C)]
 pub enum Command {
-    SetBlinker(BlinkerOptions),
+    /// Answered with [`DeviceMessage::BlinkScheduled`], `Off` cancels every job.
+    /// `request` is new for every blink, a retry with the same one is answered
+    /// with the job the first one made rather than making another.
+    SetBlinker {
+        options: BlinkerOptions,
+        request: RequestId,
+    },
     SetDateTime(DateTime),
     RgbOn,
     RgbOff,
+    /// The host received the event with this sequence number, not answered
+    AckEvent(EventSeq),
+    /// Host clock in ms, answered with [`DeviceMessage::TimeSync`]
+    SyncTime { t1: u64 },
+    /// Move the device clock by `offset` ms. `t1` is that of a
+    /// [`Command::SyncTime`] the offset was measured with, a retry with the
+    /// same `t1` is acked without moving the clock again
+    AdjustTime { offset: i64, t1: u64 },
+    /// Answered with [`DeviceMessage::Drift`]
+    GetDrift,
+    /// How time corrections are applied from now on
+    SetSlew(Slew),
+    /// Zone the device shows local time in, the clock itself stays UTC
+    SetTimeZone(tz::TimeZone),
+    /// Answered with [`DeviceMessage::BlinkJobs`]
+    ListBlinks,
+    CancelBlink(JobId),
+    /// Replaces the previous RGB schedule
+    ScheduleRgb(RgbSchedule),
+    /// Flash `text` once on the blink LED, scheduled and answered like
+    /// [`Command::SetBlinker`]
+    Morse {
+        date_time: DateTime,
+        text: morse::MorseText,
+        wpm: u8,
+        request: RequestId,
+    },
+    /// Show this colour from now on, the LED is turned on
+    SetRgb { r: u8, g: u8, b: u8, brightness: u8 },
+    SetBrightness(u8),
+    /// Switch between the time of day palette and the last [`Command::SetRgb`]
+    /// colour
+    SetRgbMode(rgb::RgbMode),
+    /// Colours for the palette mode, replaces the default one
+    SetPalette(rgb::Palette),
+    /// Where the device is, for [`rgb::RgbMode::Sun`]
+    SetLocation(sun::Location),
+    /// Animate the RGB LED, switches to [`rgb::RgbMode::Animated`]
+    Animate(rgb::Animation),
+    /// Number of pixels on the strip, 1 to [`strip::MAX_PIXELS`]
+    SetStripLength(u16),
+    /// Colour `len` pixels from `start` on, switches to
+    /// [`rgb::RgbMode::Pixels`]
+    FillPixels { start: u16, len: u16, color: rgb::Color },
+    /// Part of a whole frame, shown with the last chunk
+    PixelChunk(strip::PixelChunk),
+    /// Dim or turn off the LEDs at night, `None` turns night mode off
+    SetNightMode(Option<night::NightWindow>),
+    /// Night or day right now regardless of the night window
+    NightOverride(night::NightOverride),
+    /// Answered with [`DeviceMessage::NightMode`]
+    GetNightMode,
+    /// Duty cycle in percent the blink LED is on at, up to
+    /// [`pwm::MAX_DUTY`]
+    SetLedBrightness(u8),
+}
+
+impl Command {
+    /// Whether the device answers this command at all
+    pub fn expects_response(&self) -> bool {
+        !matches!(self, Command::AckEvent(_))
+    }
+
+    /// The blink job this asks for, if any, and the request asking
+    pub fn blink(&self) -> Option<(RequestId, BlinkerOptions)> {
+        match *self {
+            Command::SetBlinker {
+                options: options @ BlinkerOptions::On { .. },
+                request,
+            } => Some((request, options)),
+            Command::Morse {
+                date_time,
+                text,
+                wpm,
+                request,
+            } => Some((
+                request,
+                BlinkerOptions::On {
+                    date_time,
+                    spec: BlinkSpec::Morse { text, wpm },
+                    length: BlinkLength::Repetitions(1),
+                    repeat: recur::Recurrence::Once,
+                    target: BlinkTarget::Led,
+                    shape: pwm::BlinkShape::Square,
+                },
+            )),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
@@ -30,8 +145,61 @@ pub enum BlinkerOptions {
     Off,
     On {
         date_time: DateTime,
-        freq: u64,
+        spec: BlinkSpec,
+        length: BlinkLength,
+        repeat: recur::Recurrence,
+        target: BlinkTarget,
+        shape: pwm::BlinkShape,
+    },
+}
+
+/// Which LED a blink job flashes
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum BlinkTarget {
+    /// the plain LED
+    Led,
+    /// the RGB LED, or the whole strip, in this colour. It shows over
+    /// whatever the RGB LED is doing until the job is over.
+    Rgb(rgb::Color),
+}
+
+/// How the LED blinks while a blink job runs
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum BlinkSpec {
+    /// on for `duty_percent` of every `period_ms`, starting with on
+    Periodic { period_ms: u32, duty_percent: u8 },
+    /// the first `len` steps, in ms, alternately on and off starting with on,
+    /// over and over
+    Pattern {
+        steps: [u16; MAX_PATTERN_STEPS],
+        len: u8,
+    },
+    /// `text` in Morse code at `wpm` words per minute
+    Morse { text: morse::MorseText, wpm: u8 },
+}
+
+/// How long a blink job runs
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum BlinkLength {
+    /// ms
+    Duration(u64),
+    /// this many periods or times through the pattern
+    Repetitions(u32),
+}
+
+/// When the RGB LED comes on by itself, on top of [`Command::RgbOn`]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[repr(C)]
+pub enum RgbSchedule {
+    Off,
+    On {
+        date_time: DateTime,
+        /// ms
         duration: u64,
+        repeat: recur::Recurrence,
     },
 }
 
@@ -39,7 +207,11 @@ pub enum BlinkerOptions {
 #[repr(C)]
 pub enum DateTime {
     Now,
+    /// ms since the unix epoch
     Utc(u64),
+    /// this many seconds after the device receives it, works without the
+    /// time being set
+    After { seconds: u64 },
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -50,6 +222,74 @@ pub enum Ack {
     NotOk,
 }
 
+/// Something the device reports without being asked
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum Event {
+    /// Device (re)started and has lost its time reference
+    Boot,
+    /// A blink job ran to the end of its duration
+    BlinkFinished(JobId),
+    /// The button on GPIO9 was pressed
+    ButtonPressed,
+}
+
+/// Everything the device sends to the host
+///
+/// Events carry their own sequence number and are repeated until the host
+/// answers with [`Command::AckEvent`].
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[repr(C)]
+/* no boxing without an allocator, the buffers are sized for the job list */
+#[allow(clippy::large_enum_variant)]
+pub enum DeviceMessage {
+    Ack(Ack),
+    Event { seq: EventSeq, event: Event },
+    /// Answer to [`Command::SyncTime`], `t1` is passed back and `t2`/`t3` are
+    /// the device clock in ms when the command arrived and when this was sent
+    TimeSync { t1: u64, t2: u64, t3: u64 },
+    Drift(Drift),
+    /// The blink was accepted as this job
+    BlinkScheduled(JobId),
+    BlinkJobs([Option<BlinkJob>; MAX_BLINK_JOBS]),
+    NightMode(night::NightStatus),
+}
+
+/// Time corrections up to `max_offset_ms` are spread over `window_ms`
+/// instead of making the clock jump, a window of 0 turns this off
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Slew {
+    pub window_ms: u64,
+    pub max_offset_ms: u64,
+}
+
+/// Rate correction the device applies to its clock
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Drift {
+    /// parts per million, positive if the device clock runs slow
+    pub ppm: i32,
+    /// how many times the time was set since the estimate started
+    pub estimates: u32,
+}
+
+/// A blink waiting to start or in progress
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct BlinkJob {
+    pub id: JobId,
+    /// device clock ms of the current or next run, since boot if the time
+    /// wasn't set when it was scheduled
+    pub start: u64,
+    pub spec: BlinkSpec,
+    /// ms, repetitions are converted on the way in
+    pub duration: u64,
+    pub repeat: recur::Recurrence,
+    pub target: BlinkTarget,
+    pub shape: pwm::BlinkShape,
+}
+
 pub const CKSUM: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
 
 /// Serialize T into cobs encoded out_buf with crc
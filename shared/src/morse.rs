@@ -0,0 +1,263 @@
+//! Morse code for the status LED
+//!
+//! Text is turned into on/off times with the usual proportions: a dot is one
+//! unit, a dash three, the gaps are one unit within a character, three
+//! between characters and seven between words. At `wpm` words per minute a
+//! unit is `1200 / wpm` ms, going by the word PARIS. A message ends with a
+//! word gap, so repeating it keeps the words apart.
+
+use crate::blink::Step;
+use serde_derive::{Deserialize, Serialize};
+
+/// Longest message a [`MorseText`] holds
+pub const MAX_MORSE_LEN: usize = 32;
+
+const DOT: u64 = 1;
+const DASH: u64 = 3;
+const SYMBOL_GAP: u64 = 1;
+const LETTER_GAP: u64 = 3;
+const WORD_GAP: u64 = 7;
+
+/// Message of up to [`MAX_MORSE_LEN`] characters there is Morse code for,
+/// kept in upper case
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct MorseText {
+    chars: [u8; MAX_MORSE_LEN],
+    len: u8,
+}
+
+impl MorseText {
+    /// `None` if `text` is too long, blank or has characters Morse has no
+    /// code for
+    pub fn new(text: &str) -> Option<Self> {
+        let mut chars = [0; MAX_MORSE_LEN];
+        chars
+            .get_mut(..text.len())?
+            .copy_from_slice(text.as_bytes());
+        chars.make_ascii_uppercase();
+        let text = MorseText {
+            chars,
+            len: text.len() as u8,
+        };
+        text.is_valid().then_some(text)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.chars[..(self.len as usize).min(MAX_MORSE_LEN)]
+    }
+
+    /// Something there is a code for, checked again on the device since it
+    /// comes off the wire
+    pub fn is_valid(&self) -> bool {
+        let text = self.as_bytes();
+        text.len() == self.len as usize
+            && text.iter().any(|&c| c != b' ')
+            && text.iter().all(|&c| c == b' ' || code(c).is_some())
+    }
+
+    /// The message as (on, units) pairs, starting with on
+    pub fn elements(&self) -> Elements<'_> {
+        Elements {
+            text: self.as_bytes(),
+            pos: 0,
+            code: &[],
+            sym: 0,
+            gap: None,
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Length of the message in units, the closing word gap included
+    pub fn units(&self) -> u64 {
+        self.elements().map(|(_, units)| units).sum()
+    }
+
+    /// Whether the LED is on `elapsed` ms into the message sent at `wpm`,
+    /// and for how much longer it stays that way
+    pub fn level_at(&self, wpm: u8, elapsed: u64) -> (bool, u64) {
+        let step = self.step_at(wpm, elapsed);
+        (step.on, step.left_ms)
+    }
+
+    /// The dot, dash or gap `elapsed` ms into the message sent at `wpm`
+    pub fn step_at(&self, wpm: u8, elapsed: u64) -> Step {
+        let unit = unit_ms(wpm);
+        let cycle = (self.units() * unit).max(1);
+        let phase = elapsed % cycle;
+
+        let mut end = 0;
+        for (on, units) in self.elements() {
+            let start = end;
+            end += units * unit;
+            if phase < end {
+                return Step {
+                    on,
+                    into_ms: phase - start,
+                    left_ms: end - phase,
+                };
+            }
+        }
+        Step {
+            on: false,
+            into_ms: phase - end,
+            left_ms: cycle - phase,
+        }
+    }
+}
+
+/// Length of a dot at `wpm` words per minute
+pub fn unit_ms(wpm: u8) -> u64 {
+    1200 / wpm.max(1) as u64
+}
+
+/// The code for `c` as dots and dashes, `None` if there is none
+pub fn code(c: u8) -> Option<&'static [u8]> {
+    let code: &[u8] = match c.to_ascii_uppercase() {
+        b'A' => b".-",
+        b'B' => b"-...",
+        b'C' => b"-.-.",
+        b'D' => b"-..",
+        b'E' => b".",
+        b'F' => b"..-.",
+        b'G' => b"--.",
+        b'H' => b"....",
+        b'I' => b"..",
+        b'J' => b".---",
+        b'K' => b"-.-",
+        b'L' => b".-..",
+        b'M' => b"--",
+        b'N' => b"-.",
+        b'O' => b"---",
+        b'P' => b".--.",
+        b'Q' => b"--.-",
+        b'R' => b".-.",
+        b'S' => b"...",
+        b'T' => b"-",
+        b'U' => b"..-",
+        b'V' => b"...-",
+        b'W' => b".--",
+        b'X' => b"-..-",
+        b'Y' => b"-.--",
+        b'Z' => b"--..",
+        b'0' => b"-----",
+        b'1' => b".----",
+        b'2' => b"..---",
+        b'3' => b"...--",
+        b'4' => b"....-",
+        b'5' => b".....",
+        b'6' => b"-....",
+        b'7' => b"--...",
+        b'8' => b"---..",
+        b'9' => b"----.",
+        b'.' => b".-.-.-",
+        b',' => b"--..--",
+        b'?' => b"..--..",
+        b'/' => b"-..-.",
+        b'=' => b"-...-",
+        b'-' => b"-....-",
+        b'+' => b".-.-.",
+        b'@' => b".--.-.",
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Iterator over the on/off times of a message, see [`MorseText::elements`]
+pub struct Elements<'a> {
+    text: &'a [u8],
+    pos: usize,
+    /// code of the current character and how far into it we are
+    code: &'static [u8],
+    sym: usize,
+    /// gap to send before the next symbol
+    gap: Option<u64>,
+    started: bool,
+    done: bool,
+}
+
+impl Iterator for Elements<'_> {
+    type Item = (bool, u64);
+
+    fn next(&mut self) -> Option<(bool, u64)> {
+        if let Some(gap) = self.gap.take() {
+            return Some((false, gap));
+        }
+        loop {
+            if let Some(&symbol) = self.code.get(self.sym) {
+                self.sym += 1;
+                if self.sym < self.code.len() {
+                    self.gap = Some(SYMBOL_GAP);
+                }
+                return Some((true, if symbol == b'.' { DOT } else { DASH }));
+            }
+            if self.done {
+                return None;
+            }
+
+            /* on to the next character */
+            let mut word_break = false;
+            while self.text.get(self.pos) == Some(&b' ') {
+                word_break = true;
+                self.pos += 1;
+            }
+            let Some(&c) = self.text.get(self.pos) else {
+                self.done = true;
+                return self.started.then_some((false, WORD_GAP));
+            };
+            self.pos += 1;
+            self.code = code(c).unwrap_or(&[]);
+            self.sym = 0;
+
+            if self.started {
+                return Some((false, if word_break { WORD_GAP } else { LETTER_GAP }));
+            }
+            self.started = true;
+        }
+    }
+}
+
+#[test]
+fn morse_elements() {
+    let text = MorseText::new("et a").unwrap();
+    assert_eq!(text.as_bytes(), b"ET A");
+    let elements: Vec<(bool, u64)> = text.elements().collect();
+    assert_eq!(
+        elements,
+        [
+            (true, 1),  /* E */
+            (false, 3), /* letter gap */
+            (true, 3),  /* T */
+            (false, 7), /* word gap */
+            (true, 1),  /* A */
+            (false, 1),
+            (true, 3),
+            (false, 7), /* end of message */
+        ]
+    );
+    assert_eq!(text.units(), 26);
+
+    /* PARIS is 50 units, which is where the words per minute come from */
+    assert_eq!(MorseText::new("PARIS").unwrap().units(), 50);
+
+    assert!(MorseText::new("").is_none());
+    assert!(MorseText::new("  ").is_none());
+    assert!(MorseText::new("50%").is_none());
+    assert!(MorseText::new(&"E".repeat(MAX_MORSE_LEN + 1)).is_none());
+    assert!(MorseText::new(&"E".repeat(MAX_MORSE_LEN)).is_some());
+}
+
+#[test]
+fn morse_timing() {
+    /* 20 wpm is 60 ms a unit */
+    let sos = MorseText::new("SOS").unwrap();
+    assert_eq!(unit_ms(20), 60);
+    assert_eq!(sos.level_at(20, 0), (true, 60));
+    assert_eq!(sos.level_at(20, 90), (false, 30));
+    /* S is 5 units, then 3 of gap before the first dash of O */
+    assert_eq!(sos.level_at(20, 8 * 60), (true, 180));
+    /* the message repeats after 34 units */
+    assert_eq!(sos.units(), 34);
+    assert_eq!(sos.level_at(20, 34 * 60 + 10), (true, 50));
+}
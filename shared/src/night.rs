@@ -0,0 +1,311 @@
+//! Night mode
+//!
+//! A window of local time, which may run over midnight, during which both
+//! LEDs are dimmed to a maximum brightness or kept off. It goes by the time
+//! of day, so it never applies before the time has been set.
+//!
+//! The host can force night or day for a while. The override holds until the
+//! window next starts or ends, after which the window is in charge again, or
+//! until it is set back to [`NightOverride::Auto`]. Before the time has been
+//! set there is no next start or end and it holds until changed.
+
+use crate::pwm::MAX_DUTY;
+use crate::tz::TimeZone;
+use serde_derive::{Deserialize, Serialize};
+
+const MS_PER_DAY: u32 = 24 * 60 * 60 * 1000;
+
+/// What happens at night
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum NightAction {
+    /// the RGB LED is no brighter than this, the blink LED no brighter than
+    /// the same share of its full duty
+    Dim(u8),
+    /// the RGB LED and the blink LED stay off, blink jobs run unseen
+    Off,
+}
+
+/// Night from `start_ms` to `end_ms`, ms since local midnight
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct NightWindow {
+    pub start_ms: u32,
+    pub end_ms: u32,
+    pub action: NightAction,
+}
+
+impl NightWindow {
+    /// Checked again on the device since it comes off the wire
+    pub fn is_valid(&self) -> bool {
+        self.start_ms < MS_PER_DAY && self.end_ms < MS_PER_DAY && self.start_ms != self.end_ms
+    }
+
+    /// Whether `ms_of_day` is at night
+    pub fn contains(&self, ms_of_day: u32) -> bool {
+        if self.start_ms < self.end_ms {
+            (self.start_ms..self.end_ms).contains(&ms_of_day)
+        } else {
+            ms_of_day >= self.start_ms || ms_of_day < self.end_ms
+        }
+    }
+
+    /// ms from `now` until the window next starts or ends, a whole day if
+    /// that is right now
+    fn until_edge(&self, now: u64, tz: &TimeZone) -> u64 {
+        let ms_of_day = (tz.local_ms(now) % MS_PER_DAY as u64) as u32;
+        let until = |edge: u32| match (edge + MS_PER_DAY - ms_of_day) % MS_PER_DAY {
+            0 => MS_PER_DAY,
+            ms => ms,
+        };
+        until(self.start_ms).min(until(self.end_ms)) as u64
+    }
+}
+
+/// Night or day regardless of the window
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum NightOverride {
+    /// the window decides
+    Auto,
+    Night,
+    Day,
+}
+
+/// Answer to [`crate::Command::GetNightMode`]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct NightStatus {
+    pub window: Option<NightWindow>,
+    /// `Auto` once an override has run out
+    pub forced: NightOverride,
+    /// whether it is night right now
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NightMode {
+    window: Option<NightWindow>,
+    forced: NightOverride,
+    /// device clock ms the override runs out at
+    forced_until: u64,
+}
+
+impl NightMode {
+    /// No window, never night
+    pub const fn new() -> Self {
+        NightMode {
+            window: None,
+            forced: NightOverride::Auto,
+            forced_until: 0,
+        }
+    }
+
+    /// A new window, or none to turn night mode off, drops any override
+    pub fn set_window(&mut self, window: Option<NightWindow>) {
+        self.window = window;
+        self.forced = NightOverride::Auto;
+    }
+
+    /// Force night or day at `now` until the window next starts or ends
+    pub fn force(&mut self, forced: NightOverride, now: u64, tz: &TimeZone, time_set: bool) {
+        self.forced = forced;
+        self.forced_until = match self.window {
+            Some(window) if time_set => now + window.until_edge(now, tz),
+            _ => u64::MAX,
+        };
+    }
+
+    fn forced_at(&self, now: u64) -> NightOverride {
+        if now < self.forced_until {
+            self.forced
+        } else {
+            NightOverride::Auto
+        }
+    }
+
+    /// What to do at `now`, `None` during the day
+    pub fn action(&self, now: u64, tz: &TimeZone, time_set: bool) -> Option<NightAction> {
+        let window = self.window?;
+        let night = match self.forced_at(now) {
+            NightOverride::Night => true,
+            NightOverride::Day => false,
+            NightOverride::Auto => {
+                time_set && window.contains((tz.local_ms(now) % MS_PER_DAY as u64) as u32)
+            }
+        };
+        night.then_some(window.action)
+    }
+
+    /// The RGB LED's `brightness` at `now`, 0 when it has to be off
+    pub fn brightness(&self, brightness: u8, now: u64, tz: &TimeZone, time_set: bool) -> u8 {
+        match self.action(now, tz, time_set) {
+            None => brightness,
+            Some(NightAction::Dim(max)) => brightness.min(max),
+            Some(NightAction::Off) => 0,
+        }
+    }
+
+    /// Whether the blink LED has to be off at `now`
+    pub fn led_off(&self, now: u64, tz: &TimeZone, time_set: bool) -> bool {
+        self.action(now, tz, time_set) == Some(NightAction::Off)
+    }
+
+    /// The blink LED's `duty` at `now`, dimmed like the RGB LED but in
+    /// percent rather than out of 255
+    pub fn led_duty(&self, duty: u8, now: u64, tz: &TimeZone, time_set: bool) -> u8 {
+        match self.action(now, tz, time_set) {
+            None => duty,
+            /* rounded up, a dim night doesn't turn the LED off */
+            Some(NightAction::Dim(max)) => {
+                duty.min((max as u16 * MAX_DUTY as u16).div_ceil(u8::MAX as u16) as u8)
+            }
+            Some(NightAction::Off) => 0,
+        }
+    }
+
+    /// ms from `now` until night may start or end, for the LEDs to change
+    /// right then. `None` if it can't before a command changes it.
+    pub fn next_change(&self, now: u64, tz: &TimeZone, time_set: bool) -> Option<u64> {
+        let window = self.window?;
+        let forced = (now < self.forced_until && self.forced_until != u64::MAX)
+            .then(|| self.forced_until - now);
+        let edge = time_set.then(|| window.until_edge(now, tz));
+        edge.into_iter().chain(forced).min()
+    }
+
+    pub fn status(&self, now: u64, tz: &TimeZone, time_set: bool) -> NightStatus {
+        NightStatus {
+            window: self.window,
+            forced: self.forced_at(now),
+            active: self.action(now, tz, time_set).is_some(),
+        }
+    }
+}
+
+impl Default for NightMode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn night_window() {
+    const HOUR: u64 = 3_600_000;
+    let day = 1_700_000_000_000 / (MS_PER_DAY as u64) * MS_PER_DAY as u64;
+    let tz = TimeZone::UTC;
+    let mut night = NightMode::new();
+    assert_eq!(night.brightness(20, day, &tz, true), 20);
+
+    /* 22:00 to 07:00 */
+    let window = NightWindow {
+        start_ms: 22 * HOUR as u32,
+        end_ms: 7 * HOUR as u32,
+        action: NightAction::Dim(5),
+    };
+    assert!(window.is_valid());
+    night.set_window(Some(window));
+    assert_eq!(night.brightness(20, day + 23 * HOUR, &tz, true), 5);
+    assert_eq!(night.brightness(20, day + 6 * HOUR, &tz, true), 5);
+    assert_eq!(night.brightness(20, day + 7 * HOUR, &tz, true), 20);
+    assert_eq!(night.brightness(3, day + 23 * HOUR, &tz, true), 3);
+    assert!(!night.led_off(day + 23 * HOUR, &tz, true));
+    /* no time of day, no night */
+    assert_eq!(night.brightness(20, 23 * HOUR, &tz, false), 20);
+
+    /* an hour ahead the night starts an hour earlier in UTC */
+    let cet = TimeZone {
+        offset_minutes: 60,
+        dst: crate::tz::DstRule::None,
+    };
+    assert_eq!(night.brightness(20, day + 21 * HOUR, &cet, true), 5);
+
+    night.set_window(Some(NightWindow {
+        action: NightAction::Off,
+        ..window
+    }));
+    assert!(night.led_off(day + 23 * HOUR, &tz, true));
+    assert_eq!(night.brightness(20, day + 23 * HOUR, &tz, true), 0);
+
+    assert!(!NightWindow {
+        start_ms: 0,
+        end_ms: 0,
+        action: NightAction::Off
+    }
+    .is_valid());
+}
+
+#[test]
+fn night_overrides() {
+    const HOUR: u64 = 3_600_000;
+    let day = 1_700_000_000_000 / (MS_PER_DAY as u64) * MS_PER_DAY as u64;
+    let tz = TimeZone::UTC;
+    let mut night = NightMode::new();
+    night.set_window(Some(NightWindow {
+        start_ms: 22 * HOUR as u32,
+        end_ms: 7 * HOUR as u32,
+        action: NightAction::Off,
+    }));
+
+    /* lights on at 23:00 until the night is over at 07:00 */
+    night.force(NightOverride::Day, day + 23 * HOUR, &tz, true);
+    let status = night.status(day + 30 * HOUR, &tz, true);
+    assert_eq!(status.forced, NightOverride::Day);
+    assert!(!status.active);
+    let status = night.status(day + 31 * HOUR, &tz, true);
+    assert_eq!(status.forced, NightOverride::Auto);
+    assert!(!status.active);
+
+    /* an early night at 20:00 lasts until it would have ended anyway */
+    night.force(NightOverride::Night, day + 20 * HOUR, &tz, true);
+    assert!(night.led_off(day + 21 * HOUR, &tz, true));
+    assert!(night.led_off(day + 23 * HOUR, &tz, true));
+    assert!(!night.led_off(day + 31 * HOUR, &tz, true));
+
+    /* without the time the override stays until it is changed */
+    night.force(NightOverride::Night, 1_000, &tz, false);
+    assert!(night.led_off(u64::MAX - 1, &tz, false));
+    night.force(NightOverride::Auto, 1_000, &tz, false);
+    assert!(!night.led_off(1_000, &tz, false));
+
+    /* a new window starts without one */
+    night.force(NightOverride::Night, 1_000, &tz, false);
+    night.set_window(None);
+    assert_eq!(night.status(1_000, &tz, false).forced, NightOverride::Auto);
+}
+
+#[test]
+fn night_led_and_edges() {
+    const HOUR: u64 = 3_600_000;
+    let day = 1_700_000_000_000 / (MS_PER_DAY as u64) * MS_PER_DAY as u64;
+    let tz = TimeZone::UTC;
+    let mut night = NightMode::new();
+    assert_eq!(night.next_change(day, &tz, true), None);
+
+    /* 22:00 to 07:00, at a fifth of full brightness */
+    night.set_window(Some(NightWindow {
+        start_ms: 22 * HOUR as u32,
+        end_ms: 7 * HOUR as u32,
+        action: NightAction::Dim(51),
+    }));
+    assert_eq!(night.led_duty(MAX_DUTY, day + 21 * HOUR, &tz, true), MAX_DUTY);
+    assert_eq!(night.led_duty(MAX_DUTY, day + 23 * HOUR, &tz, true), 20);
+    assert_eq!(night.led_duty(10, day + 23 * HOUR, &tz, true), 10);
+    night.set_window(Some(NightWindow {
+        start_ms: 22 * HOUR as u32,
+        end_ms: 7 * HOUR as u32,
+        action: NightAction::Dim(1),
+    }));
+    assert_eq!(night.led_duty(MAX_DUTY, day + 23 * HOUR, &tz, true), 1);
+
+    /* woken up when the night starts and when it ends */
+    assert_eq!(night.next_change(day + 21 * HOUR, &tz, true), Some(HOUR));
+    assert_eq!(night.next_change(day + 23 * HOUR, &tz, true), Some(8 * HOUR));
+    assert_eq!(night.next_change(day + 22 * HOUR, &tz, true), Some(9 * HOUR));
+    assert_eq!(night.next_change(23 * HOUR, &tz, false), None);
+
+    /* an override without the time runs until it is changed */
+    night.force(NightOverride::Night, 1_000, &tz, false);
+    assert_eq!(night.led_duty(MAX_DUTY, 2_000, &tz, false), 1);
+    assert_eq!(night.next_change(2_000, &tz, false), None);
+}
@@ -0,0 +1,139 @@
+//! Duty cycle of the PWM driven blink LED
+//!
+//! The firmware drives the LED on GPIO7 from the LEDC peripheral, this works
+//! out the duty cycle it should have. A [`BlinkShape`] says what each on step
+//! of a blink looks like, straight on and off or fading in and out. Fades go
+//! in [`FADE_STEP_MS`] steps on a square law, so they look even to the eye
+//! rather than rushing up and crawling down. Like the blink schedule it goes
+//! by how far into the job it is, so a late interrupt never stretches a fade.
+
+use crate::blink::Step;
+use crate::BlinkJob;
+use serde_derive::{Deserialize, Serialize};
+
+/// Duty cycles are in percent, the way LEDC takes them
+pub const MAX_DUTY: u8 = 100;
+/// ms between duty changes while fading
+pub const FADE_STEP_MS: u64 = 20;
+
+/// How an on step of a blink looks on the blink LED, the RGB LED always
+/// blinks square
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum BlinkShape {
+    /// full brightness for the whole step
+    Square,
+    /// up from dark over the first `in_ms` of the step and back down over the
+    /// last `out_ms`, a short step doesn't get all the way up
+    Fade { in_ms: u16, out_ms: u16 },
+}
+
+impl BlinkShape {
+    /// Duty cycle during `step` with `brightness` percent for fully on, and
+    /// for how much longer it holds
+    pub fn duty(&self, step: &Step, brightness: u8) -> (u8, u64) {
+        let brightness = brightness.min(MAX_DUTY) as u64;
+        let left = step.left_ms.max(1);
+        if !step.on {
+            return (0, left);
+        }
+        let BlinkShape::Fade { in_ms, out_ms } = *self else {
+            return (brightness as u8, left);
+        };
+
+        /* how far up the ramps are, in thousandths */
+        let ramp = |ms: u64, len: u16| match len {
+            0 => 1000,
+            len => (ms * 1000 / len as u64).min(1000),
+        };
+        let level = ramp(step.into_ms, in_ms).min(ramp(step.left_ms, out_ms));
+        let duty = brightness * level * level / 1_000_000;
+        let hold = match left.saturating_sub(out_ms as u64) {
+            flat if step.into_ms >= in_ms as u64 && flat > 0 => flat,
+            _ => FADE_STEP_MS,
+        };
+        (duty as u8, hold.min(left))
+    }
+}
+
+/// Duty cycle of `job` at `now` while it is the one blinking, and for how
+/// much longer it holds, up to the end of the job
+pub fn job_duty(job: &BlinkJob, now: u64, brightness: u8) -> (u8, u64) {
+    let elapsed = now.saturating_sub(job.start);
+    let (duty, for_ms) = job.shape.duty(&job.spec.step_at(elapsed), brightness);
+    let end = job.start.saturating_add(job.duration);
+    (duty, for_ms.min(end.saturating_sub(now)).max(1))
+}
+
+#[test]
+fn square_duty() {
+    let on = Step {
+        on: true,
+        into_ms: 100,
+        left_ms: 400,
+    };
+    assert_eq!(BlinkShape::Square.duty(&on, 60), (60, 400));
+    assert_eq!(BlinkShape::Square.duty(&on, 200), (MAX_DUTY, 400));
+    let off = Step { on: false, ..on };
+    assert_eq!(BlinkShape::Square.duty(&off, 60), (0, 400));
+}
+
+#[test]
+fn fade_duty() {
+    let fade = BlinkShape::Fade {
+        in_ms: 200,
+        out_ms: 100,
+    };
+    /* a 1 s on step */
+    let at = |into_ms: u64| {
+        fade.duty(
+            &Step {
+                on: true,
+                into_ms,
+                left_ms: 1_000 - into_ms,
+            },
+            100,
+        )
+    };
+    assert_eq!(at(0), (0, FADE_STEP_MS));
+    /* half way up is a quarter as bright */
+    assert_eq!(at(100), (25, FADE_STEP_MS));
+    /* full until the fade out starts */
+    assert_eq!(at(200), (100, 700));
+    assert_eq!(at(900), (100, FADE_STEP_MS));
+    assert_eq!(at(950), (25, FADE_STEP_MS));
+    assert_eq!(at(995), (0, 5));
+
+    /* a step shorter than both ramps peaks early and dim */
+    let short = Step {
+        on: true,
+        into_ms: 60,
+        left_ms: 40,
+    };
+    assert_eq!(fade.duty(&short, 100), (9, FADE_STEP_MS));
+}
+
+#[test]
+fn job_duty_to_the_end() {
+    use crate::recur::Recurrence;
+    use crate::{BlinkSpec, BlinkTarget};
+
+    let job = BlinkJob {
+        id: 0,
+        start: 1_000,
+        spec: BlinkSpec::hz(1.0),
+        duration: 1_300,
+        repeat: Recurrence::Once,
+        target: BlinkTarget::Led,
+        shape: BlinkShape::Fade {
+            in_ms: 100,
+            out_ms: 100,
+        },
+    };
+    assert_eq!(job_duty(&job, 1_000, 80), (0, FADE_STEP_MS));
+    assert_eq!(job_duty(&job, 1_200, 80), (80, 200));
+    assert_eq!(job_duty(&job, 1_700, 80), (0, 300));
+    /* the job ends part way through the second on step, before it fades */
+    assert_eq!(job_duty(&job, 2_200, 80), (80, 100));
+    assert_eq!(job_duty(&job, 2_290, 80), (80, 10));
+}
@@ -0,0 +1,230 @@
+//! Recurrence rules for schedules the device keeps running on its own
+//!
+//! A schedule starts at some time and runs for a while. With a rule other
+//! than [`Recurrence::Once`] the device works out the next start itself when
+//! a run is over, so the host doesn't have to be around. Daily and weekly
+//! rules go by local time in the device's zone, so they need the time set.
+
+use crate::tz::{weekday, TimeZone};
+use crate::DateTime;
+use serde_derive::{Deserialize, Serialize};
+
+const MS_PER_MINUTE: u64 = 60 * 1000;
+const MS_PER_DAY: u64 = 24 * 60 * MS_PER_MINUTE;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum Recurrence {
+    Once,
+    /// every day at `at_ms` after local midnight
+    Daily {
+        at_ms: u32,
+    },
+    /// on the days set in `days`, bit 0 is Sunday, at `at_ms` after local
+    /// midnight
+    Weekly {
+        days: u8,
+        at_ms: u32,
+    },
+    /// every this many minutes from the first start on
+    Every {
+        minutes: u32,
+    },
+}
+
+impl Recurrence {
+    /// Goes by the wall clock, meaningless until the time is set
+    pub fn needs_time(&self) -> bool {
+        matches!(self, Recurrence::Daily { .. } | Recurrence::Weekly { .. })
+    }
+
+    /// First start at or after `t`, `None` if the rule never fires
+    pub fn first(&self, t: u64, tz: &TimeZone) -> Option<u64> {
+        match *self {
+            Recurrence::Once => Some(t),
+            Recurrence::Every { minutes: 0 } => None,
+            Recurrence::Every { .. } => Some(t),
+            Recurrence::Daily { at_ms } => self.first_on_days(0x7f, at_ms, t, tz),
+            Recurrence::Weekly { days, at_ms } => self.first_on_days(days, at_ms, t, tz),
+        }
+    }
+
+    /// Start of the next run after the one at `start`, skipping any that
+    /// would have started by `now`
+    pub fn next(&self, start: u64, now: u64, tz: &TimeZone) -> Option<u64> {
+        match *self {
+            Recurrence::Once | Recurrence::Every { minutes: 0 } => None,
+            Recurrence::Every { minutes } => {
+                let interval = minutes as u64 * MS_PER_MINUTE;
+                let runs = now.saturating_sub(start) / interval + 1;
+                Some(start.saturating_add(runs.saturating_mul(interval)))
+            }
+            _ => self.first(start.max(now) + 1, tz),
+        }
+    }
+
+    fn first_on_days(&self, days: u8, at_ms: u32, t: u64, tz: &TimeZone) -> Option<u64> {
+        if days & 0x7f == 0 || at_ms as u64 >= MS_PER_DAY {
+            return None;
+        }
+        let today = tz.local_ms(t) / MS_PER_DAY;
+        /* today's may be over, a week on is the same weekday again */
+        (today..=today + 7)
+            .filter(|&day| days & 1 << weekday(day as i64) != 0)
+            .map(|day| tz.utc_ms(day * MS_PER_DAY + at_ms as u64))
+            .find(|&start| start >= t)
+    }
+}
+
+/// A schedule that only ever has one run going, like the RGB LED's
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Runs {
+    /// device clock ms of the current or next run
+    pub start: u64,
+    /// ms
+    pub duration: u64,
+    pub repeat: Recurrence,
+}
+
+impl Runs {
+    /// Move past the runs that are over by `now`, false once there are no
+    /// more to come
+    pub fn advance(&mut self, now: u64, tz: &TimeZone) -> bool {
+        while now >= self.start.saturating_add(self.duration) {
+            match self.repeat.next(self.start, now, tz) {
+                Some(start) => self.start = start,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    pub fn active(&self, now: u64) -> bool {
+        (self.start..self.start.saturating_add(self.duration)).contains(&now)
+    }
+}
+
+/// Device clock ms of the first run of a schedule starting at `date_time`,
+/// `None` if it never runs or needs the time and it has not been set
+pub fn first_start(
+    date_time: DateTime,
+    repeat: Recurrence,
+    now: u64,
+    time_set: bool,
+    tz: &TimeZone,
+) -> Option<u64> {
+    if !time_set && repeat.needs_time() {
+        return None;
+    }
+    let start = match date_time {
+        DateTime::Now => now,
+        DateTime::After { seconds } => now.saturating_add(seconds.saturating_mul(1000)),
+        DateTime::Utc(t) if time_set => t,
+        DateTime::Utc(_) => return None,
+    };
+    repeat.first(start, tz)
+}
+
+#[test]
+fn recur_every() {
+    let every = Recurrence::Every { minutes: 10 };
+    let tz = TimeZone::UTC;
+    assert_eq!(every.first(5_000, &tz), Some(5_000));
+    assert_eq!(every.next(5_000, 5_000, &tz), Some(605_000));
+    /* the host was gone for a while, the missed runs are skipped */
+    assert_eq!(every.next(5_000, 3_000_000, &tz), Some(3_005_000));
+    assert_eq!(Recurrence::Once.next(5_000, 5_000, &tz), None);
+    assert_eq!(Recurrence::Every { minutes: 0 }.first(5_000, &tz), None);
+}
+
+#[test]
+fn recur_daily_and_weekly_in_local_time() {
+    use crate::tz::{days_from_civil, DstRule};
+
+    let at = |y, m, d, h: u64, min: u64| {
+        days_from_civil(y, m, d) as u64 * MS_PER_DAY + (h * 60 + min) * MS_PER_MINUTE
+    };
+    let cet = TimeZone {
+        offset_minutes: 60,
+        dst: DstRule::Eu,
+    };
+    let seven_thirty = (7 * 60 + 30) as u32 * MS_PER_MINUTE as u32;
+
+    /* 07:30 local is 06:30 UTC in winter, 05:30 once the clocks went forward */
+    let daily = Recurrence::Daily {
+        at_ms: seven_thirty,
+    };
+    assert!(daily.needs_time());
+    let first = daily.first(at(2024, 3, 30, 12, 0), &cet).unwrap();
+    assert_eq!(first, at(2024, 3, 31, 5, 30));
+    let next = daily.next(first, first + 60_000, &cet).unwrap();
+    assert_eq!(next, at(2024, 4, 1, 5, 30));
+    assert_eq!(daily.first(next, &cet), Some(next));
+
+    /* Mondays and Fridays, 2024-04-01 is a Monday */
+    let weekly = Recurrence::Weekly {
+        days: 1 << 1 | 1 << 5,
+        at_ms: seven_thirty,
+    };
+    let monday = weekly.first(at(2024, 3, 30, 12, 0), &cet).unwrap();
+    assert_eq!(monday, at(2024, 4, 1, 5, 30));
+    let friday = weekly.next(monday, monday, &cet).unwrap();
+    assert_eq!(friday, at(2024, 4, 5, 5, 30));
+    assert_eq!(
+        weekly.next(friday, friday, &cet),
+        Some(at(2024, 4, 8, 5, 30))
+    );
+
+    let never = Recurrence::Weekly {
+        days: 0,
+        at_ms: seven_thirty,
+    };
+    assert_eq!(never.first(monday, &cet), None);
+}
+
+#[test]
+fn recur_first_start() {
+    let tz = TimeZone::UTC;
+    let once = Recurrence::Once;
+    assert_eq!(first_start(DateTime::Now, once, 500, false, &tz), Some(500));
+    assert_eq!(
+        first_start(DateTime::After { seconds: 2 }, once, 500, false, &tz),
+        Some(2_500)
+    );
+    assert_eq!(
+        first_start(DateTime::Utc(9_000), once, 500, false, &tz),
+        None
+    );
+    assert_eq!(
+        first_start(DateTime::Utc(9_000), once, 500, true, &tz),
+        Some(9_000)
+    );
+
+    let daily = Recurrence::Daily { at_ms: 1_000 };
+    assert_eq!(first_start(DateTime::Now, daily, 500, false, &tz), None);
+    assert_eq!(
+        first_start(DateTime::Now, daily, MS_PER_DAY + 500, true, &tz),
+        Some(MS_PER_DAY + 1_000)
+    );
+}
+
+#[test]
+fn recur_runs() {
+    let tz = TimeZone::UTC;
+    let mut runs = Runs {
+        start: 1_000,
+        duration: 10_000,
+        repeat: Recurrence::Every { minutes: 1 },
+    };
+    assert!(runs.advance(500, &tz) && !runs.active(500));
+    assert!(runs.advance(1_000, &tz) && runs.active(1_000));
+    assert!(runs.advance(11_000, &tz) && !runs.active(11_000));
+    assert_eq!(runs.start, 61_000);
+
+    let mut once = Runs {
+        repeat: Recurrence::Once,
+        ..runs
+    };
+    assert!(once.advance(70_000, &tz) && once.active(70_000));
+    assert!(!once.advance(71_000, &tz));
+}
@@ -0,0 +1,577 @@
+//! Colour of the RGB LED
+//!
+//! By default the colour follows the time of day from a palette, which needs
+//! the time to be set. The host can upload its own palette as keyframes with
+//! a way to get from one to the next, pick a fixed colour or have it
+//! animated. Either way the device scales it by the brightness before writing
+//! it out. Animations are also gamma corrected so fades look even, the other
+//! modes show their colours as given.
+
+use crate::sun::{sun_times, Location};
+use crate::tz::TimeZone;
+use serde_derive::{Deserialize, Serialize};
+
+const MS_PER_HOUR: u64 = 60 * 60 * 1000;
+const MS_PER_DAY: u64 = 24 * MS_PER_HOUR;
+
+/// Brightness the LED has until told otherwise
+pub const DEFAULT_BRIGHTNESS: u8 = 20;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color { r: 0, g: 0, b: 0 };
+}
+
+/// The colours of the default palette, also the ones the sun goes through
+pub const MORNING: Color = Color {
+    r: 0xF8,
+    g: 0xF3,
+    b: 0x2B,
+};
+pub const DAY: Color = Color {
+    r: 0x9C,
+    g: 0xFF,
+    b: 0xFA,
+};
+pub const EVENING: Color = Color {
+    r: 0x05,
+    g: 0x3C,
+    b: 0x5E,
+};
+pub const NIGHT: Color = Color {
+    r: 0x31,
+    g: 0x08,
+    b: 0x1F,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum RgbMode {
+    /// colour by the hour of day, local time
+    Palette,
+    /// the colour last given with [`crate::Command::SetRgb`]
+    Fixed,
+    /// the default colours, changing with dawn, sunrise, noon, sunset and
+    /// dusk at the location set with [`crate::Command::SetLocation`]
+    Sun,
+    /// the animation last given with [`crate::Command::Animate`]
+    Animated,
+    /// each pixel of the strip its own colour, see [`crate::strip`]
+    Pixels,
+}
+
+/// How often an animation is redrawn
+pub const ANIMATION_FRAME_MS: u64 = 20;
+/// How often the other modes are
+const STATIC_FRAME_MS: u64 = 1000;
+
+/// Colours that change by themselves, `period_ms` is one full cycle
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum Animation {
+    /// from one colour to the other and back
+    Fade {
+        from: Color,
+        to: Color,
+        period_ms: u32,
+    },
+    /// up from dark to `color` and back down, breathing
+    Pulse { color: Color, period_ms: u32 },
+    /// round the hue circle at full saturation
+    Rainbow { period_ms: u32 },
+}
+
+impl Animation {
+    fn period_ms(&self) -> u32 {
+        match *self {
+            Animation::Fade { period_ms, .. }
+            | Animation::Pulse { period_ms, .. }
+            | Animation::Rainbow { period_ms } => period_ms,
+        }
+    }
+
+    /// A cycle has to be at least a couple of frames long
+    pub fn is_valid(&self) -> bool {
+        self.period_ms() as u64 >= 2 * ANIMATION_FRAME_MS
+    }
+
+    /// Colour `elapsed` ms into the animation
+    pub fn color_at(&self, elapsed: u64) -> Color {
+        let period = (self.period_ms() as u64).max(2);
+        let phase = elapsed % period;
+        /* triangle wave, up over the first half and down over the second */
+        let half = period / 2;
+        let up = if phase < half { phase } else { period - phase };
+        match *self {
+            Animation::Fade { from, to, .. } => from.lerp(to, up, half),
+            Animation::Pulse { color, .. } => Color::BLACK.lerp(color, up, half),
+            Animation::Rainbow { .. } => Hsv {
+                h: (phase * HUE_CIRCLE as u64 / period) as i64,
+                s: 255,
+                v: 255,
+            }
+            .into(),
+        }
+    }
+}
+
+/// What to write to the LED next, see [`RgbSettings::frame`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub color: Color,
+    /// apply gamma correction before writing it out
+    pub gamma: bool,
+    /// when to draw the next one
+    pub next_ms: u64,
+}
+
+/// Maximum number of keyframes in a [`Palette`]
+pub const MAX_KEYFRAMES: usize = 8;
+
+/// Colour the palette reaches at `at_ms` after local midnight
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Keyframe {
+    pub at_ms: u32,
+    pub color: Color,
+}
+
+/// How the palette gets from one keyframe to the next
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum Interpolation {
+    /// hold each colour until the next keyframe
+    Step,
+    /// straight line through RGB
+    Linear,
+    /// the shorter way round the hue circle, keeps colours saturated
+    Hsv,
+}
+
+/// Colours by time of day, the last keyframe carries over past midnight to
+/// the first
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Palette {
+    keys: [Keyframe; MAX_KEYFRAMES],
+    len: u8,
+    pub interpolation: Interpolation,
+}
+
+impl Palette {
+    /// Morning yellow from 3:00, daytime cyan from 9:00, evening blue from
+    /// 15:00 and night purple from 21:00
+    pub const DEFAULT: Palette = {
+        const fn key(hour: u32, color: Color) -> Keyframe {
+            Keyframe {
+                at_ms: hour * MS_PER_HOUR as u32,
+                color,
+            }
+        }
+        let unused = key(0, Color::BLACK);
+        Palette {
+            keys: [
+                key(3, MORNING),
+                key(9, DAY),
+                key(15, EVENING),
+                key(21, NIGHT),
+                unused,
+                unused,
+                unused,
+                unused,
+            ],
+            len: 4,
+            interpolation: Interpolation::Step,
+        }
+    };
+
+    /// `None` unless there are 1 to [`MAX_KEYFRAMES`] keyframes, in order and
+    /// within the day
+    pub fn new(keys: &[Keyframe], interpolation: Interpolation) -> Option<Self> {
+        let mut palette = Palette {
+            keys: [Keyframe {
+                at_ms: 0,
+                color: Color::BLACK,
+            }; MAX_KEYFRAMES],
+            len: keys.len() as u8,
+            interpolation,
+        };
+        palette.keys.get_mut(..keys.len())?.copy_from_slice(keys);
+        palette.is_valid().then_some(palette)
+    }
+
+    pub fn keys(&self) -> &[Keyframe] {
+        &self.keys[..(self.len as usize).min(MAX_KEYFRAMES)]
+    }
+
+    /// Checked again on the device since it comes off the wire
+    pub fn is_valid(&self) -> bool {
+        let keys = self.keys();
+        !keys.is_empty()
+            && keys.len() == self.len as usize
+            && keys.windows(2).all(|w| w[0].at_ms < w[1].at_ms)
+            && keys.iter().all(|k| (k.at_ms as u64) < MS_PER_DAY)
+    }
+
+    /// Colour at local time `local_ms`
+    pub fn color_at(&self, local_ms: u64) -> Color {
+        let keys = self.keys();
+        if keys.is_empty() {
+            return Color::BLACK;
+        }
+        let t = local_ms % MS_PER_DAY;
+        /* before the first keyframe it's still yesterday's last one */
+        let i = keys
+            .iter()
+            .rposition(|k| k.at_ms as u64 <= t)
+            .unwrap_or(keys.len() - 1);
+        let from = keys[i];
+        let to = keys.get(i + 1).unwrap_or(&keys[0]);
+
+        let elapsed = (MS_PER_DAY + t - from.at_ms as u64) % MS_PER_DAY;
+        let span = (MS_PER_DAY + to.at_ms as u64 - from.at_ms as u64) % MS_PER_DAY;
+        /* HSV doesn't quite round trip, keyframes should come out exact */
+        if elapsed == 0 || from.color == to.color {
+            return from.color;
+        }
+        match self.interpolation {
+            Interpolation::Step => from.color,
+            Interpolation::Linear => from.color.lerp(to.color, elapsed, span),
+            Interpolation::Hsv => Hsv::from(from.color)
+                .lerp(Hsv::from(to.color), elapsed, span)
+                .into(),
+        }
+    }
+}
+
+impl Color {
+    /// `num / den` of the way from `self` to `to`
+    fn lerp(self, to: Color, num: u64, den: u64) -> Color {
+        let mix = |a: u8, b: u8| lerp(a as i64, b as i64, num, den) as u8;
+        Color {
+            r: mix(self.r, to.r),
+            g: mix(self.g, to.g),
+            b: mix(self.b, to.b),
+        }
+    }
+}
+
+fn lerp(a: i64, b: i64, num: u64, den: u64) -> i64 {
+    a + (b - a) * num as i64 / den.max(1) as i64
+}
+
+/// Integer HSV, the hue goes round in [`HUE_CIRCLE`] steps
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Hsv {
+    h: i64,
+    s: i64,
+    v: i64,
+}
+
+const HUE_CIRCLE: i64 = 6 * 256;
+
+impl From<Color> for Hsv {
+    fn from(c: Color) -> Self {
+        let (r, g, b) = (c.r as i64, c.g as i64, c.b as i64);
+        let max = r.max(g).max(b);
+        let delta = max - r.min(g).min(b);
+        let h = if delta == 0 {
+            0
+        } else if max == r {
+            256 * (g - b) / delta
+        } else if max == g {
+            512 + 256 * (b - r) / delta
+        } else {
+            1024 + 256 * (r - g) / delta
+        };
+        Hsv {
+            h: h.rem_euclid(HUE_CIRCLE),
+            s: if max == 0 { 0 } else { delta * 255 / max },
+            v: max,
+        }
+    }
+}
+
+impl From<Hsv> for Color {
+    fn from(Hsv { h, s, v }: Hsv) -> Self {
+        let f = h % 256;
+        let p = v * (255 - s) / 255;
+        let q = v * (255 - s * f / 255) / 255;
+        let t = v * (255 - s * (255 - f) / 255) / 255;
+        let (r, g, b) = match h / 256 {
+            0 => (v, t, p),
+            1 => (q, v, p),
+            2 => (p, v, t),
+            3 => (p, q, v),
+            4 => (t, p, v),
+            _ => (v, p, q),
+        };
+        Color {
+            r: r as u8,
+            g: g as u8,
+            b: b as u8,
+        }
+    }
+}
+
+impl Hsv {
+    fn lerp(self, to: Hsv, num: u64, den: u64) -> Hsv {
+        /* grey has no hue of its own, it takes the other one's */
+        let (from_h, to_h) = match (self.s, to.s) {
+            (0, _) => (to.h, to.h),
+            (_, 0) => (self.h, self.h),
+            _ => (self.h, to.h),
+        };
+        let mut diff = to_h - from_h;
+        if diff > HUE_CIRCLE / 2 {
+            diff -= HUE_CIRCLE;
+        } else if diff < -HUE_CIRCLE / 2 {
+            diff += HUE_CIRCLE;
+        }
+        Hsv {
+            h: lerp(from_h, from_h + diff, num, den).rem_euclid(HUE_CIRCLE),
+            s: lerp(self.s, to.s, num, den),
+            v: lerp(self.v, to.v, num, den),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RgbSettings {
+    pub mode: RgbMode,
+    pub palette: Palette,
+    pub color: Color,
+    pub brightness: u8,
+    /// needed for [`RgbMode::Sun`]
+    pub location: Option<Location>,
+    pub animation: Animation,
+}
+
+impl RgbSettings {
+    pub const fn new() -> Self {
+        RgbSettings {
+            mode: RgbMode::Palette,
+            palette: Palette::DEFAULT,
+            color: Color {
+                r: 0xFF,
+                g: 0xFF,
+                b: 0xFF,
+            },
+            brightness: DEFAULT_BRIGHTNESS,
+            location: None,
+            animation: Animation::Rainbow { period_ms: 10_000 },
+        }
+    }
+
+    /// The palette and the sun go by local time, meaningless until the time
+    /// is set
+    pub fn needs_time(&self) -> bool {
+        matches!(self.mode, RgbMode::Palette | RgbMode::Sun)
+    }
+
+    /// Whether switching to `mode` makes sense yet
+    pub fn accepts(&self, mode: RgbMode, time_set: bool) -> bool {
+        match mode {
+            RgbMode::Fixed | RgbMode::Animated | RgbMode::Pixels => true,
+            RgbMode::Palette => time_set,
+            RgbMode::Sun => time_set && self.location.is_some(),
+        }
+    }
+
+    /// Colour to show at `now`, before brightness
+    pub fn color_at(&self, now: u64, tz: &TimeZone) -> Color {
+        let local_ms = tz.local_ms(now);
+        match (self.mode, self.location) {
+            (RgbMode::Fixed, _) => self.color,
+            (RgbMode::Animated, _) => self.animation.color_at(now),
+            /* nothing for the strip as a whole */
+            (RgbMode::Pixels, _) => Color::BLACK,
+            (RgbMode::Sun, Some(location)) => sun_times((local_ms / MS_PER_DAY) as i64, &location)
+                .palette(tz)
+                .color_at(local_ms),
+            (RgbMode::Palette | RgbMode::Sun, _) => self.palette.color_at(local_ms),
+        }
+    }
+
+    /// The frame to show at `now`, animations are redrawn often enough to
+    /// look smooth, the rest once a second to follow the time of day
+    pub fn frame(&self, now: u64, tz: &TimeZone) -> Frame {
+        let animated = self.mode == RgbMode::Animated;
+        Frame {
+            color: self.color_at(now, tz),
+            gamma: animated,
+            next_ms: if animated {
+                ANIMATION_FRAME_MS
+            } else {
+                STATIC_FRAME_MS
+            },
+        }
+    }
+}
+
+impl Default for RgbSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn rgb_modes() {
+    let mut settings = RgbSettings::new();
+    assert!(settings.needs_time());
+    assert_eq!(settings.brightness, DEFAULT_BRIGHTNESS);
+    let color_at = |settings: &RgbSettings, t| settings.color_at(t, &TimeZone::UTC);
+    assert_eq!(color_at(&settings, 2 * MS_PER_HOUR), NIGHT);
+    assert_eq!(color_at(&settings, 26 * MS_PER_HOUR), NIGHT);
+    assert_eq!(color_at(&settings, 22 * MS_PER_HOUR), NIGHT);
+    assert_eq!(color_at(&settings, 9 * MS_PER_HOUR - 1).r, 0xF8);
+    assert_eq!(color_at(&settings, 9 * MS_PER_HOUR).r, 0x9C);
+
+    let orange = Color {
+        r: 0xFF,
+        g: 0x88,
+        b: 0,
+    };
+    settings.mode = RgbMode::Fixed;
+    settings.color = orange;
+    assert!(!settings.needs_time());
+    assert!(settings.accepts(RgbMode::Fixed, false));
+    assert!(!settings.accepts(RgbMode::Palette, false));
+
+    /* the sun needs to know where it is */
+    assert!(!settings.accepts(RgbMode::Sun, true));
+    settings.location = Some(Location {
+        latitude: 51.5074,
+        longitude: -0.1278,
+    });
+    assert!(settings.accepts(RgbMode::Sun, true));
+    assert_eq!(color_at(&settings, 10 * MS_PER_HOUR), orange);
+
+    /* in London it's dark again by 17:00 on the shortest day */
+    settings.mode = RgbMode::Sun;
+    let day = crate::tz::days_from_civil(2024, 12, 21);
+    let midnight = day as u64 * MS_PER_DAY;
+    assert_eq!(color_at(&settings, midnight + 2 * MS_PER_HOUR), NIGHT);
+    assert_eq!(color_at(&settings, midnight + 17 * MS_PER_HOUR), NIGHT);
+    let noon = midnight + 12 * MS_PER_HOUR;
+    let sun = sun_times(day, settings.location.as_ref().unwrap());
+    assert_eq!(
+        color_at(&settings, noon),
+        sun.palette(&TimeZone::UTC).color_at(noon)
+    );
+    assert_ne!(color_at(&settings, noon), NIGHT);
+}
+
+#[test]
+fn palette_interpolation() {
+    let rgb = |r, g, b| Color { r, g, b };
+    let (red, green) = (rgb(255, 0, 0), rgb(0, 255, 0));
+    let keys = [
+        Keyframe {
+            at_ms: 6 * MS_PER_HOUR as u32,
+            color: red,
+        },
+        Keyframe {
+            at_ms: 12 * MS_PER_HOUR as u32,
+            color: green,
+        },
+    ];
+    let at = |h: u64| h * MS_PER_HOUR;
+
+    let step = Palette::new(&keys, Interpolation::Step).unwrap();
+    assert_eq!(step.color_at(at(11)), red);
+    assert_eq!(step.color_at(at(12)), green);
+    assert_eq!(step.color_at(at(3)), green);
+
+    /* halfway between red and green is dark yellow in RGB, bright in HSV */
+    let linear = Palette::new(&keys, Interpolation::Linear).unwrap();
+    assert_eq!(linear.color_at(at(6)), red);
+    assert_eq!(linear.color_at(at(9)), rgb(128, 127, 0));
+    let hsv = Palette::new(&keys, Interpolation::Hsv).unwrap();
+    assert_eq!(hsv.color_at(at(9)), rgb(255, 255, 0));
+    assert_eq!(hsv.color_at(at(12)), green);
+
+    /* back to red overnight, halfway is at 21:00 */
+    assert_eq!(linear.color_at(at(21)), rgb(127, 128, 0));
+    assert_eq!(linear.color_at(at(24)), linear.color_at(at(0)));
+
+    /* the short way round, from red through magenta to blue */
+    let magenta = Palette::new(
+        &[
+            keys[0],
+            Keyframe {
+                color: rgb(0, 0, 255),
+                ..keys[1]
+            },
+        ],
+        Interpolation::Hsv,
+    )
+    .unwrap();
+    assert_eq!(magenta.color_at(at(9)), rgb(255, 0, 255));
+
+    let single = Palette::new(&keys[..1], Interpolation::Linear).unwrap();
+    assert_eq!(single.color_at(at(20)), red);
+
+    assert!(Palette::new(&[], Interpolation::Linear).is_none());
+    assert!(Palette::new(&[keys[1], keys[0]], Interpolation::Linear).is_none());
+    assert!(Palette::new(&[keys[0]; MAX_KEYFRAMES + 1], Interpolation::Linear).is_none());
+    let midnight = Keyframe {
+        at_ms: MS_PER_DAY as u32,
+        color: red,
+    };
+    assert!(Palette::new(&[midnight], Interpolation::Linear).is_none());
+    assert!(Palette::DEFAULT.is_valid());
+}
+
+#[test]
+fn animations() {
+    let rgb = |r, g, b| Color { r, g, b };
+    let (red, blue) = (rgb(255, 0, 0), rgb(0, 0, 255));
+
+    let fade = Animation::Fade {
+        from: red,
+        to: blue,
+        period_ms: 2_000,
+    };
+    assert_eq!(fade.color_at(0), red);
+    assert_eq!(fade.color_at(500), rgb(128, 0, 127));
+    assert_eq!(fade.color_at(1_000), blue);
+    assert_eq!(fade.color_at(1_500), fade.color_at(500));
+    assert_eq!(fade.color_at(2_000), red);
+
+    let pulse = Animation::Pulse {
+        color: red,
+        period_ms: 4_000,
+    };
+    assert_eq!(pulse.color_at(0), Color::BLACK);
+    assert_eq!(pulse.color_at(1_000), rgb(127, 0, 0));
+    assert_eq!(pulse.color_at(2_000), red);
+
+    /* a third of the way round is green, two thirds blue */
+    let rainbow = Animation::Rainbow { period_ms: 3_000 };
+    assert_eq!(rainbow.color_at(0), red);
+    assert_eq!(rainbow.color_at(1_000), rgb(0, 255, 0));
+    assert_eq!(rainbow.color_at(2_000), blue);
+
+    assert!(!Animation::Rainbow { period_ms: 0 }.is_valid());
+    assert!(rainbow.is_valid());
+
+    /* animations don't need the time, and are redrawn often */
+    let mut settings = RgbSettings::new();
+    settings.mode = RgbMode::Animated;
+    settings.animation = fade;
+    assert!(!settings.needs_time() && settings.accepts(RgbMode::Animated, false));
+    let frame = settings.frame(1_000, &TimeZone::UTC);
+    assert_eq!(frame.color, blue);
+    assert!(frame.gamma);
+    assert_eq!(frame.next_ms, ANIMATION_FRAME_MS);
+    settings.mode = RgbMode::Fixed;
+    assert!(!settings.frame(1_000, &TimeZone::UTC).gamma);
+}
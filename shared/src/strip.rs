@@ -0,0 +1,204 @@
+//! Addressable LED strip on the RGB LED's pin
+//!
+//! The strip is [`MAX_PIXELS`] long at most, a single LED is a strip of one.
+//! In [`RgbMode::Pixels`] every pixel shows its own colour as set by the host,
+//! the other modes colour the whole strip, a rainbow spreads over it.
+//!
+//! A whole frame goes over in [`PixelChunk`]s small enough not to make
+//! commands any bigger than they already are. The chunks fill a back buffer
+//! that is shown once the last one is in, so a half uploaded frame never
+//! appears.
+
+use crate::rgb::{Animation, Color, Frame, RgbMode, RgbSettings};
+use crate::tz::TimeZone;
+use serde_derive::{Deserialize, Serialize};
+
+/// Longest strip the firmware has buffers for
+pub const MAX_PIXELS: usize = 60;
+/// Pixels in one [`PixelChunk`]
+pub const PIXELS_PER_CHUNK: usize = 24;
+
+/// Part of a frame, `len` pixels from `start` on
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct PixelChunk {
+    pub start: u16,
+    pub len: u8,
+    /// the frame is complete, show it
+    pub last: bool,
+    pub pixels: [Color; PIXELS_PER_CHUNK],
+}
+
+impl PixelChunk {
+    /// Chunks for a whole frame, the last one marked
+    pub fn split(frame: &[Color]) -> impl Iterator<Item = PixelChunk> + '_ {
+        let count = frame.len().div_ceil(PIXELS_PER_CHUNK);
+        frame
+            .chunks(PIXELS_PER_CHUNK)
+            .enumerate()
+            .map(move |(i, chunk)| {
+                let mut pixels = [Color::BLACK; PIXELS_PER_CHUNK];
+                pixels[..chunk.len()].copy_from_slice(chunk);
+                PixelChunk {
+                    start: (i * PIXELS_PER_CHUNK) as u16,
+                    len: chunk.len() as u8,
+                    last: i + 1 == count,
+                    pixels,
+                }
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Strip {
+    len: usize,
+    /// shown in [`RgbMode::Pixels`]
+    pixels: [Color; MAX_PIXELS],
+    /// where [`PixelChunk`]s go until the frame is complete
+    back: [Color; MAX_PIXELS],
+}
+
+impl Strip {
+    /// A single LED
+    pub const fn new() -> Self {
+        Strip {
+            len: 1,
+            pixels: [Color::BLACK; MAX_PIXELS],
+            back: [Color::BLACK; MAX_PIXELS],
+        }
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        self.len
+    }
+
+    /// False if there is no room for that many
+    pub fn set_len(&mut self, len: u16) -> bool {
+        let ok = (1..=MAX_PIXELS).contains(&(len as usize));
+        if ok {
+            self.len = len as usize;
+        }
+        ok
+    }
+
+    /// False if `start..start + len` isn't on the strip
+    pub fn fill(&mut self, start: u16, len: u16, color: Color) -> bool {
+        match self.pixels[..self.len].get_mut(start as usize..start as usize + len as usize) {
+            Some(pixels) if len > 0 => {
+                pixels.fill(color);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Take a chunk of a frame, shown once the last one is in. False if it
+    /// doesn't fit on the strip.
+    pub fn load(&mut self, chunk: &PixelChunk) -> bool {
+        let start = chunk.start as usize;
+        let Some(pixels) = chunk.pixels.get(..chunk.len as usize) else {
+            return false;
+        };
+        let Some(back) = self.back[..self.len].get_mut(start..start + pixels.len()) else {
+            return false;
+        };
+        back.copy_from_slice(pixels);
+        if chunk.last {
+            self.pixels = self.back;
+        }
+        true
+    }
+
+    /// The frame to show at `now` and the colours for the strip, the
+    /// frame's own colour is the first pixel's
+    pub fn render<'a>(
+        &'a self,
+        settings: &'a RgbSettings,
+        now: u64,
+        tz: &TimeZone,
+    ) -> (Frame, impl Iterator<Item = Color> + 'a) {
+        let frame = settings.frame(now, tz);
+        let len = self.len as u64;
+        let colors = (0..self.len).map(move |i| match (settings.mode, settings.animation) {
+            (RgbMode::Pixels, _) => self.pixels[i],
+            /* a pixel further along is a bit further round the circle */
+            (RgbMode::Animated, Animation::Rainbow { period_ms }) => settings
+                .animation
+                .color_at(now + period_ms as u64 * i as u64 / len),
+            _ => frame.color,
+        });
+        (frame, colors)
+    }
+}
+
+impl Default for Strip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn strip_pixels() {
+    let red = Color { r: 255, g: 0, b: 0 };
+    let mut strip = Strip::new();
+    assert_eq!(strip.pixel_count(), 1);
+    assert!(!strip.set_len(0) && !strip.set_len(MAX_PIXELS as u16 + 1));
+    assert!(strip.set_len(10));
+
+    assert!(strip.fill(2, 3, red));
+    assert!(strip.fill(9, 1, red));
+    assert!(!strip.fill(9, 2, red));
+    assert!(!strip.fill(4, 0, red));
+
+    let mut settings = RgbSettings::new();
+    settings.mode = RgbMode::Pixels;
+    let (frame, colors) = strip.render(&settings, 0, &TimeZone::UTC);
+    let colors: Vec<Color> = colors.collect();
+    assert_eq!(colors.len(), 10);
+    assert_eq!(colors[1], Color::BLACK);
+    assert_eq!(colors[2..5], [red; 3]);
+    assert_eq!(colors[9], red);
+    assert!(!frame.gamma);
+}
+
+#[test]
+fn strip_frames_in_chunks() {
+    let green = Color { r: 0, g: 255, b: 0 };
+    let mut strip = Strip::new();
+    assert!(strip.set_len(MAX_PIXELS as u16));
+    let mut settings = RgbSettings::new();
+    settings.mode = RgbMode::Pixels;
+    let shown = |strip: &Strip, settings: &RgbSettings| -> Vec<Color> {
+        strip.render(settings, 0, &TimeZone::UTC).1.collect()
+    };
+
+    let frame = [green; MAX_PIXELS];
+    let chunks: Vec<PixelChunk> = PixelChunk::split(&frame).collect();
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(chunks[2].start, 48);
+    assert_eq!(chunks[2].len, 12);
+    assert!(chunks[2].last && !chunks[1].last);
+
+    /* nothing shows until the last chunk is in */
+    assert!(strip.load(&chunks[0]) && strip.load(&chunks[1]));
+    assert_eq!(shown(&strip, &settings)[0], Color::BLACK);
+    assert!(strip.load(&chunks[2]));
+    assert_eq!(shown(&strip, &settings), frame);
+
+    /* a frame longer than the strip is refused */
+    assert!(strip.set_len(40));
+    assert!(!strip.load(&chunks[1]));
+
+    /* the rainbow goes round once along the strip */
+    assert!(strip.set_len(3));
+    settings.mode = RgbMode::Animated;
+    settings.animation = Animation::Rainbow { period_ms: 3_000 };
+    assert_eq!(
+        shown(&strip, &settings),
+        [
+            Color { r: 255, g: 0, b: 0 },
+            green,
+            Color { r: 0, g: 0, b: 255 }
+        ]
+    );
+}
@@ -0,0 +1,231 @@
+//! Sunrise, sunset and civil twilight
+//!
+//! Uses the sunrise equation with the usual corrections for the equation of
+//! time, refraction and the size of the sun's disc, which is good to a minute
+//! or two away from the poles. Plenty for colouring an LED. Far enough north
+//! or south the sun may not rise or set at all on some days, those events are
+//! `None` then.
+
+use crate::rgb::{Interpolation, Keyframe, Palette, DAY, EVENING, MORNING, NIGHT};
+use crate::tz::TimeZone;
+use libm::{acos, asin, cos, fabs, fmod, sin};
+use serde_derive::{Deserialize, Serialize};
+
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+/// Julian date of 2000-01-01 12:00, the epoch of the equation
+const J2000: f64 = 2_451_545.0;
+/// Julian date of 1970-01-01 00:00
+const UNIX_EPOCH: f64 = 2_440_587.5;
+const DAYS_TO_2000: i64 = 10_957;
+/// Axial tilt of the earth
+const OBLIQUITY: f64 = 23.4397;
+/// Sun's centre this far below the horizon, refraction and its radius count
+const SUNRISE_ALTITUDE: f64 = -0.833;
+const CIVIL_TWILIGHT_ALTITUDE: f64 = -6.0;
+
+/// Where the device is, in degrees, north and east positive
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Location {
+    pub latitude: f32,
+    pub longitude: f32,
+}
+
+impl Location {
+    /// Checked again on the device since it comes off the wire
+    pub fn is_valid(&self) -> bool {
+        (-90.0..=90.0).contains(&self.latitude) && (-180.0..=180.0).contains(&self.longitude)
+    }
+}
+
+/// The sun's day in UTC ms
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SunTimes {
+    /// start of civil twilight
+    pub dawn: Option<u64>,
+    pub sunrise: Option<u64>,
+    pub noon: u64,
+    pub sunset: Option<u64>,
+    /// end of civil twilight
+    pub dusk: Option<u64>,
+    /// whether the sun is up at noon, for when it doesn't rise or set
+    pub up_at_noon: bool,
+}
+
+/// Sun times on the `day`th day after 1970-01-01 at `location`, the day the
+/// way it goes at that longitude
+pub fn sun_times(day: i64, location: &Location) -> SunTimes {
+    let rad = f64::to_radians;
+    let degrees = |a: f64| fmod(fmod(a, 360.0) + 360.0, 360.0);
+    let latitude = location.latitude as f64;
+
+    /* mean solar noon, then the sun's place along the ecliptic */
+    let mean_noon = (day - DAYS_TO_2000) as f64 - location.longitude as f64 / 360.0;
+    let anomaly = degrees(357.5291 + 0.985_600_28 * mean_noon);
+    let center = 1.9148 * sin(rad(anomaly))
+        + 0.02 * sin(rad(2.0 * anomaly))
+        + 0.0003 * sin(rad(3.0 * anomaly));
+    let ecliptic_longitude = degrees(anomaly + center + 180.0 + 102.9372);
+    let transit = J2000 + mean_noon + 0.0053 * sin(rad(anomaly))
+        - 0.0069 * sin(rad(2.0 * ecliptic_longitude));
+    let declination = asin(sin(rad(ecliptic_longitude)) * sin(rad(OBLIQUITY)));
+
+    let to_ms = |julian: f64| ((julian - UNIX_EPOCH) * MS_PER_DAY as f64) as u64;
+    /* when the sun crosses `altitude` on the way up and down, None if it
+     * stays on one side all day */
+    let crossings = |altitude: f64| {
+        let cos_hour_angle = (sin(rad(altitude)) - sin(rad(latitude)) * sin(declination))
+            / (cos(rad(latitude)) * cos(declination));
+        if !(-1.0..=1.0).contains(&cos_hour_angle) {
+            return (None, None);
+        }
+        let half_day = acos(cos_hour_angle).to_degrees() / 360.0;
+        (
+            Some(to_ms(transit - half_day)),
+            Some(to_ms(transit + half_day)),
+        )
+    };
+    let (dawn, dusk) = crossings(CIVIL_TWILIGHT_ALTITUDE);
+    let (sunrise, sunset) = crossings(SUNRISE_ALTITUDE);
+    /* at noon the sun is 90° less the angle between its path and the zenith */
+    let noon_altitude = 90.0 - fabs(latitude - declination.to_degrees());
+
+    SunTimes {
+        dawn,
+        sunrise,
+        noon: to_ms(transit),
+        sunset,
+        dusk,
+        up_at_noon: noon_altitude > SUNRISE_ALTITUDE,
+    }
+}
+
+impl SunTimes {
+    /// Palette through the day, night until dawn, morning at sunrise, day at
+    /// noon, evening at sunset and night again at dusk, by local time in `tz`
+    pub fn palette(&self, tz: &TimeZone) -> Palette {
+        let noon_color = match (self.up_at_noon, self.dawn) {
+            (true, _) => DAY,
+            /* twilight is all there is of the day */
+            (false, Some(_)) => EVENING,
+            (false, None) => NIGHT,
+        };
+        let events = [
+            (self.dawn, NIGHT),
+            (self.sunrise, MORNING),
+            (Some(self.noon), noon_color),
+            (self.sunset, EVENING),
+            (self.dusk, NIGHT),
+        ];
+
+        let mut keys = [Keyframe {
+            at_ms: 0,
+            color: NIGHT,
+        }; 5];
+        let mut len = 0;
+        for (t, color) in events {
+            let Some(t) = t else { continue };
+            let at_ms = (tz.local_ms(t) % MS_PER_DAY) as u32;
+            if keys[..len].iter().all(|k| k.at_ms != at_ms) {
+                keys[len] = Keyframe { at_ms, color };
+                len += 1;
+            }
+        }
+        /* dusk may be past midnight up north */
+        keys[..len].sort_unstable_by_key(|k| k.at_ms);
+        Palette::new(&keys[..len], Interpolation::Hsv).unwrap_or(Palette::DEFAULT)
+    }
+}
+
+#[cfg(test)]
+fn assert_near(t: Option<u64>, y: i64, m: u32, d: u32, hh: u64, mm: u64) {
+    let expected =
+        crate::tz::days_from_civil(y, m, d) as u64 * MS_PER_DAY + (hh * 60 + mm) * 60_000;
+    let t = t.expect("no such event that day");
+    /* almanacs round to the minute, the equation is good to a minute or two */
+    assert!(
+        t.abs_diff(expected) <= 2 * 60_000,
+        "{} minutes off",
+        (t as i64 - expected as i64) / 60_000
+    );
+}
+
+#[test]
+fn sun_times_against_almanac() {
+    use crate::tz::days_from_civil;
+
+    /* London, times in UTC */
+    let london = Location {
+        latitude: 51.5074,
+        longitude: -0.1278,
+    };
+    let midsummer = sun_times(days_from_civil(2024, 6, 21), &london);
+    assert_near(midsummer.sunrise, 2024, 6, 21, 3, 43);
+    assert_near(midsummer.sunset, 2024, 6, 21, 20, 21);
+    assert_near(Some(midsummer.noon), 2024, 6, 21, 12, 2);
+    let midwinter = sun_times(days_from_civil(2024, 12, 21), &london);
+    assert_near(midwinter.sunrise, 2024, 12, 21, 8, 4);
+    assert_near(midwinter.sunset, 2024, 12, 21, 15, 53);
+    assert_near(midwinter.dawn, 2024, 12, 21, 7, 25);
+    assert_near(midwinter.dusk, 2024, 12, 21, 16, 32);
+
+    /* Sydney in June, the morning is still the previous day in UTC */
+    let sydney = Location {
+        latitude: -33.8688,
+        longitude: 151.2093,
+    };
+    let winter = sun_times(days_from_civil(2024, 6, 21), &sydney);
+    assert_near(winter.sunrise, 2024, 6, 20, 21, 0);
+    assert_near(winter.sunset, 2024, 6, 21, 6, 54);
+
+    /* Tromsø has the midnight sun in June and only twilight in December */
+    let tromso = Location {
+        latitude: 69.6492,
+        longitude: 18.9553,
+    };
+    let summer = sun_times(days_from_civil(2024, 6, 21), &tromso);
+    assert!(summer.up_at_noon && summer.sunrise.is_none() && summer.dawn.is_none());
+    let winter = sun_times(days_from_civil(2024, 12, 21), &tromso);
+    assert!(!winter.up_at_noon && winter.sunrise.is_none() && winter.dawn.is_some());
+
+    assert!(!Location {
+        latitude: 91.0,
+        longitude: 0.0
+    }
+    .is_valid());
+}
+
+#[test]
+fn sun_palette() {
+    use crate::tz::days_from_civil;
+
+    let london = Location {
+        latitude: 51.5074,
+        longitude: -0.1278,
+    };
+    let day = days_from_civil(2024, 12, 21);
+    let times = sun_times(day, &london);
+    let palette = times.palette(&TimeZone::UTC);
+    assert_eq!(palette.keys().len(), 5);
+    assert_eq!(palette.color_at(day as u64 * MS_PER_DAY), NIGHT);
+    assert_eq!(palette.color_at(times.sunrise.unwrap()), MORNING);
+    assert_eq!(palette.color_at(times.noon), DAY);
+    assert_eq!(palette.color_at(times.dusk.unwrap()), NIGHT);
+
+    /* an hour ahead moves the keyframes along */
+    let cet = TimeZone {
+        offset_minutes: 60,
+        dst: crate::tz::DstRule::None,
+    };
+    let local_noon = times.palette(&cet).keys()[2].at_ms as u64;
+    assert_eq!(local_noon, (times.noon + 3_600_000) % MS_PER_DAY);
+
+    /* the sun never sets, nothing but daylight and noon */
+    let tromso = Location {
+        latitude: 69.6492,
+        longitude: 18.9553,
+    };
+    let summer = sun_times(days_from_civil(2024, 6, 21), &tromso).palette(&TimeZone::UTC);
+    assert_eq!(summer.keys().len(), 1);
+    assert_eq!(summer.color_at(0), DAY);
+}
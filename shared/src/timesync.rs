@@ -0,0 +1,51 @@
+//! NTP style offset and round trip calculation
+//!
+//! The host stamps a [`crate::Command::SyncTime`] with its clock (`t1`), the
+//! device notes its clock when the frame arrived (`t2`) and when the answer
+//! goes out (`t3`), and the host notes when the answer arrived (`t4`). All in
+//! milliseconds. Assuming the link is equally fast both ways, the device clock
+//! is off by `offset` and the round trip took `delay` without the time the
+//! device spent on the request.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SyncSample {
+    /// device clock minus host clock
+    pub offset_ms: i64,
+    /// time spent on the link, both ways
+    pub delay_ms: i64,
+}
+
+impl SyncSample {
+    pub fn new(t1: u64, t2: u64, t3: u64, t4: u64) -> Self {
+        let (t1, t2, t3, t4) = (t1 as i64, t2 as i64, t3 as i64, t4 as i64);
+        SyncSample {
+            offset_ms: ((t2 - t1) + (t3 - t4)) / 2,
+            delay_ms: (t4 - t1) - (t3 - t2),
+        }
+    }
+
+    /// The sample least disturbed by queuing on the link is the one with the
+    /// shortest round trip
+    pub fn best(samples: impl IntoIterator<Item = SyncSample>) -> Option<SyncSample> {
+        samples.into_iter().min_by_key(|s| s.delay_ms)
+    }
+}
+
+#[test]
+fn sync_sample_symmetric_link() {
+    /* device is 5s ahead, 20ms each way, 3ms processing */
+    let s = SyncSample::new(1_000, 6_020, 6_023, 1_043);
+    assert_eq!(s.offset_ms, 5_000);
+    assert_eq!(s.delay_ms, 40);
+
+    /* device behind, e.g. never set and counting from boot */
+    let s = SyncSample::new(1_700_000_000_000, 12_010, 12_010, 1_700_000_000_020);
+    assert_eq!(s.offset_ms, 12_000 - 1_700_000_000_000);
+    assert_eq!(s.delay_ms, 20);
+
+    let best = SyncSample::best([
+        SyncSample::new(0, 100, 100, 300),
+        SyncSample::new(0, 10, 10, 20),
+    ]);
+    assert_eq!(best.unwrap().delay_ms, 20);
+}
@@ -0,0 +1,186 @@
+//! Time zone rules for turning UTC into local time on the device
+//!
+//! A zone is a fixed offset from UTC plus, optionally, the daylight saving
+//! rules used in the EU or the US. Only integer arithmetic, the calendar
+//! conversions follow Howard Hinnant's `days_from_civil`/`civil_from_days`.
+
+use serde_derive::{Deserialize, Serialize};
+
+const MS_PER_MINUTE: i64 = 60 * 1000;
+const MS_PER_HOUR: i64 = 60 * MS_PER_MINUTE;
+const MS_PER_DAY: i64 = 24 * MS_PER_HOUR;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub enum DstRule {
+    None,
+    /// last Sunday of March to last Sunday of October, switching at 01:00 UTC
+    Eu,
+    /// second Sunday of March to first Sunday of November, switching at 02:00
+    /// local time
+    Us,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct TimeZone {
+    /// standard (winter) time offset from UTC, east is positive
+    pub offset_minutes: i16,
+    pub dst: DstRule,
+}
+
+impl TimeZone {
+    pub const UTC: TimeZone = TimeZone {
+        offset_minutes: 0,
+        dst: DstRule::None,
+    };
+
+    /// Offset from UTC in ms at `utc` ms since the epoch, DST included
+    pub fn offset_ms(&self, utc: u64) -> i64 {
+        let standard = self.offset_minutes as i64 * MS_PER_MINUTE;
+        let utc = utc as i64;
+        let (year, _, _) = civil_from_days(utc.div_euclid(MS_PER_DAY));
+
+        let (start, end) = match self.dst {
+            DstRule::None => return standard,
+            DstRule::Eu => (
+                last_sunday(year, 3) * MS_PER_DAY + MS_PER_HOUR,
+                last_sunday(year, 10) * MS_PER_DAY + MS_PER_HOUR,
+            ),
+            /* 02:00 standard time in, 02:00 daylight time out */
+            DstRule::Us => (
+                nth_sunday(year, 3, 2) * MS_PER_DAY + 2 * MS_PER_HOUR - standard,
+                nth_sunday(year, 11, 1) * MS_PER_DAY + MS_PER_HOUR - standard,
+            ),
+        };
+
+        if (start..end).contains(&utc) {
+            standard + MS_PER_HOUR
+        } else {
+            standard
+        }
+    }
+
+    /// Local wall clock time at `utc`, in ms since the local epoch
+    pub fn local_ms(&self, utc: u64) -> u64 {
+        utc.saturating_add_signed(self.offset_ms(utc))
+    }
+
+    /// UTC at wall clock time `local`. A local time skipped when the clocks
+    /// go forward comes out an hour late, a repeated one is taken the first
+    /// time round.
+    pub fn utc_ms(&self, local: u64) -> u64 {
+        let standard = local.saturating_add_signed(-(self.offset_minutes as i64 * MS_PER_MINUTE));
+        let daylight = standard.saturating_sub(MS_PER_HOUR as u64);
+        if self.local_ms(daylight) == local {
+            daylight
+        } else {
+            standard
+        }
+    }
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self::UTC
+    }
+}
+
+/// Days since 1970-01-01 of the given date
+pub fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = m as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Date of the day `z` days after 1970-01-01
+pub fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+/// 0 is Sunday
+pub fn weekday(days: i64) -> i64 {
+    (days + 4).rem_euclid(7)
+}
+
+/// Day number of the `n`th Sunday of the month
+fn nth_sunday(y: i64, m: u32, n: i64) -> i64 {
+    let first = days_from_civil(y, m, 1);
+    first + (7 - weekday(first)) % 7 + (n - 1) * 7
+}
+
+/// Day number of the last Sunday of the month
+fn last_sunday(y: i64, m: u32) -> i64 {
+    let next = if m == 12 {
+        days_from_civil(y + 1, 1, 1)
+    } else {
+        days_from_civil(y, m + 1, 1)
+    };
+    let last = next - 1;
+    last - weekday(last)
+}
+
+#[test]
+fn tz_calendar() {
+    assert_eq!(days_from_civil(1970, 1, 1), 0);
+    assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+    assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+    assert_eq!(weekday(0), 4);
+    assert_eq!(last_sunday(2024, 3), days_from_civil(2024, 3, 31));
+    assert_eq!(last_sunday(2024, 10), days_from_civil(2024, 10, 27));
+    assert_eq!(nth_sunday(2024, 3, 2), days_from_civil(2024, 3, 10));
+    assert_eq!(nth_sunday(2024, 11, 1), days_from_civil(2024, 11, 3));
+}
+
+#[test]
+fn tz_dst_switches() {
+    let at = |y, m, d, h: i64, min: i64| {
+        (days_from_civil(y, m, d) * MS_PER_DAY + h * MS_PER_HOUR + min * MS_PER_MINUTE) as u64
+    };
+
+    let cet = TimeZone {
+        offset_minutes: 60,
+        dst: DstRule::Eu,
+    };
+    assert_eq!(cet.offset_ms(at(2024, 1, 15, 12, 0)), MS_PER_HOUR);
+    assert_eq!(cet.offset_ms(at(2024, 3, 31, 0, 59)), MS_PER_HOUR);
+    assert_eq!(cet.offset_ms(at(2024, 3, 31, 1, 0)), 2 * MS_PER_HOUR);
+    assert_eq!(cet.offset_ms(at(2024, 10, 27, 0, 59)), 2 * MS_PER_HOUR);
+    assert_eq!(cet.offset_ms(at(2024, 10, 27, 1, 0)), MS_PER_HOUR);
+
+    /* US eastern, switches at 07:00 and 06:00 UTC */
+    let eastern = TimeZone {
+        offset_minutes: -300,
+        dst: DstRule::Us,
+    };
+    assert_eq!(eastern.offset_ms(at(2024, 3, 10, 6, 59)), -5 * MS_PER_HOUR);
+    assert_eq!(eastern.offset_ms(at(2024, 3, 10, 7, 0)), -4 * MS_PER_HOUR);
+    assert_eq!(eastern.offset_ms(at(2024, 11, 3, 5, 59)), -4 * MS_PER_HOUR);
+    assert_eq!(eastern.offset_ms(at(2024, 11, 3, 6, 0)), -5 * MS_PER_HOUR);
+
+    let local = eastern.local_ms(at(2024, 7, 4, 16, 30));
+    assert_eq!(local, at(2024, 7, 4, 12, 30));
+    assert_eq!(eastern.utc_ms(local), at(2024, 7, 4, 16, 30));
+    assert_eq!(
+        eastern.utc_ms(at(2024, 1, 4, 12, 30)),
+        at(2024, 1, 4, 17, 30)
+    );
+
+    /* 02:30 doesn't exist on the 31st of March, 02:30 on the 27th of October
+     * happens twice */
+    assert_eq!(cet.utc_ms(at(2024, 3, 31, 2, 30)), at(2024, 3, 31, 1, 30));
+    assert_eq!(cet.utc_ms(at(2024, 10, 27, 2, 30)), at(2024, 10, 27, 0, 30));
+}